@@ -0,0 +1,876 @@
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+
+// T-clocks per second
+pub const CPU_CLOCK: u32 = 4_194_304;
+// the frame sequencer steps at 512Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CPU_CLOCK / 512;
+
+const DUTY_PATTERNS: [u8; 4] = [
+    0b0000_0001, // 12.5%
+    0b1000_0001, // 25%
+    0b1000_0111, // 50%
+    0b0111_1110, // 75%
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// bits that always read back as 1, from NR10 (0xFF10) up to 0xFF2F
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+#[derive(Debug, Copy, Clone)]
+struct Length {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new(max: u16) -> Length {
+        Length {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    // returns true when the channel has to be turned off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    // handles the length half of an NRx4 write. when the next frame sequencer
+    // step doesn't clock the length, enabling it clocks it once right away
+    fn write(&mut self, value: u8, trigger: bool, length_step_next: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = (value & 0x40) != 0;
+
+        let mut off = false;
+        if !length_step_next && !was_enabled && self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            off = self.counter == 0 && !trigger;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && !length_step_next {
+                self.counter -= 1;
+            }
+        }
+        off
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.counter = r.read_u16()?;
+        self.enabled = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = (value & 0b1000) != 0;
+        self.period = value & 0b111;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.volume);
+        w.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.volume = r.read_u8()?;
+        self.timer = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+    // clearing negate after a subtraction was done turns the channel off
+    negated: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow: 0,
+            negated: false,
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.timer);
+        w.write_bool(self.enabled);
+        w.write_u16(self.shadow);
+        w.write_bool(self.negated);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.timer = r.read_u8()?;
+        self.enabled = r.read_bool()?;
+        self.shadow = r.read_u16()?;
+        self.negated = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Square {
+    enabled: bool,
+    dac: bool,
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Square {
+    fn new() -> Square {
+        Square {
+            enabled: false,
+            dac: false,
+            duty: 0,
+            duty_pos: 0,
+            freq: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.freq as i32) * 4
+    }
+
+    fn tick(&mut self, t_clocks: i32) {
+        self.timer -= t_clocks;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_pos = (self.duty_pos + 1) % 8;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> u8 {
+        let high = (DUTY_PATTERNS[self.duty as usize] >> (7 - self.duty_pos)) & 1;
+        if self.enabled { high * self.envelope.volume } else { 0 }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.duty_pos);
+        w.write_u16(self.freq);
+        w.write_u32(self.timer as u32);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = r.read_bool()?;
+        self.duty_pos = r.read_u8()? % 8;
+        self.freq = r.read_u16()? & 0x7FF;
+        self.timer = r.read_u32()? as i32;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Wave {
+    enabled: bool,
+    dac: bool,
+    volume_code: u8,
+    freq: u16,
+    timer: i32,
+    position: u8,
+    sample: u8,
+    length: Length,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac: false,
+            volume_code: 0,
+            freq: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: Length::new(256),
+            ram: [0; 16],
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.freq as i32) * 2
+    }
+
+    fn tick(&mut self, t_clocks: i32) {
+        self.timer -= t_clocks;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % 32;
+            // two 4-bit samples per byte, high nibble first
+            let byte = self.ram[(self.position / 2) as usize];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            1 => self.sample,
+            2 => self.sample >> 1,
+            _ => self.sample >> 2,
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u16(self.freq);
+        w.write_u32(self.timer as u32);
+        w.write_u8(self.position);
+        w.write_u8(self.sample);
+        self.length.save_state(w);
+        w.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = r.read_bool()?;
+        self.freq = r.read_u16()? & 0x7FF;
+        self.timer = r.read_u32()? as i32;
+        self.position = r.read_u8()? % 32;
+        self.sample = r.read_u8()? & 0x0F;
+        self.length.load_state(r)?;
+        r.read_bytes(&mut self.ram)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Noise {
+    enabled: bool,
+    dac: bool,
+    lfsr: u16,
+    shift: u8,
+    width7: bool,
+    divisor: u8,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Noise {
+        Noise {
+            enabled: false,
+            dac: false,
+            lfsr: 0x7FFF,
+            shift: 0,
+            width7: false,
+            divisor: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[self.divisor as usize] << self.shift) as i32
+    }
+
+    fn tick(&mut self, t_clocks: i32) {
+        self.timer -= t_clocks;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            // shifts of 14 and 15 never clock the LFSR
+            if self.shift < 14 {
+                let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                self.lfsr = (self.lfsr >> 1) | (bit << 14);
+                if self.width7 {
+                    self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+                }
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> u8 {
+        let high = (!self.lfsr & 1) as u8;
+        if self.enabled { high * self.envelope.volume } else { 0 }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u16(self.lfsr);
+        w.write_u32(self.timer as u32);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.enabled = r.read_bool()?;
+        self.lfsr = r.read_u16()? & 0x7FFF;
+        self.timer = r.read_u32()? as i32;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)
+    }
+}
+
+pub struct APU {
+    powered: bool,
+    regs: [u8; 0x20],
+    square1: Square,
+    sweep: Sweep,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    frame_clock: u32,
+    frame_step: u8,
+    sample_rate: Option<u32>,
+    // the rate samples are actually produced at, see set_rate_ratio
+    output_rate: u32,
+    sample_clock: u32,
+    sum: (f32, f32),
+    sum_count: u32,
+    capacitor: (f32, f32),
+    charge_factor: f32,
+    samples: Vec<f32>,
+}
+
+impl APU {
+    pub fn new() -> APU {
+        APU {
+            powered: false,
+            regs: [0; 0x20],
+            square1: Square::new(),
+            sweep: Sweep::new(),
+            square2: Square::new(),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_clock: 0,
+            frame_step: 0,
+            sample_rate: None,
+            output_rate: 0,
+            sample_clock: 0,
+            sum: (0.0, 0.0),
+            sum_count: 0,
+            capacitor: (0.0, 0.0),
+            charge_factor: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    // no samples are produced until a sample rate is set
+    pub fn set_sample_rate(&mut self, sample_rate: Option<u32>) {
+        self.sample_rate = sample_rate;
+        self.sample_clock = 0;
+        self.sum = (0.0, 0.0);
+        self.sum_count = 0;
+        if let Some(rate) = sample_rate {
+            self.output_rate = rate;
+            // the high-pass filter the DMG applies through its output capacitor
+            self.charge_factor = 0.999_958_f32.powf(CPU_CLOCK as f32 / rate as f32);
+        }
+    }
+
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    // produces slightly more or fewer samples than the sample rate asks
+    // for, so a real-time sink's queue can be kept from running dry or
+    // piling up. the pitch change of a fraction of a percent isn't audible
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        if let Some(rate) = self.sample_rate {
+            self.output_rate = (rate as f64 * ratio).round() as u32;
+        }
+    }
+
+    // interleaved left and right samples, in the range -1.0 to 1.0
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn tick_n(&mut self, m_clocks: u8) {
+        for _ in 0..m_clocks {
+            self.tick();
+        }
+    }
+
+    pub fn tick(&mut self) {
+        if self.powered {
+            self.square1.tick(4);
+            self.square2.tick(4);
+            self.wave.tick(4);
+            self.noise.tick(4);
+
+            self.frame_clock += 4;
+            if self.frame_clock >= FRAME_SEQUENCER_PERIOD {
+                self.frame_clock -= FRAME_SEQUENCER_PERIOD;
+                self.step_frame_sequencer();
+            }
+        }
+
+        if self.sample_rate.is_some() {
+            let (left, right) = self.mix();
+            self.sum.0 += left;
+            self.sum.1 += right;
+            self.sum_count += 1;
+
+            self.sample_clock += 4 * self.output_rate;
+            if self.sample_clock >= CPU_CLOCK {
+                self.sample_clock -= CPU_CLOCK;
+                self.push_sample();
+            }
+        }
+    }
+
+    // length on even steps, sweep on 2 and 6, envelopes on 7
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step & 1 == 0 {
+            if self.square1.length.clock() {
+                self.square1.enabled = false;
+            }
+            if self.square2.length.clock() {
+                self.square2.enabled = false;
+            }
+            if self.wave.length.clock() {
+                self.wave.enabled = false;
+            }
+            if self.noise.length.clock() {
+                self.noise.enabled = false;
+            }
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    fn length_step_next(&self) -> bool {
+        self.frame_step & 1 == 0
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+
+        if self.sweep.enabled && self.sweep.period != 0 {
+            let freq = self.sweep.next_frequency();
+            if freq > 2047 {
+                self.square1.enabled = false;
+            } else if self.sweep.shift != 0 {
+                self.sweep.shadow = freq;
+                self.square1.freq = freq;
+                // the new frequency is checked for overflow once more
+                if self.sweep.next_frequency() > 2047 {
+                    self.square1.enabled = false;
+                }
+            }
+        }
+    }
+
+    fn trigger_sweep(&mut self) {
+        self.sweep.shadow = self.square1.freq;
+        self.sweep.negated = false;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        if self.sweep.shift != 0 && self.sweep.next_frequency() > 2047 {
+            self.square1.enabled = false;
+        }
+    }
+
+    fn dac_output(digital: u8, dac: bool) -> f32 {
+        if dac {
+            digital as f32 / 7.5 - 1.0
+        } else {
+            0.0
+        }
+    }
+
+    fn mix(&self) -> (f32, f32) {
+        if !self.powered {
+            return (0.0, 0.0);
+        }
+
+        let outputs = [
+            APU::dac_output(self.square1.output(), self.square1.dac),
+            APU::dac_output(self.square2.output(), self.square2.dac),
+            APU::dac_output(self.wave.output(), self.wave.dac),
+            APU::dac_output(self.noise.output(), self.noise.dac),
+        ];
+
+        let nr50 = self.regs[0x14];
+        let nr51 = self.regs[0x15];
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, output) in outputs.iter().enumerate() {
+            if (nr51 & (0x10 << i)) != 0 {
+                left += output;
+            }
+            if (nr51 & (0x01 << i)) != 0 {
+                right += output;
+            }
+        }
+
+        let left_volume = (((nr50 >> 4) & 0b111) + 1) as f32 / 8.0;
+        let right_volume = ((nr50 & 0b111) + 1) as f32 / 8.0;
+        (left / 4.0 * left_volume, right / 4.0 * right_volume)
+    }
+
+    fn push_sample(&mut self) {
+        let count = self.sum_count.max(1) as f32;
+        let (left, right) = (self.sum.0 / count, self.sum.1 / count);
+        self.sum = (0.0, 0.0);
+        self.sum_count = 0;
+
+        let out_left = left - self.capacitor.0;
+        let out_right = right - self.capacitor.1;
+        self.capacitor.0 = left - out_left * self.charge_factor;
+        self.capacitor.1 = right - out_right * self.charge_factor;
+        self.samples.push(out_left);
+        self.samples.push(out_right);
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xFF26 => {
+                (self.powered as u8) << 7
+                | READ_MASKS[0x16]
+                | ((self.noise.enabled as u8) << 3)
+                | ((self.wave.enabled as u8) << 2)
+                | ((self.square2.enabled as u8) << 1)
+                | (self.square1.enabled as u8)
+            },
+            0xFF10..=0xFF2F => {
+                let reg = (address - 0xFF10) as usize;
+                self.regs[reg] | READ_MASKS[reg]
+            },
+            0xFF30..=0xFF3F => self.wave.ram[(address - 0xFF30) as usize],
+            _ => unreachable!()
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        // while powered off only NR52, wave RAM and the length
+        // counters (on the DMG) can be written
+        let length_reg = matches!(address, 0xFF11 | 0xFF16 | 0xFF1B | 0xFF20);
+        if !self.powered && address != 0xFF26 && address < 0xFF30 && !length_reg {
+            return;
+        }
+        if (0xFF10..=0xFF2F).contains(&address) && self.powered {
+            self.regs[(address - 0xFF10) as usize] = value;
+        }
+
+        let length_step_next = self.length_step_next();
+        match address {
+            0xFF10 => {
+                self.sweep.period = (value >> 4) & 0b111;
+                self.sweep.negate = (value & 0b1000) != 0;
+                self.sweep.shift = value & 0b111;
+                if !self.sweep.negate && self.sweep.negated {
+                    self.square1.enabled = false;
+                }
+            },
+            0xFF11 => {
+                if self.powered {
+                    self.square1.duty = value >> 6;
+                }
+                self.square1.length.load(value & 0x3F);
+            },
+            0xFF12 => {
+                self.square1.envelope.write(value);
+                self.square1.dac = (value & 0xF8) != 0;
+                if !self.square1.dac {
+                    self.square1.enabled = false;
+                }
+            },
+            0xFF13 => self.square1.freq = (self.square1.freq & 0x700) | value as u16,
+            0xFF14 => {
+                let trigger = (value & 0x80) != 0;
+                self.square1.freq = (self.square1.freq & 0xFF) | (((value & 0b111) as u16) << 8);
+                if self.square1.length.write(value, trigger, length_step_next) {
+                    self.square1.enabled = false;
+                }
+                if trigger {
+                    self.square1.trigger();
+                    self.trigger_sweep();
+                }
+            },
+            0xFF16 => {
+                if self.powered {
+                    self.square2.duty = value >> 6;
+                }
+                self.square2.length.load(value & 0x3F);
+            },
+            0xFF17 => {
+                self.square2.envelope.write(value);
+                self.square2.dac = (value & 0xF8) != 0;
+                if !self.square2.dac {
+                    self.square2.enabled = false;
+                }
+            },
+            0xFF18 => self.square2.freq = (self.square2.freq & 0x700) | value as u16,
+            0xFF19 => {
+                let trigger = (value & 0x80) != 0;
+                self.square2.freq = (self.square2.freq & 0xFF) | (((value & 0b111) as u16) << 8);
+                if self.square2.length.write(value, trigger, length_step_next) {
+                    self.square2.enabled = false;
+                }
+                if trigger {
+                    self.square2.trigger();
+                }
+            },
+            0xFF1A => {
+                self.wave.dac = (value & 0x80) != 0;
+                if !self.wave.dac {
+                    self.wave.enabled = false;
+                }
+            },
+            0xFF1B => self.wave.length.load(value),
+            0xFF1C => self.wave.volume_code = (value >> 5) & 0b11,
+            0xFF1D => self.wave.freq = (self.wave.freq & 0x700) | value as u16,
+            0xFF1E => {
+                let trigger = (value & 0x80) != 0;
+                self.wave.freq = (self.wave.freq & 0xFF) | (((value & 0b111) as u16) << 8);
+                if self.wave.length.write(value, trigger, length_step_next) {
+                    self.wave.enabled = false;
+                }
+                if trigger {
+                    self.wave.trigger();
+                }
+            },
+            0xFF20 => self.noise.length.load(value & 0x3F),
+            0xFF21 => {
+                self.noise.envelope.write(value);
+                self.noise.dac = (value & 0xF8) != 0;
+                if !self.noise.dac {
+                    self.noise.enabled = false;
+                }
+            },
+            0xFF22 => {
+                self.noise.shift = value >> 4;
+                self.noise.width7 = (value & 0b1000) != 0;
+                self.noise.divisor = value & 0b111;
+            },
+            0xFF23 => {
+                let trigger = (value & 0x80) != 0;
+                if self.noise.length.write(value, trigger, length_step_next) {
+                    self.noise.enabled = false;
+                }
+                if trigger {
+                    self.noise.trigger();
+                }
+            },
+            0xFF24 | 0xFF25 => {},
+            0xFF26 => {
+                let power = (value & 0x80) != 0;
+                if self.powered && !power {
+                    self.power_off();
+                } else if !self.powered && power {
+                    self.powered = true;
+                    self.frame_step = 0;
+                    self.frame_clock = 0;
+                }
+            },
+            0xFF15 | 0xFF1F | 0xFF27..=0xFF2F => {},
+            0xFF30..=0xFF3F => self.wave.ram[(address - 0xFF30) as usize] = value,
+            _ => unreachable!()
+        }
+    }
+
+    // clears every register, but wave RAM and the length counters survive
+    fn power_off(&mut self) {
+        let lengths = [
+            self.square1.length.counter,
+            self.square2.length.counter,
+            self.wave.length.counter,
+            self.noise.length.counter,
+        ];
+        let wave_ram = self.wave.ram;
+
+        self.powered = false;
+        self.regs = [0; 0x20];
+        self.square1 = Square::new();
+        self.sweep = Sweep::new();
+        self.square2 = Square::new();
+        self.wave = Wave::new();
+        self.noise = Noise::new();
+
+        self.square1.length.counter = lengths[0];
+        self.square2.length.counter = lengths[1];
+        self.wave.length.counter = lengths[2];
+        self.noise.length.counter = lengths[3];
+        self.wave.ram = wave_ram;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.powered);
+        w.write_bytes(&self.regs);
+        self.square1.save_state(w);
+        self.sweep.save_state(w);
+        self.square2.save_state(w);
+        self.wave.save_state(w);
+        self.noise.save_state(w);
+        w.write_u32(self.frame_clock);
+        w.write_u8(self.frame_step);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let powered = r.read_bool()?;
+        let mut regs = [0; 0x20];
+        r.read_bytes(&mut regs)?;
+
+        // replaying the register writes restores everything that
+        // follows directly from them, like duties, DACs and panning
+        *self = APU {
+            sample_rate: self.sample_rate,
+            output_rate: self.output_rate,
+            charge_factor: self.charge_factor,
+            ..APU::new()
+        };
+        self.write(0xFF26, (powered as u8) << 7);
+        for (i, &value) in regs.iter().enumerate() {
+            let address = 0xFF10 + i as u16;
+            match address {
+                // skip the trigger bits
+                0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => self.write(address, value & 0x7F),
+                0xFF26 => {},
+                _ => self.write(address, value),
+            }
+        }
+
+        self.square1.load_state(r)?;
+        self.sweep.load_state(r)?;
+        self.square2.load_state(r)?;
+        self.wave.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.frame_clock = r.read_u32()? % FRAME_SEQUENCER_PERIOD;
+        self.frame_step = r.read_u8()? % 8;
+        Ok(())
+    }
+}
+
+impl Default for APU {
+    fn default() -> APU {
+        APU::new()
+    }
+}
@@ -0,0 +1,260 @@
+use std::io::{self, Write, Seek, SeekFrom, BufWriter};
+use std::fs::File;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
+
+// receives the APU's output as interleaved left and right samples
+pub trait AudioSink {
+    fn sample_rate(&self) -> u32;
+
+    fn write(&mut self, samples: &[f32]);
+
+    // stereo frames waiting to be played, for sinks that play in real
+    // time. the frontend paces emulation by this when it's available
+    fn queued(&self) -> Option<usize> {
+        None
+    }
+}
+
+// throws everything away, for running muted
+pub struct NullSink {
+    sample_rate: u32,
+}
+
+impl NullSink {
+    pub fn new(sample_rate: u32) -> NullSink {
+        NullSink { sample_rate }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write(&mut self, _samples: &[f32]) {}
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+// 16 bit stereo PCM. the sizes in the header are filled in by finish,
+// or when the sink is dropped
+pub struct WavSink<W: Write + Seek = BufWriter<File>> {
+    out: Option<W>,
+    sample_rate: u32,
+    data_size: u32,
+    error: Option<io::Error>,
+}
+
+impl WavSink {
+    pub fn create(path: &str, sample_rate: u32) -> io::Result<WavSink> {
+        WavSink::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<WavSink<W>> {
+        WavSink::write_header(&mut out, sample_rate, 0)?;
+        Ok(WavSink {
+            out: Some(out),
+            sample_rate,
+            data_size: 0,
+            error: None,
+        })
+    }
+
+    fn write_header(out: &mut W, sample_rate: u32, data_size: u32) -> io::Result<()> {
+        let channels: u16 = 2;
+        let block_align: u16 = channels * 2;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?; // bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&data_size.to_le_bytes())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let sample_rate = self.sample_rate;
+        let data_size = self.data_size;
+        match self.out.as_mut() {
+            Some(out) => {
+                out.seek(SeekFrom::Start(0))?;
+                WavSink::write_header(out, sample_rate, data_size)?;
+                out.seek(SeekFrom::End(0))?;
+                out.flush()
+            },
+            None => Ok(()),
+        }
+    }
+
+    // reports the first error that happened while writing
+    pub fn finish(mut self) -> io::Result<W> {
+        self.finalize()?;
+        Ok(self.out.take().unwrap())
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn write(&mut self, samples: &[f32]) {
+        let out = match (self.out.as_mut(), &self.error) {
+            (Some(out), None) => out,
+            _ => return,
+        };
+
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        match out.write_all(&bytes) {
+            Ok(_) => self.data_size += bytes.len() as u32,
+            Err(e) => self.error = Some(e),
+        }
+    }
+}
+
+impl<W: Write + Seek> Drop for WavSink<W> {
+    fn drop(&mut self) {
+        let _ = self.finalize();
+    }
+}
+
+// the host's audio clock and the emulated one never run at exactly the
+// same speed, so the APU's output rate is nudged to keep a real-time
+// sink's queue around its target instead of crackling when it runs dry
+pub struct RateControl {
+    pub target: usize,
+    pub max_delta: f64,
+}
+
+impl RateControl {
+    pub fn new(target: usize) -> RateControl {
+        RateControl {
+            target,
+            max_delta: 0.005,
+        }
+    }
+
+    // the ratio to hand to APU::set_rate_ratio for the frames queued
+    pub fn ratio(&self, queued: usize) -> f64 {
+        let fill = queued as f64 / self.target.max(1) as f64;
+        1.0 + self.max_delta * (1.0 - fill).clamp(-1.0, 1.0)
+    }
+}
+
+#[cfg(feature = "audio")]
+pub use self::cpal_sink::{CpalSink, AudioError};
+
+#[cfg(feature = "audio")]
+mod cpal_sink {
+    use super::AudioSink;
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use cpal::{Sample, SampleFormat, Stream, StreamConfig};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug)]
+    pub enum AudioError {
+        NoDevice,
+        Stream(String),
+    }
+
+    // plays through the host's default output device
+    pub struct CpalSink {
+        _stream: Stream,
+        queue: Arc<Mutex<VecDeque<f32>>>,
+        sample_rate: u32,
+        capacity: usize,
+    }
+
+    impl CpalSink {
+        // samples beyond max_queued stereo frames are dropped, which
+        // bounds the latency if emulation runs ahead of the device
+        pub fn new(max_queued: usize) -> Result<CpalSink, AudioError> {
+            let device = cpal::default_host()
+                .default_output_device()
+                .ok_or(AudioError::NoDevice)?;
+            let supported = device.default_output_config()
+                .map_err(|e| AudioError::Stream(e.to_string()))?;
+            let format = supported.sample_format();
+            let config = supported.config();
+
+            let queue = Arc::new(Mutex::new(VecDeque::new()));
+            let stream = match format {
+                SampleFormat::F32 => CpalSink::build::<f32>(&device, &config, queue.clone()),
+                SampleFormat::I16 => CpalSink::build::<i16>(&device, &config, queue.clone()),
+                SampleFormat::U16 => CpalSink::build::<u16>(&device, &config, queue.clone()),
+            }?;
+            stream.play().map_err(|e| AudioError::Stream(e.to_string()))?;
+
+            Ok(CpalSink {
+                _stream: stream,
+                queue,
+                sample_rate: config.sample_rate.0,
+                capacity: max_queued * 2,
+            })
+        }
+
+        fn build<T: Sample>(device: &cpal::Device, config: &StreamConfig,
+                queue: Arc<Mutex<VecDeque<f32>>>) -> Result<Stream, AudioError> {
+            let channels = config.channels as usize;
+            // an underrun holds the last sample rather than dropping to
+            // zero, which pops less
+            let mut last = (0.0, 0.0);
+            device.build_output_stream(
+                config,
+                move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+                    let mut queue = queue.lock().unwrap();
+                    for frame in data.chunks_mut(channels) {
+                        if queue.len() >= 2 {
+                            last = (queue.pop_front().unwrap(), queue.pop_front().unwrap());
+                        }
+                        match frame.len() {
+                            1 => frame[0] = Sample::from(&((last.0 + last.1) / 2.0)),
+                            _ => {
+                                frame[0] = Sample::from(&last.0);
+                                frame[1] = Sample::from(&last.1);
+                                for out in frame[2..].iter_mut() {
+                                    *out = Sample::from(&0.0f32);
+                                }
+                            },
+                        }
+                    }
+                },
+                |e| eprintln!("Audio stream error: {}", e),
+            ).map_err(|e| AudioError::Stream(e.to_string()))
+        }
+    }
+
+    impl AudioSink for CpalSink {
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+
+        fn write(&mut self, samples: &[f32]) {
+            let mut queue = self.queue.lock().unwrap();
+            queue.extend(samples);
+            while queue.len() > self.capacity {
+                queue.pop_front();
+            }
+        }
+
+        fn queued(&self) -> Option<usize> {
+            Some(self.queue.lock().unwrap().len() / 2)
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::fs::File;
+use std::process;
+use std::env;
+use ironboy::GameBoy;
+use ironboy::ppu::{SCREEN_W, SCREEN_H};
+use ironboy::rtc::RtcClock;
+use ironboy::audio::{AudioSink, WavSink, DEFAULT_SAMPLE_RATE};
+use ironboy::screenshot::save_png;
+use ironboy::recording::{Recorder, RecordingFormat};
+
+const USAGE: &str = "usage: headless [options] path_to_rom
+
+options:
+    --boot <path>           boot rom to use (default: roms/DMG_ROM.bin)
+    --frames <n>            number of frames to run, or the timeout when a
+                            condition is given (default: 3600)
+    --until-pc <addr>       stop once PC reaches the hex address
+    --until-serial <text>   stop once the text has been sent over serial
+    --screenshot <path>     write the final frame, as a PNG if the path
+                            ends in .png and a PPM otherwise
+    --wav <path>            record the audio to a WAV file
+    --record <path>         record every frame, as a GIF, Y4M or raw RGB
+                            video depending on the path's extension
+                            (.gif, .y4m or .rgb)
+
+exit status is 0 when the frames ran out without a condition or a
+condition was met, 1 on timeout, 2 on bad arguments and 3 when the CPU
+locked up on an unused opcode.";
+
+struct Options {
+    boot_path: String,
+    rom_path: String,
+    frames: u32,
+    until_pc: Option<u16>,
+    until_serial: Option<String>,
+    screenshot: Option<String>,
+    wav: Option<String>,
+    record: Option<(String, RecordingFormat)>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Exit {
+    Frames,
+    PC,
+    Serial,
+    Timeout,
+    Lockup,
+}
+
+fn usage_error(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+fn parse_args(args: Vec<String>) -> Options {
+    let mut options = Options {
+        boot_path: String::from("roms/DMG_ROM.bin"),
+        rom_path: String::new(),
+        frames: 3600,
+        until_pc: None,
+        until_serial: None,
+        screenshot: None,
+        wav: None,
+        record: None,
+    };
+
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            options.rom_path = arg;
+            continue;
+        }
+
+        let value = match args.next() {
+            Some(v) => v,
+            None => usage_error(&format!("Missing value for {}", arg)),
+        };
+        match arg.as_str() {
+            "--boot" => options.boot_path = value,
+            "--frames" => options.frames = match value.parse() {
+                Ok(n) => n,
+                Err(_) => usage_error(&format!("Invalid frame count: {}", value)),
+            },
+            "--until-pc" => options.until_pc = match u16::from_str_radix(value.trim_start_matches("0x"), 16) {
+                Ok(pc) => Some(pc),
+                Err(_) => usage_error(&format!("Invalid address: {}", value)),
+            },
+            "--until-serial" => options.until_serial = Some(value),
+            "--screenshot" => options.screenshot = Some(value),
+            "--wav" => options.wav = Some(value),
+            "--record" => options.record = match RecordingFormat::from_path(&value) {
+                Some(format) => Some((value, format)),
+                None => usage_error(&format!("Unknown recording format: {}", value)),
+            },
+            _ => usage_error(&format!("Unknown option: {}", arg)),
+        }
+    }
+
+    if options.rom_path.is_empty() {
+        usage_error("Missing path to rom");
+    }
+    options
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    let mut data = Vec::<u8>::new();
+    let result = File::open(path).and_then(|mut f| f.read_to_end(&mut data));
+    if let Err(e) = result {
+        eprintln!("{}: {}", path, e);
+        process::exit(2);
+    }
+    data
+}
+
+fn write_ppm(path: &str, frame: &[[u32; SCREEN_W]; SCREEN_H]) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "P6\n{} {}\n255\n", SCREEN_W, SCREEN_H)?;
+    for row in frame.iter() {
+        for pixel in row.iter() {
+            file.write_all(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8])?;
+        }
+    }
+    file.flush()
+}
+
+fn main() {
+    let options = parse_args(env::args().collect());
+
+    let mut gameboy = GameBoy::new(read_file(&options.boot_path));
+    if let Err(e) = gameboy.load_rom(read_file(&options.rom_path)) {
+        eprintln!("Error loading cartridge: {:?}", e);
+        process::exit(2);
+    }
+    // keep runs reproducible no matter when they happen
+    gameboy.mmu.cartridge.set_rtc_clock(RtcClock::Emulated);
+
+    let mut wav = options.wav.as_ref().map(|path| {
+        match WavSink::create(path, DEFAULT_SAMPLE_RATE) {
+            Ok(sink) => sink,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(2);
+            },
+        }
+    });
+    if let Some(ref sink) = wav {
+        gameboy.set_sample_rate(Some(sink.sample_rate()));
+    }
+
+    let mut recorder = options.record.as_ref().map(|(path, format)| {
+        match Recorder::create(path, *format) {
+            Ok(recorder) => recorder,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                process::exit(2);
+            },
+        }
+    });
+
+    let has_condition = options.until_pc.is_some() || options.until_serial.is_some();
+    let mut cycles: u32 = 0;
+    let mut frames: u32 = 0;
+
+    // frames end when the PPU finishes one, so recordings never catch it
+    // halfway through drawing, or after a frame's worth of cycles so the
+    // limit still applies while the LCD is off
+    let exit = loop {
+        cycles += gameboy.step() as u32;
+        if options.until_pc == Some(gameboy.cpu.reg.pc) {
+            break Exit::PC;
+        }
+        if let Some(lockup) = gameboy.take_lockup() {
+            eprintln!("{}", lockup);
+            break Exit::Lockup;
+        }
+
+        if !gameboy.take_frame(cycles) {
+            continue;
+        }
+        cycles = 0;
+        frames += 1;
+        if let Some(ref mut sink) = wav {
+            gameboy.drain_audio(sink);
+        }
+        if let Some(ref mut recorder) = recorder {
+            recorder.write_frame(gameboy.framebuffer());
+        }
+
+        if let Some(ref text) = options.until_serial {
+            if String::from_utf8_lossy(&gameboy.mmu.serial.output).contains(text.as_str()) {
+                break Exit::Serial;
+            }
+        }
+        if frames >= options.frames {
+            break if has_condition { Exit::Timeout } else { Exit::Frames };
+        }
+    };
+
+    let serial = &gameboy.mmu.serial.output;
+    if !serial.is_empty() {
+        println!("{}", String::from_utf8_lossy(serial));
+    }
+    println!("{}: {:?} after {} frames, PC={:#06X}",
+        options.rom_path, exit, frames, gameboy.cpu.reg.pc);
+
+    if let (Some(mut sink), Some(path)) = (wav, options.wav.as_ref()) {
+        gameboy.drain_audio(&mut sink);
+        if let Err(e) = sink.finish() {
+            eprintln!("{}: {}", path, e);
+        }
+    }
+
+    if let (Some(recorder), Some((path, _))) = (recorder, options.record.as_ref()) {
+        if let Err(e) = recorder.finish() {
+            eprintln!("{}: {}", path, e);
+        }
+    }
+
+    if let Some(ref path) = options.screenshot {
+        let result = if path.ends_with(".png") {
+            save_png(path, gameboy.framebuffer(), 1)
+        } else {
+            write_ppm(path, gameboy.framebuffer())
+        };
+        if let Err(e) = result {
+            eprintln!("{}: {}", path, e);
+        }
+    }
+
+    process::exit(match exit {
+        Exit::Timeout => 1,
+        Exit::Lockup => 3,
+        _ => 0,
+    });
+}
@@ -1,4 +1,6 @@
 use std::result::Result;
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+use crate::rtc::{Rtc, RtcClock, RTC_FOOTER_SIZE, RTC_FOOTER_SIZE_OLD};
 
 pub const KILOBYTE: u32 = 1024;
 pub const MEGABYTE: u32 = 1024 * KILOBYTE;
@@ -108,7 +110,7 @@ impl Mbc5 {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Mbc {
     NoMBC,
     MBC1 { mbc: Mbc1 },
@@ -120,18 +122,20 @@ pub enum Mbc {
 #[derive(Debug)]
 pub enum CartridgeError {
     MissingHeaderInformation,
-    UnsupportedROMType,
     UnsupportedROMSize,
     UnsupportedRAMSize,
     UnsupportedMBC,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Cartridge {
     pub title: String,
     rom: Vec<u8>,
     ram: Vec<u8>,
     mbc: Mbc,
+    rtc: Option<Rtc>,
+    battery: bool,
+    pub ram_dirty: bool,
     pub rom_offsets: (u32, u32),
     ram_offset: u32,
 }
@@ -142,10 +146,6 @@ impl Cartridge {
             return Err(CartridgeError::MissingHeaderInformation);
         }
 
-        if data[0x143] == 0xC0 {
-            return Err(CartridgeError::UnsupportedROMType)
-        }
-
         let rom_size = match data[0x148] {
             0x00 => 32 * KILOBYTE,  // no ROM banking
             0x01 => 64 * KILOBYTE,  // 4 banks
@@ -176,11 +176,18 @@ impl Cartridge {
                 ram_size = 512;
                 Mbc::MBC2 { mbc: Mbc2::setup() }
             },
-            0x11..=0x13 => Mbc::MBC3 { mbc: Mbc3::setup() },
+            0x0F..=0x13 => Mbc::MBC3 { mbc: Mbc3::setup() },
             0x19..=0x1E => Mbc::MBC5 { mbc: Mbc5::setup() },
             _ => return Err(CartridgeError::UnsupportedMBC)
         };
 
+        let rtc = match data[0x147] {
+            0x0F | 0x10 => Some(Rtc::new(RtcClock::WallClock)),
+            _ => None,
+        };
+
+        let battery = matches!(data[0x147], 0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0xFF);
+
         let title = match String::from_utf8(data[0x134..0x13F].to_owned()) {
             Err(e) => panic!("Error loading cartridge title: {}", e),
             Ok(s) => s,
@@ -191,6 +198,9 @@ impl Cartridge {
         Ok(Cartridge {
             title,
             mbc,
+            rtc,
+            battery,
+            ram_dirty: false,
             rom,
             ram: vec![0; ram_size],
             rom_offsets: (0x0000, 0x4000),
@@ -198,6 +208,21 @@ impl Cartridge {
         })
     }
 
+    // with no cartridge inserted, every read from the cartridge floats high
+    pub fn empty() -> Cartridge {
+        Cartridge {
+            title: String::new(),
+            mbc: Mbc::NoMBC,
+            rtc: None,
+            battery: false,
+            ram_dirty: false,
+            rom: vec![0xFF; 32 * KILOBYTE as usize],
+            ram: Vec::new(),
+            rom_offsets: (0x0000, 0x4000),
+            ram_offset: 0x0000,
+        }
+    }
+
     pub fn load_rom(data: Vec<u8>, rom_size: usize) -> Vec<u8> {
         let mut i: usize = 0;
         let mut rom = vec![0; rom_size];
@@ -211,6 +236,131 @@ impl Cartridge {
         rom
     }
 
+    // 0x80 runs on both models, 0xC0 only on the CGB
+    pub fn supports_cgb(&self) -> bool {
+        matches!(self.rom[0x143], 0x80 | 0xC0)
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery && (!self.ram.is_empty() || self.rtc.is_some())
+    }
+
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.clock = clock;
+        }
+    }
+
+    pub fn tick(&mut self, m_clocks: u8) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.tick(m_clocks);
+        }
+    }
+
+    // the raw contents of RAM followed by the RTC footer if there is a clock,
+    // the same .sav layout other emulators use
+    pub fn battery_save(&self) -> Vec<u8> {
+        let mut save = self.ram.clone();
+        if let Some(ref rtc) = self.rtc {
+            save.extend_from_slice(&rtc.footer());
+        }
+        save
+    }
+
+    // saves of a different size are loaded as far as they fit
+    pub fn load_battery_save(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+
+        let footer = &data[len..];
+        if let Some(ref mut rtc) = self.rtc {
+            if footer.len() == RTC_FOOTER_SIZE || footer.len() == RTC_FOOTER_SIZE_OLD {
+                rtc.load_footer(footer);
+            }
+        }
+    }
+
+    // title, manufacturer, flags and checksums; enough to tell
+    // whether a save state belongs to this cartridge
+    pub fn header(&self) -> &[u8] {
+        &self.rom[0x134..0x150]
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        match self.mbc {
+            Mbc::NoMBC => w.write_u8(0),
+            Mbc::MBC1 { ref mbc } => {
+                w.write_u8(1);
+                w.write_bool(mbc.ram_enabled);
+                w.write_u8(mbc.bank1);
+                w.write_u8(mbc.bank2);
+                w.write_bool(mbc.mode);
+            },
+            Mbc::MBC2 { ref mbc } => {
+                w.write_u8(2);
+                w.write_bool(mbc.ram_enabled);
+                w.write_u8(mbc.bank);
+            },
+            Mbc::MBC3 { ref mbc } => {
+                w.write_u8(3);
+                w.write_bool(mbc.ram_enabled);
+                w.write_u8(mbc.romb);
+                w.write_u8(mbc.ramb);
+            },
+            Mbc::MBC5 { ref mbc } => {
+                w.write_u8(5);
+                w.write_bool(mbc.ram_enabled);
+                w.write_u8(mbc.bank0);
+                w.write_u8(mbc.bank1);
+                w.write_u8(mbc.ramb);
+            },
+        }
+        w.write_u32(self.rom_offsets.0);
+        w.write_u32(self.rom_offsets.1);
+        w.write_u32(self.ram_offset);
+        w.write_vec(&self.ram);
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(w);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let kind = r.read_u8()?;
+        match self.mbc {
+            Mbc::NoMBC if kind == 0 => {},
+            Mbc::MBC1 { ref mut mbc } if kind == 1 => {
+                mbc.ram_enabled = r.read_bool()?;
+                mbc.bank1 = r.read_u8()?;
+                mbc.bank2 = r.read_u8()?;
+                mbc.mode = r.read_bool()?;
+            },
+            Mbc::MBC2 { ref mut mbc } if kind == 2 => {
+                mbc.ram_enabled = r.read_bool()?;
+                mbc.bank = r.read_u8()?;
+            },
+            Mbc::MBC3 { ref mut mbc } if kind == 3 => {
+                mbc.ram_enabled = r.read_bool()?;
+                mbc.romb = r.read_u8()?;
+                mbc.ramb = r.read_u8()?;
+            },
+            Mbc::MBC5 { ref mut mbc } if kind == 5 => {
+                mbc.ram_enabled = r.read_bool()?;
+                mbc.bank0 = r.read_u8()?;
+                mbc.bank1 = r.read_u8()?;
+                mbc.ramb = r.read_u8()?;
+            },
+            _ => return Err(SaveStateError::WrongCartridge),
+        }
+        self.rom_offsets = (r.read_u32()?, r.read_u32()?);
+        self.ram_offset = r.read_u32()?;
+        self.ram_dirty = true;
+        r.read_vec(&mut self.ram)?;
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load_state(r)?;
+        }
+        Ok(())
+    }
+
     pub fn read_lower_rom(&self, address: u16) -> u8 {
         let (lower, _) = self.rom_offsets;
         let address_in_bank = (address & 0x3FFF) as usize;
@@ -273,8 +423,16 @@ impl Cartridge {
                         self.rom_offsets = mbc.get_rom_offsets();
                     },
                     0x4000..=0x5FFF => {
-                        mbc.ramb = value & 0b11;
-                        self.ram_offset = mbc.get_ram_offset();
+                        // 0x08-0x0C map an RTC register in place of RAM
+                        mbc.ramb = value & 0x0F;
+                        if mbc.ramb <= 0x03 {
+                            self.ram_offset = mbc.get_ram_offset();
+                        }
+                    },
+                    0x6000..=0x7FFF => {
+                        if let Some(ref mut rtc) = self.rtc {
+                            rtc.latch(value);
+                        }
                     },
                     _ => ()
                 }
@@ -321,8 +479,11 @@ impl Cartridge {
             },
             Mbc::MBC3 { ref mbc } => {
                 if mbc.ram_enabled {
-                    match mbc.ramb {
-                        0x00..=0x03 => self.ram[physical_address & (self.ram.len() - 1)],
+                    match (mbc.ramb, self.rtc.as_ref()) {
+                        (0x00..=0x03, _) if !self.ram.is_empty() => {
+                            self.ram[physical_address & (self.ram.len() - 1)]
+                        },
+                        (0x08..=0x0C, Some(rtc)) => rtc.read(mbc.ramb - 0x08),
                         _ => undefined
                     }
                 } else {
@@ -347,18 +508,27 @@ impl Cartridge {
                 if mbc.ram_enabled {
                     let len = self.ram.len() - 1;
                     self.ram[physical_address & len] = value;
+                    self.ram_dirty = true;
                 }
             },
             Mbc::MBC2 { ref mbc } => {
                 if mbc.ram_enabled {
                     self.ram[physical_address & 0x1FF] = value & 0x0F;
+                    self.ram_dirty = true;
                 }
             },
             Mbc::MBC3 { ref mbc } => {
                 if mbc.ram_enabled {
-                    let len = self.ram.len() - 1;
-                    match mbc.ramb {
-                        0x00..=0x03 => self.ram[physical_address & len] = value,
+                    match (mbc.ramb, self.rtc.as_mut()) {
+                        (0x00..=0x03, _) if !self.ram.is_empty() => {
+                            let len = self.ram.len() - 1;
+                            self.ram[physical_address & len] = value;
+                            self.ram_dirty = true;
+                        },
+                        (0x08..=0x0C, Some(rtc)) => {
+                            rtc.write(mbc.ramb - 0x08, value);
+                            self.ram_dirty = true;
+                        },
                         _ => (),
                     }
                 }
@@ -367,6 +537,7 @@ impl Cartridge {
                 if mbc.ram_enabled {
                     let len = self.ram.len() - 1;
                     self.ram[physical_address & len] = value;
+                    self.ram_dirty = true;
                 }
             }
         }
@@ -0,0 +1,171 @@
+use crate::ppu::{DmgPalette, Preset};
+use crate::recording::RecordingFormat;
+use crate::filter::{Upscaler, MAX_SCALE};
+use std::fs;
+use std::io;
+
+// looked for in the working directory, next to roms/
+pub const CONFIG_PATH: &str = "ironboy.cfg";
+
+// what the window does when the CPU locks up on an unused opcode: keep
+// running like the hardware would, or pause with the registers printed
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LockupAction {
+    Freeze,
+    Break,
+}
+
+impl LockupAction {
+    pub fn from_name(name: &str) -> Option<LockupAction> {
+        match name {
+            "freeze" => Some(LockupAction::Freeze),
+            "break" => Some(LockupAction::Break),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    // the line number, counting from 1, and the offending text
+    UnknownKey(usize, String),
+    InvalidValue(usize, String),
+}
+
+// one `key = value` per line, and lines starting with # are comments:
+//
+//     palette = pocket
+//     bg_palette = e0f8d0 88c070 346856 081820
+//
+// palette picks a preset, and bg_palette, obj0_palette and obj1_palette
+// override its colours, lightest first. screenshot_scale makes screenshots
+// bigger than the 160x144 screen, and record_format is gif, y4m or raw.
+//
+// the window is scale times the screen, filled by filter (none, scale2x,
+// scale3x or smooth2x) when the scale is a multiple of its own. lcd_grid
+// draws the gaps between the LCD's dots and ghosting leaves that much
+// percent of the last frame behind, like the DMG's slow LCD.
+//
+// on_lockup = break pauses the window when the CPU locks up, where freeze
+// leaves the screen running like the hardware
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub palette: DmgPalette,
+    pub screenshot_scale: usize,
+    pub record_format: RecordingFormat,
+    pub scale: usize,
+    pub filter: Upscaler,
+    pub lcd_grid: bool,
+    pub ghosting: u32,
+    pub on_lockup: LockupAction,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            palette: DmgPalette::default(),
+            screenshot_scale: 1,
+            record_format: RecordingFormat::Gif,
+            scale: 2,
+            filter: Upscaler::None,
+            lcd_grid: false,
+            ghosting: 0,
+            on_lockup: LockupAction::Freeze,
+        }
+    }
+
+    // a missing file gives the defaults
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::new()),
+            Err(e) => Err(ConfigError::Io(e)),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::new();
+        let mut preset = Preset::Grey;
+        let mut overrides: [Option<[u32; 4]>; 3] = [None; 3];
+        // checked against the scale once everything's read
+        let mut filter_line = 0;
+
+        for (i, line) in text.lines().enumerate() {
+            let n = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(ConfigError::InvalidValue(n, line.to_string())),
+            };
+            let invalid = || ConfigError::InvalidValue(n, value.to_string());
+            match key {
+                "palette" => preset = Preset::from_name(value).ok_or_else(invalid)?,
+                "bg_palette" => overrides[0] = Some(Config::colors(value).ok_or_else(invalid)?),
+                "obj0_palette" => overrides[1] = Some(Config::colors(value).ok_or_else(invalid)?),
+                "obj1_palette" => overrides[2] = Some(Config::colors(value).ok_or_else(invalid)?),
+                "screenshot_scale" => config.screenshot_scale = match value.parse() {
+                    Ok(scale) if (1..=8).contains(&scale) => scale,
+                    _ => return Err(invalid()),
+                },
+                "record_format" => config.record_format = RecordingFormat::from_name(value).ok_or_else(invalid)?,
+                "scale" => config.scale = match value.parse() {
+                    Ok(scale) if (1..=MAX_SCALE).contains(&scale) => scale,
+                    _ => return Err(invalid()),
+                },
+                "filter" => {
+                    config.filter = Upscaler::from_name(value).ok_or_else(invalid)?;
+                    filter_line = n;
+                },
+                "lcd_grid" => config.lcd_grid = match value {
+                    "on" | "true" => true,
+                    "off" | "false" => false,
+                    _ => return Err(invalid()),
+                },
+                "ghosting" => config.ghosting = match value.trim_end_matches('%').parse() {
+                    Ok(percent) if percent <= 100 => percent,
+                    _ => return Err(invalid()),
+                },
+                "on_lockup" => config.on_lockup = LockupAction::from_name(value).ok_or_else(invalid)?,
+                _ => return Err(ConfigError::UnknownKey(n, key.to_string())),
+            }
+        }
+
+        if !config.filter.fits(config.scale) {
+            return Err(ConfigError::InvalidValue(filter_line, config.filter.name().to_string()));
+        }
+
+        let palette = &mut config.palette;
+        *palette = preset.palette();
+        palette.bg = overrides[0].unwrap_or(palette.bg);
+        palette.obj0 = overrides[1].unwrap_or(palette.obj0);
+        palette.obj1 = overrides[2].unwrap_or(palette.obj1);
+        Ok(config)
+    }
+
+    // four RRGGBB colours, optionally starting with #
+    fn colors(value: &str) -> Option<[u32; 4]> {
+        let mut colors = [0; 4];
+        let mut parts = value.split_whitespace();
+        for color in colors.iter_mut() {
+            let hex = parts.next()?.trim_start_matches('#');
+            if hex.len() != 6 {
+                return None;
+            }
+            *color = 0xFF00_0000 | u32::from_str_radix(hex, 16).ok()?;
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(colors),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config::new()
+    }
+}
@@ -1,54 +1,50 @@
 use crate::mmu::MMU;
 use crate::timer::Timer;
 use crate::registers::{Registers, Flag};
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::fmt;
 
-pub const CLOCKS: [u8; 256] = [
-    1,3,2,2,1,1,2,1,5,2,2,2,1,1,2,1,
-    0,3,2,2,1,1,2,1,3,2,2,2,1,1,2,1,
-    2,3,2,2,1,1,2,1,2,2,2,2,1,1,2,1,
-    2,3,2,2,3,3,3,1,2,2,2,2,1,1,2,1,
-    1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
-    1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
-    1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
-    2,2,2,2,2,2,0,2,1,1,1,1,1,1,2,1,
-    1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
-    1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
-    1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
-    1,1,1,1,1,1,2,1,1,1,1,1,1,1,2,1,
-    2,3,3,4,3,4,2,4,2,4,3,0,3,6,2,4,
-    2,3,3,0,3,4,2,4,2,4,3,0,3,0,2,4,
-    3,3,2,0,0,4,2,4,4,1,4,0,0,0,2,4,
-    3,3,2,1,0,4,2,4,3,2,4,1,0,0,2,4,
-];
+// the rest of the machine as the CPU sees it
+pub trait Bus {
+    fn mmu(&mut self) -> &mut MMU;
+    // lets everything else run for one of the CPU's M-cycles
+    fn tick(&mut self);
+    // one M-cycle with the clock stopped, in which nothing runs
+    fn sleep(&mut self) {}
+}
+
+// where the CPU hung after fetching one of the unused opcodes. the
+// hardware never fetches another instruction, while the rest keeps going
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Lockup {
+    pub pc: u16,
+    pub opcode: u8,
+}
 
-pub const CB_CLOCKS: [u8; 256] = [
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,3,2,2,2,2,2,2,2,3,2,
-    2,2,2,2,2,2,3,2,2,2,2,2,2,2,3,2,
-    2,2,2,2,2,2,3,2,2,2,2,2,2,2,3,2,
-    2,2,2,2,2,2,3,2,2,2,2,2,2,2,3,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-    2,2,2,2,2,2,4,2,2,2,2,2,2,2,4,2,
-];
+impl fmt::Display for Lockup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CPU locked at PC={:#06X} opcode={:#04X}", self.pc, self.opcode)
+    }
+}
 
 pub struct CPU {
     pub reg: Registers,
     ime: bool,
     halt: bool,
+    // STOP mode, left when a joypad line goes low
+    stopped: bool,
+    pub lockup: Option<Lockup>,
     pub timer: Rc<RefCell<Timer>>,
     pub clocks_elapsed: u8,
-    last_instr: Instruction,
+    // EI only enables interrupts once the instruction after it is done
+    ime_scheduled: bool,
+    // HALT with interrupts disabled and one already pending doesn't halt,
+    // but the byte after it is fetched without moving PC on
+    halt_bug: bool,
+    // the address and first byte of the instruction being executed
+    fetched: (u16, u8),
 }
 
 impl CPU {
@@ -57,66 +53,85 @@ impl CPU {
             reg: Registers::new(),
             ime: false,
             halt: false,
+            stopped: false,
+            lockup: None,
             timer,
             clocks_elapsed: 0,
-            last_instr: Instruction::NULL,
+            ime_scheduled: false,
+            halt_bug: false,
+            fetched: (0, 0),
         }
     }
 
-    pub fn execute(&mut self, mmu: &mut MMU, instr: Instruction) {
+    pub fn execute(&mut self, bus: &mut impl Bus, instr: Instruction) {
         match instr {
             Instruction::LD(t1, t2) => {
                 match (t1, t2) {
-                    (Target::SP, Target::IMM16) => self.reg.sp = self.get_imm16(mmu),
+                    (Target::SP, Target::IMM16) => self.reg.sp = self.get_imm16(bus),
                     (Target::BC, Target::IMM16) => {
-                        let imm16 = self.get_imm16(mmu);
+                        let imm16 = self.get_imm16(bus);
                         self.reg.set_bc(imm16)
                     },
                     (Target::DE, Target::IMM16) => {
-                        let imm16 = self.get_imm16(mmu);
+                        let imm16 = self.get_imm16(bus);
                         self.reg.set_de(imm16)
                     },
                     (Target::HL, Target::IMM16) => {
-                        let imm16 = self.get_imm16(mmu);
+                        let imm16 = self.get_imm16(bus);
                         self.reg.set_hl(imm16)
                     },
                     (Target::IMM16, Target::SP) => {
                         let lo = (self.reg.sp & 0x00FF) as u8;
                         let hi = ((self.reg.sp & 0xFF00) >> 8) as u8;
-                        let nn = self.get_imm16(mmu);
-                        mmu.wb(nn, lo);
-                        mmu.wb(nn + 1, hi);
-                    },
-                    (Target::HLI, Target::A) => mmu.wb(self.reg.hli(), self.reg.a),
-                    (Target::HLD, Target::A) => mmu.wb(self.reg.hld(), self.reg.a),
-                    (Target::A, Target::HLI) => self.reg.a = mmu.rb(self.reg.hli()),
-                    (Target::A, Target::HLD) => self.reg.a = mmu.rb(self.reg.hld()),
-                    (Target::A, Target::HL) => self.reg.a = mmu.rb(self.reg.hl()),
-                    (Target::B, Target::HL) => self.reg.b = mmu.rb(self.reg.hl()),
-                    (Target::C, Target::HL) => self.reg.c = mmu.rb(self.reg.hl()),
-                    (Target::D, Target::HL) => self.reg.d = mmu.rb(self.reg.hl()),
-                    (Target::E, Target::HL) => self.reg.e = mmu.rb(self.reg.hl()),
-                    (Target::H, Target::HL) => self.reg.h = mmu.rb(self.reg.hl()),
-                    (Target::L, Target::HL) => self.reg.l = mmu.rb(self.reg.hl()),
-                    (Target::BC, Target::A) => mmu.wb(self.reg.bc(), self.reg.a),
-                    (Target::DE, Target::A) => mmu.wb(self.reg.de(), self.reg.a),
-                    (Target::HL, Target::A) => mmu.wb(self.reg.hl(), self.reg.a),
-                    (Target::HL, Target::B) => mmu.wb(self.reg.hl(), self.reg.b),
-                    (Target::HL, Target::C) => mmu.wb(self.reg.hl(), self.reg.c),
-                    (Target::HL, Target::D) => mmu.wb(self.reg.hl(), self.reg.d),
-                    (Target::HL, Target::E) => mmu.wb(self.reg.hl(), self.reg.e),
-                    (Target::HL, Target::H) => mmu.wb(self.reg.hl(), self.reg.h),
-                    (Target::HL, Target::L) => mmu.wb(self.reg.hl(), self.reg.l),
-                    (Target::A, Target::BC) => self.reg.a = mmu.rb(self.reg.bc()),
-                    (Target::A, Target::DE) => self.reg.a = mmu.rb(self.reg.de()),
-                    (Target::A, Target::IMM8) => self.reg.a = self.get_imm8(mmu),
-                    (Target::B, Target::IMM8) => self.reg.b = self.get_imm8(mmu),
-                    (Target::C, Target::IMM8) => self.reg.c = self.get_imm8(mmu),
-                    (Target::D, Target::IMM8) => self.reg.d = self.get_imm8(mmu),
-                    (Target::E, Target::IMM8) => self.reg.e = self.get_imm8(mmu),
-                    (Target::H, Target::IMM8) => self.reg.h = self.get_imm8(mmu),
-                    (Target::L, Target::IMM8) => self.reg.l = self.get_imm8(mmu),
-                    (Target::HL, Target::IMM8) => mmu.wb(self.reg.hl(), self.get_imm8(mmu)),
+                        let nn = self.get_imm16(bus);
+                        self.write(bus, nn, lo);
+                        self.write(bus, nn + 1, hi);
+                    },
+                    (Target::HLI, Target::A) => {
+                        let address = self.reg.hli();
+                        self.write(bus, address, self.reg.a);
+                    },
+                    (Target::HLD, Target::A) => {
+                        let address = self.reg.hld();
+                        self.write(bus, address, self.reg.a);
+                    },
+                    (Target::A, Target::HLI) => {
+                        let address = self.reg.hli();
+                        self.reg.a = self.read(bus, address);
+                    },
+                    (Target::A, Target::HLD) => {
+                        let address = self.reg.hld();
+                        self.reg.a = self.read(bus, address);
+                    },
+                    (Target::A, Target::HL) => self.reg.a = self.read(bus, self.reg.hl()),
+                    (Target::B, Target::HL) => self.reg.b = self.read(bus, self.reg.hl()),
+                    (Target::C, Target::HL) => self.reg.c = self.read(bus, self.reg.hl()),
+                    (Target::D, Target::HL) => self.reg.d = self.read(bus, self.reg.hl()),
+                    (Target::E, Target::HL) => self.reg.e = self.read(bus, self.reg.hl()),
+                    (Target::H, Target::HL) => self.reg.h = self.read(bus, self.reg.hl()),
+                    (Target::L, Target::HL) => self.reg.l = self.read(bus, self.reg.hl()),
+                    (Target::BC, Target::A) => self.write(bus, self.reg.bc(), self.reg.a),
+                    (Target::DE, Target::A) => self.write(bus, self.reg.de(), self.reg.a),
+                    (Target::HL, Target::A) => self.write(bus, self.reg.hl(), self.reg.a),
+                    (Target::HL, Target::B) => self.write(bus, self.reg.hl(), self.reg.b),
+                    (Target::HL, Target::C) => self.write(bus, self.reg.hl(), self.reg.c),
+                    (Target::HL, Target::D) => self.write(bus, self.reg.hl(), self.reg.d),
+                    (Target::HL, Target::E) => self.write(bus, self.reg.hl(), self.reg.e),
+                    (Target::HL, Target::H) => self.write(bus, self.reg.hl(), self.reg.h),
+                    (Target::HL, Target::L) => self.write(bus, self.reg.hl(), self.reg.l),
+                    (Target::A, Target::BC) => self.reg.a = self.read(bus, self.reg.bc()),
+                    (Target::A, Target::DE) => self.reg.a = self.read(bus, self.reg.de()),
+                    (Target::A, Target::IMM8) => self.reg.a = self.get_imm8(bus),
+                    (Target::B, Target::IMM8) => self.reg.b = self.get_imm8(bus),
+                    (Target::C, Target::IMM8) => self.reg.c = self.get_imm8(bus),
+                    (Target::D, Target::IMM8) => self.reg.d = self.get_imm8(bus),
+                    (Target::E, Target::IMM8) => self.reg.e = self.get_imm8(bus),
+                    (Target::H, Target::IMM8) => self.reg.h = self.get_imm8(bus),
+                    (Target::L, Target::IMM8) => self.reg.l = self.get_imm8(bus),
+                    (Target::HL, Target::IMM8) => {
+                        let imm8 = self.get_imm8(bus);
+                        self.write(bus, self.reg.hl(), imm8);
+                    },
                     (Target::B, Target::A) => self.reg.b = self.reg.a,
                     (Target::B, Target::B) => self.reg.b = self.reg.b,
                     (Target::B, Target::C) => self.reg.b = self.reg.c,
@@ -167,25 +182,32 @@ impl CPU {
                     (Target::A, Target::H) => self.reg.a = self.reg.h,
                     (Target::A, Target::L) => self.reg.a = self.reg.l,
                     (Target::FFIMM8, Target::A) => {
-                        let address: u16 = 0xFF00 | (self.get_imm8(mmu) as u16);
-                        mmu.wb(address, self.reg.a);
+                        let address: u16 = 0xFF00 | (self.get_imm8(bus) as u16);
+                        self.write(bus, address, self.reg.a);
                     },
                     (Target::FFC, Target::A) => {
                         let address: u16 = 0xFF00 | (self.reg.c as u16);
-                        mmu.wb(address, self.reg.a);
+                        self.write(bus, address, self.reg.a);
                     },
                     (Target::A, Target::FFIMM8) => {
-                        let v =  mmu.rb(0xFF00 | (self.get_imm8(mmu) as u16));
+                        let address = 0xFF00 | (self.get_imm8(bus) as u16);
+                        let v = self.read(bus, address);
                         self.reg.a = v;
                     },
                     (Target::A, Target::FFC) => {
-                        let v = mmu.rb(0xFF00 | self.reg.c as u16);
+                        let v = self.read(bus, 0xFF00 | self.reg.c as u16);
                         self.reg.a = v;
                     },
-                    (Target::IMM16, Target::A) => mmu.wb(self.get_imm16(mmu), self.reg.a),
-                    (Target::A, Target::IMM16) => self.reg.a = mmu.rb(self.get_imm16(mmu)),
+                    (Target::IMM16, Target::A) => {
+                        let address = self.get_imm16(bus);
+                        self.write(bus, address, self.reg.a);
+                    },
+                    (Target::A, Target::IMM16) => {
+                        let address = self.get_imm16(bus);
+                        self.reg.a = self.read(bus, address);
+                    },
                     (Target::HL, Target::SP) => {
-                        let imm8 = self.get_imm8(mmu) as i8 as i16 as u16;
+                        let imm8 = self.get_imm8(bus) as i8 as i16 as u16;
                         let v = self.reg.sp.wrapping_add(imm8);
                         let hc = (self.reg.sp & 0xF) + (imm8 & 0xF) > 0xF;
                         let c = (self.reg.sp & 0xFF) + (imm8 & 0xFF) > 0xFF;
@@ -194,12 +216,20 @@ impl CPU {
                         self.reg.set_flag(Flag::C, c);
                         self.reg.set_flag(Flag::H, hc);
                         self.reg.set_hl(v);
+                        self.idle(bus);
+                    },
+                    (Target::SP, Target::HL) => {
+                        self.reg.sp = self.reg.hl();
+                        self.idle(bus);
                     },
-                    (Target::SP, Target::HL) => self.reg.sp = self.reg.hl(),
                     _ => unreachable!()
                 }
             },
             Instruction::INC(t) => {
+                // the 16 bit ones go through the address incrementer, which takes a cycle
+                if matches!(t, Target::BC | Target::DE | Target::HL | Target::SP) {
+                    self.idle(bus);
+                }
                 match t {
                     Target::A => {
                         let a = self.reg.a.wrapping_add(1);
@@ -255,17 +285,20 @@ impl CPU {
                     Target::HL => self.reg.set_hl(self.reg.hl().wrapping_add(1)),
                     Target::SP => self.reg.sp = self.reg.sp.wrapping_add(1),
                     Target::AtHL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let new_hl = at_hl.wrapping_add(1);
                         self.reg.set_flag(Flag::Z, new_hl == 0);
                         self.reg.set_flag(Flag::N, false);
                         self.reg.set_flag(Flag::H, (at_hl & 0x0F) == 0x0F);
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                     },
                     _ => unreachable!()
                 }
             },
             Instruction::DEC(t) => {
+                if matches!(t, Target::BC | Target::DE | Target::HL | Target::SP) {
+                    self.idle(bus);
+                }
                 match t {
                     Target::A => {
                         let a = self.reg.a.wrapping_sub(1);
@@ -321,12 +354,12 @@ impl CPU {
                     Target::HL => self.reg.set_hl(self.reg.hl().wrapping_sub(1)),
                     Target::SP => self.reg.sp = self.reg.sp.wrapping_sub(1),
                     Target::AtHL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let new_hl = at_hl.wrapping_sub(1);
                         self.reg.set_flag(Flag::Z, new_hl == 0);
                         self.reg.set_flag(Flag::N, true);
                         self.reg.set_flag(Flag::H, (at_hl & 0x0F) == 0);
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                     },
                     _ => unreachable!()
                 }
@@ -340,8 +373,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -360,8 +393,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -380,8 +413,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -400,8 +433,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -414,7 +447,7 @@ impl CPU {
                 self.reg.a = v;
             },
             Instruction::ADDSP => {
-                let imm8 = self.get_imm8(mmu) as i8 as i16 as u16;
+                let imm8 = self.get_imm8(bus) as i8 as i16 as u16;
                 let v = self.reg.sp.wrapping_add(imm8);
                 let hc = (self.reg.sp & 0xF) + (imm8 & 0xF) > 0xF;
                 let c = (self.reg.sp & 0xFF) + (imm8 & 0xFF) > 0xFF;
@@ -423,6 +456,9 @@ impl CPU {
                 self.reg.set_flag(Flag::C, c);
                 self.reg.set_flag(Flag::H, hc);
                 self.reg.sp = v;
+                // one cycle for each byte of SP
+                self.idle(bus);
+                self.idle(bus);
             },
             Instruction::ADC(t) => {
                 let operand = match t {
@@ -433,8 +469,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -463,6 +499,7 @@ impl CPU {
                 self.reg.set_flag(Flag::C, c);
                 self.reg.set_flag(Flag::H, hc);
                 self.reg.set_hl(v);
+                self.idle(bus);
             },
             Instruction::SUB(t) => {
                 let operand = match t {
@@ -473,8 +510,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -495,8 +532,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -519,8 +556,8 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
-                    Target::IMM8 => self.get_imm8(mmu),
+                    Target::HL => self.read(bus, self.reg.hl()),
+                    Target::IMM8 => self.get_imm8(bus),
                     _ => unreachable!()
                 };
 
@@ -540,7 +577,7 @@ impl CPU {
                     Target::E  => self.reg.e,
                     Target::H  => self.reg.h,
                     Target::L  => self.reg.l,
-                    Target::HL => mmu.rb(self.reg.hl()),
+                    Target::HL => self.read(bus, self.reg.hl()),
                     _ => unreachable!()
                 };
 
@@ -559,8 +596,8 @@ impl CPU {
                     Target::H  => self.reg.h &= mask,
                     Target::L  => self.reg.l &= mask,
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
-                        mmu.wb(self.reg.hl(), at_hl & mask);
+                        let at_hl = self.read(bus, self.reg.hl());
+                        self.write(bus, self.reg.hl(), at_hl & mask);
                     },
                     _ => unreachable!()
                 }
@@ -576,8 +613,8 @@ impl CPU {
                     Target::H  => self.reg.h |= mask,
                     Target::L  => self.reg.l |= mask,
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
-                        mmu.wb(self.reg.hl(), at_hl | mask);
+                        let at_hl = self.read(bus, self.reg.hl());
+                        self.write(bus, self.reg.hl(), at_hl | mask);
                     },
                     _ => unreachable!()
                 }
@@ -592,34 +629,29 @@ impl CPU {
                     _ => unreachable!()
                 };
 
-                let jump = self.get_imm8(mmu) as i8;
+                let jump = self.get_imm8(bus) as i8;
                 if should_jump {
-                    if f != JumpFlag::A {
-                        self.clocks_elapsed += 1;
-                        self.timer.borrow_mut().tick();
-                    }
                     self.reg.pc = self.reg.pc.wrapping_add(jump as u16);
+                    self.idle(bus);
                 }
             },
             Instruction::JP(f) => {
-                let mut jump = self.get_imm16(mmu);
+                // JP HL has no operand, and PC is loaded straight from HL
+                if f == JumpFlag::AtHL {
+                    self.reg.pc = self.reg.hl();
+                    return;
+                }
+                let jump = self.get_imm16(bus);
                 let should_jump = match f {
                     JumpFlag::NZ => !self.reg.get_flag(Flag::Z),
                     JumpFlag::NC => !self.reg.get_flag(Flag::C),
                     JumpFlag::Z => self.reg.get_flag(Flag::Z),
                     JumpFlag::C => self.reg.get_flag(Flag::C),
-                    JumpFlag::A => true,
-                    JumpFlag::AtHL => {
-                        jump = self.reg.hl();
-                        true
-                    },
+                    _ => true,
                 };
 
                 if should_jump {
-                    if f != JumpFlag::AtHL && f != JumpFlag::A {
-                        self.clocks_elapsed += 1;
-                        self.timer.borrow_mut().tick();
-                    }
+                    self.idle(bus);
                     self.reg.pc = jump;
                 }
             },
@@ -633,13 +665,9 @@ impl CPU {
                     _ => unreachable!()
                 };
 
-                let jump = self.get_imm16(mmu);
+                let jump = self.get_imm16(bus);
                 if should_jump {
-                    if f != JumpFlag::A {
-                        self.clocks_elapsed += 3;
-                        self.timer.borrow_mut().tick_n(3);
-                    }
-                    self.push(mmu, self.reg.pc);
+                    self.push(bus, self.reg.pc);
                     self.reg.pc = jump
                 }
             },
@@ -653,25 +681,26 @@ impl CPU {
                     _ => unreachable!()
                 };
 
+                // checking the condition takes a cycle of its own
+                if f != JumpFlag::A {
+                    self.idle(bus);
+                }
                 if should_jump {
-                    if f != JumpFlag::A {
-                        self.clocks_elapsed += 3;
-                        self.timer.borrow_mut().tick_n(3);
-                    }
-                    self.reg.pc = self.pop(mmu);
+                    self.reg.pc = self.pop(bus);
+                    self.idle(bus);
                 }
             },
             Instruction::PUSH(t) => {
                 match t {
-                    Target::AF => self.push(mmu, self.reg.af()),
-                    Target::BC => self.push(mmu, self.reg.bc()),
-                    Target::DE => self.push(mmu, self.reg.de()),
-                    Target::HL => self.push(mmu, self.reg.hl()),
+                    Target::AF => self.push(bus, self.reg.af()),
+                    Target::BC => self.push(bus, self.reg.bc()),
+                    Target::DE => self.push(bus, self.reg.de()),
+                    Target::HL => self.push(bus, self.reg.hl()),
                     _ => unreachable!()
                 }
             },
             Instruction::POP(t) => {
-                let pop = self.pop(mmu);
+                let pop = self.pop(bus);
                 match t {
                     Target::AF => self.reg.set_af(pop),
                     Target::BC => self.reg.set_bc(pop),
@@ -681,7 +710,7 @@ impl CPU {
                 }
             },
             Instruction::RST(t) => {
-                self.push(mmu, self.reg.pc);
+                self.push(bus, self.reg.pc);
                 self.reg.pc = t as u16;
             },
             Instruction::RL(t) => {
@@ -723,10 +752,10 @@ impl CPU {
                         (self.reg.l, c)
                     },
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let c = (at_hl & 0x80) != 0;
                         let new_hl = (at_hl << 1) + cf;
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                         (new_hl, c)
                     }
                     _ => unreachable!()
@@ -775,10 +804,10 @@ impl CPU {
                         (self.reg.l, c)
                     },
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let c = (at_hl & 0x80) != 0;
                         let new_hl = (at_hl << 1) + c as u8;
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                         (new_hl, c)
                     },
                     _ => unreachable!()
@@ -827,10 +856,10 @@ impl CPU {
                         (self.reg.l, c)
                     },
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let c = (at_hl & 0x01) != 0;
                         let new_hl = at_hl.rotate_right(1);
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                         (new_hl, c)
                     },
                     _ => unreachable!()
@@ -880,10 +909,10 @@ impl CPU {
                         (self.reg.l, c)
                     },
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let c = (at_hl & 0x01) != 0;
                         let new_hl = ((cf as u8) << 7) | (at_hl >> 1);
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                         (new_hl, c)
                     },
                     _ => unreachable!()
@@ -932,10 +961,10 @@ impl CPU {
                         (self.reg.l, c)
                     },
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let c = (at_hl & 0x80) != 0;
                         let new_hl = at_hl << 1;
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                         (new_hl, c)
                     },
                     _ => unreachable!()
@@ -984,10 +1013,10 @@ impl CPU {
                         (self.reg.l, c)
                     },
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let c = (at_hl & 0x01) != 0;
                         let new_hl = (at_hl & 0x80) | (at_hl >> 1);
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                         (new_hl, c)
                     },
                     _ => unreachable!()
@@ -1036,10 +1065,10 @@ impl CPU {
                         (self.reg.l, c)
                     },
                     Target::HL => {
-                        let at_hl = mmu.rb(self.reg.hl());
+                        let at_hl = self.read(bus, self.reg.hl());
                         let c = (at_hl & 0x01) != 0;
                         let new_hl = at_hl >> 1;
-                        mmu.wb(self.reg.hl(), new_hl);
+                        self.write(bus, self.reg.hl(), new_hl);
                         (new_hl, c)
                     },
                     _ => unreachable!()
@@ -1121,9 +1150,9 @@ impl CPU {
                         v
                     },
                     Target::HL => {
-                        let new_hl = mmu.rb(self.reg.hl());
+                        let new_hl = self.read(bus, self.reg.hl());
                         let v = ((new_hl & 0x0F) << 4) | ((new_hl & 0xF0) >> 4);
-                        mmu.wb(self.reg.hl(), v);
+                        self.write(bus, self.reg.hl(), v);
                         v
                     }
                     _ => unreachable!()
@@ -1182,123 +1211,214 @@ impl CPU {
                 self.reg.set_flag(Flag::C, carry);
             },
             Instruction::DI => self.ime = false,
-            Instruction::EI => self.ime = true,
-            Instruction::HALT => self.halt = true,
+            Instruction::EI => self.ime_scheduled = true,
+            Instruction::HALT => {
+                if !self.ime && self.interrupt_pending(bus) {
+                    self.halt_bug = true;
+                } else {
+                    self.halt = true;
+                }
+            },
             Instruction::RETI => {
                 self.ime = true;
-                self.reg.pc = self.pop(mmu);
+                self.reg.pc = self.pop(bus);
+                self.idle(bus);
             },
             Instruction::NOP => {},
-            Instruction::STOP => panic!("STOP"),
-            Instruction::NULL => panic!("Unused opcode"),
+            Instruction::STOP => {
+                // STOP is followed by a padding byte
+                self.reg.pc = self.reg.pc.wrapping_add(1);
+                // on a CGB with KEY1 armed it switches speeds instead of stopping
+                if !bus.mmu().switch_speed() {
+                    self.stopped = true;
+                }
+                let mut timer = self.timer.borrow_mut();
+                timer.counter = 0;
+                timer.div = 0;
+            },
+            Instruction::NULL => {
+                let (pc, opcode) = self.fetched;
+                self.lockup = Some(Lockup { pc, opcode });
+            },
         }
     }
 
-    pub fn step(&mut self, mmu: &mut MMU) {
-        let (instr, clocks, prefixed) = if self.halt {
-            (Instruction::HALT, 4, false)
-        } else {
-            self.fetch_instr(mmu)
-        };
+    pub fn step(&mut self, bus: &mut impl Bus) {
+        self.clocks_elapsed = 0;
 
-        self.clocks_elapsed = clocks;
-        self.timer.borrow_mut().tick_n(clocks);
+        // nothing but a reset gets the CPU going again
+        if self.lockup.is_some() {
+            self.idle(bus);
+            return;
+        }
 
-        if self.interrupt_exists(mmu) {
-            // effect of EI is delayed one instruction
-            if self.last_instr == Instruction::EI {
-                self.execute(mmu, instr);
-            } else if prefixed {
-                self.reg.pc -= 1;
+        // the CPU, LCD and timer stay stopped until a button is pressed
+        if self.stopped {
+            if !bus.mmu().joypad.line_low() {
+                self.clocks_elapsed += 1;
+                bus.sleep();
+                return;
             }
-            self.handle_interrupt(mmu);
-        } else if !self.halt {
-            self.execute(mmu, instr);
+            self.stopped = false;
         }
 
-        self.last_instr = instr;
+        // the CPU sits idle while VRAM DMA copies
+        let stall = bus.mmu().hdma.take_stall(u8::MAX);
+        if stall > 0 {
+            for _ in 0..stall {
+                self.idle(bus);
+            }
+            return;
+        }
+
+        // while halted the CPU only checks for interrupts, once every M-cycle,
+        // and leaves HALT in the cycle it finds one
+        if self.halt {
+            self.idle(bus);
+            if !self.interrupt_pending(bus) {
+                return;
+            }
+            self.halt = false;
+        } else {
+            let instr = self.fetch_instr(bus);
+            self.execute(bus, instr);
+        }
+
+        // interrupts are checked between instructions, before EI takes effect
+        if self.ime && self.interrupt_pending(bus) {
+            self.handle_interrupt(bus);
+        }
+        if self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
     }
 
-    pub fn interrupt_exists(&self, mmu: &mut MMU) -> bool {
-        let e_i = mmu.rb(0xFFFF);
-        let i_f = mmu.rb(0xFF0F);
-        let e_f = e_i & i_f;
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.reg.save_state(w);
+        w.write_bool(self.ime);
+        w.write_bool(self.halt);
+        w.write_bool(self.stopped);
+        w.write_bool(self.lockup.is_some());
+        let lockup = self.lockup.unwrap_or(Lockup { pc: 0, opcode: 0 });
+        w.write_u16(lockup.pc);
+        w.write_u8(lockup.opcode);
+        w.write_bool(self.halt_bug);
+    }
 
-        (self.halt || self.ime) && (e_f & 0b0001_1111) != 0
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.reg.load_state(r)?;
+        self.ime = r.read_bool()?;
+        self.halt = r.read_bool()?;
+        self.stopped = r.read_bool()?;
+        let locked = r.read_bool()?;
+        let lockup = Lockup { pc: r.read_u16()?, opcode: r.read_u8()? };
+        self.lockup = if locked { Some(lockup) } else { None };
+        self.halt_bug = r.read_bool()?;
+        Ok(())
     }
 
-    pub fn handle_interrupt(&mut self, mmu: &mut MMU) {
+    pub fn interrupt_pending(&self, bus: &mut impl Bus) -> bool {
+        let e_i = bus.mmu().rb(0xFFFF);
+        let i_f = bus.mmu().rb(0xFF0F);
+        (e_i & i_f & 0b0001_1111) != 0
+    }
+
+    // two cycles waiting, two pushing PC and one jumping. which interrupt
+    // it is only gets decided after the high byte is pushed, so a push that
+    // overwrites IE can cancel the dispatch, which then ends up at 0x0000
+    pub fn handle_interrupt(&mut self, bus: &mut impl Bus) {
+        // a HALT with one already pending doesn't get to halt at all
         self.halt = false;
-        if !self.ime {
-            return;
-        }
         self.ime = false;
+        self.idle(bus);
+        self.idle(bus);
 
-        let e_i = mmu.rb(0xFFFF);
-        let i_f = mmu.rb(0xFF0F);
-        let e_f = e_i & i_f;
-        let index = e_f.trailing_zeros();
-        mmu.wb(0xFF0F, i_f & !(1 << index));
+        let pc = self.reg.pc;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(bus, self.reg.sp, (pc >> 8) as u8);
+        let e_i = bus.mmu().rb(0xFFFF);
+        let i_f = bus.mmu().rb(0xFF0F);
+        let e_f = e_i & i_f & 0b0001_1111;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.write(bus, self.reg.sp, pc as u8);
 
-        // because EI is delayed, we don't have to return to the
-        // instruction that we would have skipped otherwise
-        if self.last_instr == Instruction::EI || self.last_instr == Instruction::HALT {
-            self.push(mmu, self.reg.pc);
-        } else {
-            self.push(mmu, self.reg.pc - 1);
-        }
-        self.reg.pc = match index {
+        self.reg.pc = match e_f.trailing_zeros() {
             0 => 0x40, // VBlank
             1 => 0x48, // LCD Stat
             2 => 0x50, // Timer overflow
             3 => 0x58, // Serial link
             4 => 0x60, // Joypad press
-            _ => unreachable!(),
+            _ => 0x00, // cancelled
         };
+        if e_f != 0 {
+            bus.mmu().wb(0xFF0F, i_f & !(1 << e_f.trailing_zeros()));
+        }
+        self.idle(bus);
     }
 
-    pub fn fetch_instr(&mut self, mmu: &MMU) -> (Instruction, u8, bool) {
-        let byte = self.get_imm8(mmu);
+    pub fn fetch_instr(&mut self, bus: &mut impl Bus) -> Instruction {
+        let pc = self.reg.pc;
+        let byte = if self.halt_bug {
+            self.halt_bug = false;
+            self.read(bus, self.reg.pc)
+        } else {
+            self.get_imm8(bus)
+        };
+        self.fetched = (pc, byte);
         match byte {
             0xCB => {
-                let cb_byte = self.get_imm8(mmu);
-                let instr = Instruction::decode_cb(cb_byte);
-                let clocks = CB_CLOCKS[cb_byte as usize];
-                (instr, clocks, true)
-            },
-            _ => {
-                let instr = Instruction::decode(byte);
-                let clocks = CLOCKS[byte as usize];
-                (instr, clocks, false)
+                let cb_byte = self.get_imm8(bus);
+                Instruction::decode_cb(cb_byte)
             },
+            _ => Instruction::decode(byte),
         }
     }
 
-    pub fn get_imm16(&mut self, mmu: &MMU) -> u16 {
-        let lo = mmu.rb(self.reg.pc);
+    // every access takes an M-cycle, which the rest of the
+    // machine runs through before the CPU gets to the bus
+    pub fn read(&mut self, bus: &mut impl Bus, address: u16) -> u8 {
+        self.idle(bus);
+        bus.mmu().rb(address)
+    }
+
+    pub fn write(&mut self, bus: &mut impl Bus, address: u16, value: u8) {
+        self.idle(bus);
+        bus.mmu().wb(address, value);
+    }
+
+    pub fn idle(&mut self, bus: &mut impl Bus) {
+        self.clocks_elapsed += 1;
+        bus.tick();
+    }
+
+    pub fn get_imm16(&mut self, bus: &mut impl Bus) -> u16 {
+        let lo = self.read(bus, self.reg.pc);
         self.reg.pc = self.reg.pc.wrapping_add(1);
-        let hi = mmu.rb(self.reg.pc);
+        let hi = self.read(bus, self.reg.pc);
         self.reg.pc = self.reg.pc.wrapping_add(1);
         ((hi as u16) << 8) | lo as u16
     }
 
-    pub fn get_imm8(&mut self, mmu: &MMU) -> u8 {
-        let imm8 = mmu.rb(self.reg.pc);
+    pub fn get_imm8(&mut self, bus: &mut impl Bus) -> u8 {
+        let imm8 = self.read(bus, self.reg.pc);
         self.reg.pc = self.reg.pc.wrapping_add(1);
         imm8
     }
 
-    pub fn push(&mut self, mmu: &mut MMU, value: u16) {
+    // SP is decremented in a cycle of its own before the first write
+    pub fn push(&mut self, bus: &mut impl Bus, value: u16) {
+        self.idle(bus);
         self.reg.sp = self.reg.sp.wrapping_sub(1);
-        mmu.wb(self.reg.sp, (value >> 8) as u8);
+        self.write(bus, self.reg.sp, (value >> 8) as u8);
         self.reg.sp = self.reg.sp.wrapping_sub(1);
-        mmu.wb(self.reg.sp, value as u8);
+        self.write(bus, self.reg.sp, value as u8);
     }
 
-    pub fn pop(&mut self, mmu: &MMU) -> u16 {
-        let lo = mmu.rb(self.reg.sp);
+    pub fn pop(&mut self, bus: &mut impl Bus) -> u16 {
+        let lo = self.read(bus, self.reg.sp);
         self.reg.sp = self.reg.sp.wrapping_add(1);
-        let hi = mmu.rb(self.reg.sp);
+        let hi = self.read(bus, self.reg.sp);
         self.reg.sp = self.reg.sp.wrapping_add(1);
         ((hi as u16) << 8) | lo as u16
     }
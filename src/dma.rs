@@ -0,0 +1,96 @@
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+
+pub const OAM_DMA_LENGTH: u16 = 0xA0;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Bus {
+    External,
+    Vram,
+    Oam,
+    Internal,
+}
+
+impl Bus {
+    pub fn of(address: u16) -> Bus {
+        match address {
+            0x8000..=0x9FFF => Bus::Vram,
+            0xFE00..=0xFEFF => Bus::Oam,
+            0xFF00..=0xFFFF => Bus::Internal,
+            _ => Bus::External,
+        }
+    }
+}
+
+// copies 160 bytes into OAM, one per M-cycle, after a cycle of setup.
+// a transfer that's restarted keeps running during the new one's setup
+#[derive(Debug, Clone)]
+pub struct OamDma {
+    pub active: bool,
+    pub source: u16,
+    pub index: u16,
+    pub starting: Option<u16>,
+    // the byte on the bus, which is what the CPU reads when it collides
+    pub last_byte: u8,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            active: false,
+            source: 0,
+            index: 0,
+            starting: None,
+            last_byte: 0xFF,
+        }
+    }
+
+    // 0xE0-0xFF can't reach the top of the address space,
+    // they read the echo of WRAM instead
+    pub fn start(&mut self, value: u8) {
+        let page = if value >= 0xE0 { value & 0xDF } else { value };
+        self.starting = Some((page as u16) << 8);
+    }
+
+    // what the CPU gets instead of the real value while a transfer runs:
+    // OAM is off limits and the bus being copied from holds the DMA's byte
+    pub fn conflict(&self, address: u16) -> Option<u8> {
+        if !self.active {
+            return None;
+        }
+        match Bus::of(address) {
+            Bus::Internal => None,
+            Bus::Oam => Some(0xFF),
+            bus if bus == Bus::of(self.source) => Some(self.last_byte),
+            _ => None,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.active);
+        w.write_u16(self.source);
+        w.write_u16(self.index);
+        w.write_bool(self.starting.is_some());
+        w.write_u16(self.starting.unwrap_or(0));
+        w.write_u8(self.last_byte);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.active = r.read_bool()?;
+        self.source = r.read_u16()? & 0xFF00;
+        self.index = r.read_u16()?;
+        if self.index > OAM_DMA_LENGTH {
+            return Err(SaveStateError::InvalidData);
+        }
+        let starting = r.read_bool()?;
+        let source = r.read_u16()? & 0xFF00;
+        self.starting = if starting { Some(source) } else { None };
+        self.last_byte = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Default for OamDma {
+    fn default() -> OamDma {
+        OamDma::new()
+    }
+}
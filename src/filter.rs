@@ -0,0 +1,225 @@
+use crate::ppu::{SCREEN_W, SCREEN_H};
+
+pub const MAX_SCALE: usize = 8;
+// how bright the gaps between the LCD's dots are, out of 256
+const GRID_BRIGHTNESS: u32 = 176;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Upscaler {
+    None,
+    Scale2x,
+    Scale3x,
+    // Scale2x's edge rules, but blending the corners it would copy, which
+    // rounds diagonals off the way HQ2x does without its lookup tables
+    Smooth2x,
+}
+
+impl Upscaler {
+    pub const ALL: [Upscaler; 4] = [Upscaler::None, Upscaler::Scale2x, Upscaler::Scale3x, Upscaler::Smooth2x];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Upscaler::None => "none",
+            Upscaler::Scale2x => "scale2x",
+            Upscaler::Scale3x => "scale3x",
+            Upscaler::Smooth2x => "smooth2x",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Upscaler> {
+        Upscaler::ALL.iter().copied().find(|u| u.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn factor(self) -> usize {
+        match self {
+            Upscaler::None => 1,
+            Upscaler::Scale2x | Upscaler::Smooth2x => 2,
+            Upscaler::Scale3x => 3,
+        }
+    }
+
+    // whether it can be used to fill a window scale times the screen
+    pub fn fits(self, scale: usize) -> bool {
+        scale.is_multiple_of(self.factor())
+    }
+}
+
+// turns the 160x144 frame into what the window shows, scale times
+// bigger. frames are blended with the one before to fake the DMG LCD's
+// slow response, then upscaled, blown up to the full size with nearest
+// neighbour and finally given the gaps between the LCD's dots
+pub struct VideoFilter {
+    pub scale: usize,
+    pub upscaler: Upscaler,
+    pub grid: bool,
+    // how much of the previous frame is left, in percent
+    pub ghosting: u32,
+    previous: Option<Box<[[u32; SCREEN_W]; SCREEN_H]>>,
+    blended: Vec<u32>,
+    upscaled: Vec<u32>,
+    out: Vec<u32>,
+}
+
+impl VideoFilter {
+    pub fn new(scale: usize, upscaler: Upscaler) -> VideoFilter {
+        VideoFilter {
+            scale: scale.clamp(1, MAX_SCALE),
+            upscaler,
+            grid: false,
+            ghosting: 0,
+            previous: None,
+            blended: vec![0; SCREEN_W * SCREEN_H],
+            upscaled: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        SCREEN_W * self.scale
+    }
+
+    pub fn height(&self) -> usize {
+        SCREEN_H * self.scale
+    }
+
+    // width() x height() pixels, row by row
+    pub fn apply(&mut self, frame: &[[u32; SCREEN_W]; SCREEN_H]) -> &[u32] {
+        let ghosting = self.ghosting.min(100);
+        let previous = self.previous.get_or_insert_with(|| Box::new(*frame));
+        for (i, (cur, prev)) in frame.iter().flatten().zip(previous.iter().flatten()).enumerate() {
+            self.blended[i] = mix(*cur, *prev, ghosting * 256 / 100);
+        }
+        **previous = *frame;
+
+        let upscaler = if self.upscaler.fits(self.scale) { self.upscaler } else { Upscaler::None };
+        let factor = upscaler.factor();
+        match upscaler {
+            Upscaler::None => {
+                self.upscaled.clear();
+                self.upscaled.extend_from_slice(&self.blended);
+            },
+            Upscaler::Scale2x => scale2x(&self.blended, &mut self.upscaled, false),
+            Upscaler::Smooth2x => scale2x(&self.blended, &mut self.upscaled, true),
+            Upscaler::Scale3x => scale3x(&self.blended, &mut self.upscaled),
+        }
+
+        let zoom = self.scale / factor;
+        let (width, height) = (self.width(), self.height());
+        let source_width = SCREEN_W * factor;
+        self.out.resize(width * height, 0);
+        for y in 0..height {
+            let row = &self.upscaled[y / zoom * source_width..][..source_width];
+            let out = &mut self.out[y * width..][..width];
+            for (x, pixel) in out.iter_mut().enumerate() {
+                *pixel = row[x / zoom];
+            }
+        }
+
+        if self.grid && self.scale > 1 {
+            let last = self.scale - 1;
+            for (y, row) in self.out.chunks_mut(width).enumerate() {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    if x % self.scale == last || y % self.scale == last {
+                        *pixel = darken(*pixel, GRID_BRIGHTNESS);
+                    }
+                }
+            }
+        }
+        &self.out
+    }
+}
+
+impl Default for VideoFilter {
+    fn default() -> VideoFilter {
+        VideoFilter::new(1, Upscaler::None)
+    }
+}
+
+// a weighted towards b by amount out of 256
+fn mix(a: u32, b: u32, amount: u32) -> u32 {
+    let channel = |shift: u32| {
+        let a = a >> shift & 0xFF;
+        let b = b >> shift & 0xFF;
+        ((a * (256 - amount) + b * amount) / 256) << shift
+    };
+    (a & 0xFF00_0000) | channel(16) | channel(8) | channel(0)
+}
+
+fn darken(color: u32, brightness: u32) -> u32 {
+    mix(color & 0xFF00_0000, color, brightness)
+}
+
+// the pixel at x, y with the edges repeated outwards
+fn at(frame: &[u32], x: isize, y: isize) -> u32 {
+    let x = x.clamp(0, SCREEN_W as isize - 1) as usize;
+    let y = y.clamp(0, SCREEN_H as isize - 1) as usize;
+    frame[y * SCREEN_W + x]
+}
+
+// AdvanceMAME's Scale2x: each pixel becomes 2x2, taking a neighbour's
+// colour in the corners where two of them meet along an edge
+fn scale2x(frame: &[u32], out: &mut Vec<u32>, smooth: bool) {
+    let width = SCREEN_W * 2;
+    out.resize(width * SCREEN_H * 2, 0);
+    for y in 0..SCREEN_H as isize {
+        for x in 0..SCREEN_W as isize {
+            let e = at(frame, x, y);
+            let b = at(frame, x, y - 1);
+            let d = at(frame, x - 1, y);
+            let f = at(frame, x + 1, y);
+            let h = at(frame, x, y + 1);
+            let corner = |edge: bool, n: u32| match (edge, smooth) {
+                (false, _) => e,
+                (true, false) => n,
+                (true, true) => mix(e, n, 128),
+            };
+            let (top, bottom) = if b != h && d != f {
+                ([corner(d == b, d), corner(b == f, f)], [corner(d == h, d), corner(h == f, f)])
+            } else {
+                ([e, e], [e, e])
+            };
+            let i = y as usize * 2 * width + x as usize * 2;
+            out[i..i + 2].copy_from_slice(&top);
+            out[i + width..i + width + 2].copy_from_slice(&bottom);
+        }
+    }
+}
+
+fn scale3x(frame: &[u32], out: &mut Vec<u32>) {
+    let width = SCREEN_W * 3;
+    out.resize(width * SCREEN_H * 3, 0);
+    for y in 0..SCREEN_H as isize {
+        for x in 0..SCREEN_W as isize {
+            let a = at(frame, x - 1, y - 1);
+            let b = at(frame, x, y - 1);
+            let c = at(frame, x + 1, y - 1);
+            let d = at(frame, x - 1, y);
+            let e = at(frame, x, y);
+            let f = at(frame, x + 1, y);
+            let g = at(frame, x - 1, y + 1);
+            let h = at(frame, x, y + 1);
+            let i = at(frame, x + 1, y + 1);
+            let pick = |cond: bool, n: u32| if cond { n } else { e };
+
+            let block = if b != h && d != f {
+                [
+                    pick(d == b, d),
+                    pick((d == b && e != c) || (b == f && e != a), b),
+                    pick(b == f, f),
+                    pick((d == b && e != g) || (d == h && e != a), d),
+                    e,
+                    pick((b == f && e != i) || (h == f && e != c), f),
+                    pick(d == h, d),
+                    pick((d == h && e != i) || (h == f && e != g), h),
+                    pick(h == f, f),
+                ]
+            } else {
+                [e; 9]
+            };
+            let start = y as usize * 3 * width + x as usize * 3;
+            for (row, pixels) in block.chunks(3).enumerate() {
+                out[start + row * width..start + row * width + 3].copy_from_slice(pixels);
+            }
+        }
+    }
+}
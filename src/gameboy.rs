@@ -0,0 +1,337 @@
+use crate::cpu::{CPU, Bus, Lockup};
+use crate::mmu::{MMU, Model};
+use crate::ppu::{PPU, DmgPalette, RawPixel, SCREEN_W, SCREEN_H};
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::timer::Timer;
+use crate::joypad::Button;
+use crate::interrupts::IntReq;
+use crate::savestate::*;
+use crate::audio::AudioSink;
+use crate::screenshot;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use std::rc::Rc;
+use std::cell::RefCell;
+
+// 154 lines of 114 M-cycles each
+pub const CYCLES_PER_FRAME: u32 = 154 * 114;
+
+// the CGB boot rom is 0x900 bytes long with a gap for the cartridge header
+pub const CGB_BOOT_SIZE: usize = 0x900;
+
+// what the CGB boot rom picks for DMG cartridges it doesn't recognise
+const COMPAT_BG_PALETTE: [u16; 4] = [0x7FFF, 0x1BEF, 0x6180, 0x0000];
+const COMPAT_OBJ_PALETTE: [u16; 4] = [0x7FFF, 0x421F, 0x1CF2, 0x0000];
+
+pub struct GameBoy {
+    boot: Vec<u8>,
+    forced_model: Option<Model>,
+    pub cpu: CPU,
+    pub mmu: MMU,
+    pub ppu: PPU,
+    // in double speed mode the PPU and APU get half the CPU's M-cycles
+    odd_cycle: bool,
+    lockup_reported: bool,
+}
+
+impl GameBoy {
+    pub fn new(boot: Vec<u8>) -> GameBoy {
+        GameBoy::with_cartridge(boot, Cartridge::empty(), None)
+    }
+
+    // a CGB boot rom makes every cartridge run on a CGB, otherwise the
+    // model follows the cartridge header unless it's been forced
+    fn with_cartridge(boot: Vec<u8>, cartridge: Cartridge, forced_model: Option<Model>) -> GameBoy {
+        let model = match forced_model {
+            Some(model) => model,
+            None if boot.len() == CGB_BOOT_SIZE || cartridge.supports_cgb() => Model::CGB,
+            None => Model::DMG,
+        };
+
+        let intr = Rc::new(RefCell::new(IntReq::new()));
+        let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+        let mut mmu = MMU::new(cartridge, timer.clone());
+        let cpu = CPU::new(timer);
+        let ppu = PPU::new(intr);
+        mmu.model = model;
+        mmu.cgb_mode = model == Model::CGB;
+
+        let mut gameboy = GameBoy {
+            boot,
+            forced_model,
+            cpu,
+            mmu,
+            ppu,
+            odd_cycle: false,
+            lockup_reported: false,
+        };
+        if model == Model::CGB && gameboy.boot.len() != CGB_BOOT_SIZE {
+            gameboy.skip_cgb_boot();
+        } else {
+            gameboy.mmu.read_boot(&gameboy.boot);
+        }
+        gameboy
+    }
+
+    // without a CGB boot rom, the machine starts out in the state it leaves behind
+    fn skip_cgb_boot(&mut self) {
+        let reg = &mut self.cpu.reg;
+        reg.a = 0x11;
+        reg.f = 0x80;
+        reg.b = 0x00;
+        reg.c = 0x00;
+        reg.d = 0xFF;
+        reg.e = 0x56;
+        reg.h = 0x00;
+        reg.l = 0x0D;
+        reg.sp = 0xFFFE;
+        reg.pc = 0x0100;
+
+        self.mmu.cgb_mode = self.mmu.cartridge.supports_cgb();
+        if !self.mmu.cgb_mode {
+            self.mmu.bg_palettes.set_palette(0, COMPAT_BG_PALETTE);
+            self.mmu.obj_palettes.set_palette(0, COMPAT_OBJ_PALETTE);
+            self.mmu.obj_palettes.set_palette(1, COMPAT_OBJ_PALETTE);
+        }
+
+        self.mmu.wb(0xFF26, 0x80);
+        self.mmu.wb(0xFF24, 0x77);
+        self.mmu.wb(0xFF25, 0xF3);
+        self.mmu.wb(0xFF40, 0x91);
+        self.mmu.wb(0xFF47, 0xFC);
+        self.mmu.wb(0xFF50, 0x01);
+    }
+
+    // takes effect with the next cartridge that's loaded,
+    // None goes back to picking the model automatically
+    pub fn force_model(&mut self, model: Option<Model>) {
+        self.forced_model = model;
+    }
+
+    // lets the CPU into VRAM and OAM whatever the PPU is doing, which
+    // can help when debugging a game that draws garbage
+    pub fn set_access_restrictions(&mut self, enabled: bool) {
+        self.mmu.restrict_access = enabled;
+    }
+
+    // the colours DMG games are shown in. CGB games and DMG games on a
+    // CGB pick theirs from palette RAM
+    pub fn set_palette(&mut self, palette: DmgPalette) {
+        self.ppu.palette = palette;
+    }
+
+    pub fn palette(&self) -> DmgPalette {
+        self.ppu.palette
+    }
+
+    pub fn model(&self) -> Model {
+        self.mmu.model
+    }
+
+    // inserting a cartridge power cycles the whole machine
+    pub fn load_rom(&mut self, rom: Vec<u8>) -> Result<(), CartridgeError> {
+        let cartridge = Cartridge::new(rom)?;
+        let boot = std::mem::take(&mut self.boot);
+        let sample_rate = self.mmu.apu.sample_rate();
+        let restrict_access = self.mmu.restrict_access;
+        let palette = self.ppu.palette;
+        *self = GameBoy::with_cartridge(boot, cartridge, self.forced_model);
+        self.mmu.apu.set_sample_rate(sample_rate);
+        self.mmu.restrict_access = restrict_access;
+        self.ppu.palette = palette;
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_bytes(STATE_MAGIC);
+        w.write_u32(STATE_VERSION);
+        w.write_bytes(self.mmu.cartridge.header());
+        self.cpu.save_state(&mut w);
+        self.mmu.save_state(&mut w);
+        self.ppu.save_state(&mut w);
+        w.into_bytes()
+    }
+
+    // the state is restored into a fresh machine first, so
+    // a bad save state leaves the running one untouched
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut r = StateReader::new(data);
+
+        let mut magic = [0; 8];
+        if r.read_bytes(&mut magic).is_err() || &magic != STATE_MAGIC {
+            return Err(SaveStateError::InvalidHeader);
+        }
+        let version = r.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let mut header = [0; 0x1C];
+        r.read_bytes(&mut header)?;
+        if header[..] != *self.mmu.cartridge.header() {
+            return Err(SaveStateError::WrongCartridge);
+        }
+
+        let mut gameboy = GameBoy::with_cartridge(self.boot.clone(), self.mmu.cartridge.clone(),
+            self.forced_model);
+        gameboy.mmu.apu.set_sample_rate(self.mmu.apu.sample_rate());
+        gameboy.mmu.restrict_access = self.mmu.restrict_access;
+        gameboy.ppu.palette = self.ppu.palette;
+        gameboy.cpu.load_state(&mut r)?;
+        gameboy.mmu.load_state(&mut r)?;
+        gameboy.ppu.load_state(&mut r)?;
+        if !r.is_empty() {
+            return Err(SaveStateError::InvalidData);
+        }
+
+        *self = gameboy;
+        Ok(())
+    }
+
+    pub fn title(&self) -> &str {
+        // the title is padded with zeroes up to 11 bytes
+        self.mmu.cartridge.title.split('\0').next().unwrap_or_default()
+    }
+
+    // executes one instruction, with everything else running alongside
+    // each of its M-cycles. returns the number of M-cycles that elapsed
+    // at single speed
+    pub fn step(&mut self) -> u8 {
+        let mut system = System {
+            mmu: &mut self.mmu,
+            ppu: &mut self.ppu,
+            odd_cycle: &mut self.odd_cycle,
+            m_clocks: 0,
+        };
+        self.cpu.step(&mut system);
+        system.m_clocks
+    }
+
+    // runs until the PPU has a finished frame, or for one frame's worth
+    // of cycles if the LCD is turned off
+    pub fn run_frame(&mut self) {
+        let mut cycles: u32 = 0;
+        while !self.take_frame(cycles) {
+            cycles += self.step() as u32;
+        }
+    }
+
+    // for frontends that step themselves: whether the PPU has finished a
+    // frame, or the cycles since the last one make a frame with the LCD off.
+    // this consumes the PPU's flag, so a finished frame is only taken once,
+    // and the framebuffer only holds a whole frame right when it's true
+    pub fn take_frame(&mut self, cycles: u32) -> bool {
+        if self.ppu.update_screen || cycles >= CYCLES_PER_FRAME {
+            self.ppu.update_screen = false;
+            return true;
+        }
+        false
+    }
+
+    // the APU only produces samples once it knows the rate to produce
+    // them at, usually the rate of the sink they're drained into
+    pub fn set_sample_rate(&mut self, sample_rate: Option<u32>) {
+        self.mmu.apu.set_sample_rate(sample_rate);
+    }
+
+    // stretches or squeezes the output rate by ratio, to keep a real-time
+    // sink's queue from running dry or piling up
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        self.mmu.apu.set_rate_ratio(ratio);
+    }
+
+    // everything the APU produced since the last call, interleaved left and
+    // right, for frontends that hand it to more than one sink
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.mmu.apu.take_samples()
+    }
+
+    // hands everything the APU produced since the last call to the sink
+    pub fn drain_audio(&mut self, sink: &mut dyn AudioSink) {
+        let samples = self.take_samples();
+        sink.write(&samples);
+    }
+
+    pub fn framebuffer(&self) -> &[[u32; SCREEN_W]; SCREEN_H] {
+        &self.ppu.data
+    }
+
+    // writes the current frame to a PNG named after the game and the
+    // time, in dir. returns the path it was written to
+    pub fn save_screenshot(&self, dir: &str, scale: usize) -> io::Result<String> {
+        let name = screenshot::screenshot_name(self.title(), SystemTime::now());
+        let path = Path::new(dir).join(name).to_string_lossy().into_owned();
+        screenshot::save_png(&path, self.framebuffer(), scale)?;
+        Ok(path)
+    }
+
+    // the frame before it was coloured in, which doesn't depend on the palette
+    pub fn raw_framebuffer(&self) -> &[[RawPixel; SCREEN_W]; SCREEN_H] {
+        &self.ppu.raw
+    }
+
+    // the lockup the CPU has run into, once, for the frontend to report
+    // or break on. the machine carries on without the CPU either way
+    pub fn take_lockup(&mut self) -> Option<Lockup> {
+        if self.lockup_reported {
+            return None;
+        }
+        self.lockup_reported = self.cpu.lockup.is_some();
+        self.cpu.lockup
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.mmu.joypad.button_down(button);
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.mmu.joypad.button_up(button);
+    }
+}
+
+// what the CPU drives while it runs an instruction
+struct System<'a> {
+    mmu: &'a mut MMU,
+    ppu: &'a mut PPU,
+    odd_cycle: &'a mut bool,
+    // at single speed
+    m_clocks: u8,
+}
+
+impl Bus for System<'_> {
+    fn mmu(&mut self) -> &mut MMU {
+        self.mmu
+    }
+
+    // the timer and OAM DMA keep up with the CPU, while in double speed
+    // mode the PPU, APU and cartridge get every other M-cycle
+    fn tick(&mut self) {
+        Bus::tick(self.mmu);
+        if !self.single_speed_cycle() {
+            return;
+        }
+        self.ppu.tick(self.mmu);
+        self.mmu.apu.tick();
+        self.mmu.cartridge.tick(1);
+    }
+
+    // time still passes for the frontend while everything is stopped
+    fn sleep(&mut self) {
+        self.single_speed_cycle();
+    }
+}
+
+impl System<'_> {
+    // counts an M-cycle, returning whether it's one at single speed
+    fn single_speed_cycle(&mut self) -> bool {
+        if self.mmu.double_speed {
+            *self.odd_cycle = !*self.odd_cycle;
+            if *self.odd_cycle {
+                return false;
+            }
+        }
+        self.m_clocks += 1;
+        true
+    }
+}
@@ -0,0 +1,104 @@
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+
+// VRAM DMA on the CGB, either all at once (general purpose) or one
+// block of 16 bytes every time the PPU enters HBlank
+#[derive(Debug, Clone)]
+pub struct Hdma {
+    pub source: u16,
+    pub dest: u16,
+    // blocks left to copy, minus one
+    pub remaining: u8,
+    pub active: bool,
+    pub hblank: bool,
+    // M-cycles the CPU still has to wait for copies to finish
+    pub stall: u32,
+}
+
+impl Hdma {
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            dest: 0,
+            remaining: 0x7F,
+            active: false,
+            hblank: false,
+            stall: 0,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            // once done or cancelled, bit 7 is set
+            0xFF55 => ((!self.active as u8) << 7) | self.remaining,
+            _ => 0xFF,
+        }
+    }
+
+    // returns true when a general purpose transfer has to run right away
+    pub fn write(&mut self, address: u16, value: u8) -> bool {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((value as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (value & 0xF0) as u16,
+            0xFF53 => self.dest = (self.dest & 0x00FF) | (((value & 0x1F) as u16) << 8),
+            0xFF54 => self.dest = (self.dest & 0xFF00) | (value & 0xF0) as u16,
+            0xFF55 => {
+                // clearing bit 7 while an HBlank transfer runs cancels it
+                if self.active && (value & 0x80) == 0 {
+                    self.active = false;
+                    return false;
+                }
+                self.remaining = value & 0x7F;
+                self.hblank = (value & 0x80) != 0;
+                self.active = true;
+                return !self.hblank;
+            },
+            _ => unreachable!()
+        }
+        false
+    }
+
+    // moves on to the next block, returning the addresses to copy between
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, 0x8000 | (self.dest & 0x1FF0));
+        self.source = self.source.wrapping_add(0x10);
+        self.dest = (self.dest + 0x10) & 0x1FF0;
+        if self.remaining == 0 {
+            self.remaining = 0x7F;
+            self.active = false;
+        } else {
+            self.remaining -= 1;
+        }
+        block
+    }
+
+    pub fn take_stall(&mut self, max: u8) -> u8 {
+        let cycles = self.stall.min(max as u32);
+        self.stall -= cycles;
+        cycles as u8
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.source);
+        w.write_u16(self.dest);
+        w.write_u8(self.remaining);
+        w.write_bool(self.active);
+        w.write_bool(self.hblank);
+        w.write_u32(self.stall);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.source = r.read_u16()? & 0xFFF0;
+        self.dest = r.read_u16()? & 0x1FF0;
+        self.remaining = r.read_u8()? & 0x7F;
+        self.active = r.read_bool()?;
+        self.hblank = r.read_bool()?;
+        self.stall = r.read_u32()?;
+        Ok(())
+    }
+}
+
+impl Default for Hdma {
+    fn default() -> Hdma {
+        Hdma::new()
+    }
+}
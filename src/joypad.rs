@@ -1,4 +1,5 @@
 use crate::interrupts::*;
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -26,8 +27,8 @@ impl Joypad {
     pub fn new(intr: Rc<RefCell<IntReq>>) -> Joypad {
         Joypad {
             intr,
-            dpad: 0,
-            bpad: 0,
+            dpad: 0x0F,
+            bpad: 0x0F,
             select: 0,
         }
     }
@@ -48,6 +49,11 @@ impl Joypad {
         0b1100_0000 | state
     }
 
+    // whether a held button in a selected group is pulling its line low
+    pub fn line_low(&self) -> bool {
+        (self.state() & 0x0F) != 0x0F
+    }
+
     pub fn button_down(&mut self, button: Button) {
         self.intr.borrow_mut().set_flag(IntFlag::Joypad);
         if button as u8 > 0x0F {
@@ -64,4 +70,17 @@ impl Joypad {
             self.bpad |= button as u8;
         }
     }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.dpad);
+        w.write_u8(self.bpad);
+        w.write_u8(self.select);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.dpad = r.read_u8()?;
+        self.bpad = r.read_u8()?;
+        self.select = r.read_u8()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,41 @@
+pub mod mmu;
+pub mod registers;
+pub mod cpu;
+pub mod ppu;
+pub mod cartridge;
+pub mod rtc;
+pub mod timer;
+pub mod joypad;
+pub mod interrupts;
+pub mod serial;
+pub mod apu;
+pub mod hdma;
+pub mod dma;
+pub mod audio;
+pub mod config;
+pub mod screenshot;
+pub mod recording;
+pub mod filter;
+pub mod savestate;
+pub mod gameboy;
+mod test {
+    mod cpu;
+    mod ppu;
+    mod timer;
+    mod cartridge;
+    mod gameboy;
+    mod serial;
+    mod savestate;
+    mod rtc;
+    mod apu;
+    mod audio;
+    mod cgb;
+    mod hdma;
+    mod dma;
+    mod config;
+    mod screenshot;
+    mod recording;
+    mod filter;
+}
+
+pub use crate::gameboy::GameBoy;
@@ -1,32 +1,18 @@
-mod mmu;
-mod registers;
-mod cpu;
-mod ppu;
-mod cartridge;
-mod timer;
-mod joypad;
-mod interrupts;
-mod test {
-    mod cpu;
-    mod ppu;
-    mod timer;
-    mod cartridge;
-}
-
-use std::ffi::CStr;
 use std::io::prelude::*;
 use std::fs::File;
-use std::rc::Rc;
-use std::cell::RefCell;
-use minifb::{Key, Window, WindowOptions, Scale};
-use crate::cpu::CPU;
-use crate::mmu::MMU;
-use crate::ppu::{PPU, SCREEN_W, SCREEN_H};
-use crate::cartridge::Cartridge;
-use crate::timer::Timer;
-use crate::joypad::*;
-use crate::interrupts::IntReq;
+use minifb::{Key, KeyRepeat, Window, WindowOptions, Scale};
+use ironboy::GameBoy;
+use ironboy::ppu::{DmgPalette, Preset};
+use ironboy::joypad::Button;
+use ironboy::apu::CPU_CLOCK;
+use ironboy::gameboy::CYCLES_PER_FRAME;
+use ironboy::audio::{AudioSink, NullSink, WavSink, RateControl, DEFAULT_SAMPLE_RATE};
+use ironboy::config::{Config, LockupAction, CONFIG_PATH};
+use ironboy::recording::{Recorder, RecordingFormat, recording_name};
+use ironboy::filter::{VideoFilter, Upscaler};
 use std::env;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 const BUTTONS: [(Key, Button); 8] = [
     (Key::Enter, Button::Start),
@@ -39,13 +25,159 @@ const BUTTONS: [(Key, Button); 8] = [
     (Key::Right, Button::Right),
 ];
 
+// the selected save state slot is stored next to the rom as <rom>.ss<slot>
+const SLOT_KEYS: [Key; 10] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+];
+const SAVE_STATE_KEY: Key = Key::F5;
+const LOAD_STATE_KEY: Key = Key::F8;
+const PALETTE_KEY: Key = Key::P;
+// filters that fit the window's scale, the LCD grid, and ghosting between
+// off and the config's amount, or half when that's off too
+const FILTER_KEY: Key = Key::F;
+const GRID_KEY: Key = Key::L;
+const GHOSTING_KEY: Key = Key::G;
+const DEFAULT_GHOSTING: u32 = 50;
+// screenshots go in the working directory as <title>-<date>-<time>.png
+const SCREENSHOT_KEY: Key = Key::F12;
+// starts and stops recording to <title>-<date>-<time>.<format>, next to
+// the screenshots. y4m and raw recordings get a .wav of the audio too
+const RECORD_KEY: Key = Key::F10;
+
+struct Recording {
+    path: String,
+    video: Recorder,
+    audio: Option<WavSink>,
+}
+
+fn start_recording(gameboy: &GameBoy, format: RecordingFormat, sample_rate: u32) -> Option<Recording> {
+    let path = recording_name(gameboy.title(), SystemTime::now(), format);
+    let video = match Recorder::create(&path, format) {
+        Err(e) => {
+            eprintln!("Couldn't start recording {}: {}", path, e);
+            return None;
+        },
+        Ok(r) => r,
+    };
+    let mut audio = None;
+    if format.wants_wav() {
+        let wav_path = format!("{}.wav", &path[..path.len() - format.extension().len() - 1]);
+        match WavSink::create(&wav_path, sample_rate) {
+            Err(e) => eprintln!("Couldn't record audio to {}: {}", wav_path, e),
+            Ok(sink) => audio = Some(sink),
+        }
+    }
+    println!("Recording to {}", path);
+    Some(Recording { path, video, audio })
+}
+
+fn stop_recording(recording: Recording) {
+    let mut result = recording.video.finish().map(|_| ());
+    if let Some(audio) = recording.audio {
+        result = result.and(audio.finish().map(|_| ()));
+    }
+    match result {
+        Err(e) => eprintln!("Couldn't finish recording {}: {}", recording.path, e),
+        Ok(_) => println!("Saved recording to {}", recording.path),
+    }
+}
+
+// the palette from the config file, then any of the presets it isn't
+fn palettes(config: &Config) -> Vec<(&'static str, DmgPalette)> {
+    let mut palettes = vec![("config", config.palette)];
+    for preset in Preset::ALL.iter() {
+        if preset.palette() != config.palette {
+            palettes.push((preset.name(), preset.palette()));
+        }
+    }
+    palettes
+}
+
+fn save_state(gameboy: &GameBoy, path: &str) {
+    let result = File::create(path).and_then(|mut f| f.write_all(&gameboy.save_state()));
+    match result {
+        Err(e) => eprintln!("Couldn't write save state {}: {}", path, e),
+        Ok(_) => println!("Saved state to {}", path),
+    }
+}
+
+// returns whether the state was loaded
+fn load_state(gameboy: &mut GameBoy, path: &str) -> bool {
+    let mut state = Vec::<u8>::new();
+    if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut state)) {
+        eprintln!("Couldn't read save state {}: {}", path, e);
+        return false;
+    }
+    match gameboy.load_state(&state) {
+        Err(e) => {
+            eprintln!("Couldn't load save state {}: {:?}", path, e);
+            false
+        },
+        Ok(_) => {
+            println!("Loaded state from {}", path);
+            true
+        },
+    }
+}
+
+// battery-backed RAM is kept next to the rom as <rom>.sav
+const SAV_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+fn read_sav(gameboy: &mut GameBoy, path: &str) {
+    let mut sav = Vec::<u8>::new();
+    match File::open(path).and_then(|mut f| f.read_to_end(&mut sav)) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => eprintln!("Couldn't read save {}: {}", path, e),
+        Ok(_) => gameboy.mmu.cartridge.load_battery_save(&sav),
+    }
+}
+
+// written to a temporary file first so a crash can't leave a torn save
+fn write_sav(gameboy: &mut GameBoy, path: &str) {
+    let tmp_path = format!("{}.tmp", path);
+    let result = File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(&gameboy.mmu.cartridge.battery_save()))
+        .and_then(|_| std::fs::rename(&tmp_path, path));
+    match result {
+        Err(e) => eprintln!("Couldn't write save {}: {}", path, e),
+        Ok(_) => gameboy.mmu.cartridge.ram_dirty = false,
+    }
+}
+
+// about 60ms of audio is kept queued, and at most 200ms before samples are dropped
+const AUDIO_TARGET_QUEUED: usize = DEFAULT_SAMPLE_RATE as usize * 6 / 100;
+const AUDIO_MAX_QUEUED: usize = DEFAULT_SAMPLE_RATE as usize / 5;
+
+#[cfg(feature = "audio")]
+fn open_audio() -> Box<dyn AudioSink> {
+    match ironboy::audio::CpalSink::new(AUDIO_MAX_QUEUED) {
+        Ok(sink) => Box::new(sink),
+        Err(e) => {
+            eprintln!("Couldn't open audio output, running muted: {:?}", e);
+            Box::new(NullSink::new(DEFAULT_SAMPLE_RATE))
+        },
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn open_audio() -> Box<dyn AudioSink> {
+    Box::new(NullSink::new(DEFAULT_SAMPLE_RATE))
+}
+
+// 59.73Hz rather than 60
+fn frame_duration() -> Duration {
+    Duration::from_nanos(CYCLES_PER_FRAME as u64 * 4 * 1_000_000_000 / CPU_CLOCK as u64)
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let path: &str = args[args.len() - 1].as_ref();
 
-    if &path[path.len()-3..path.len()] != ".gb" {
-        panic!("Invalid file extension in path: {}", path);
-    }
+    let base = match path.rfind('.') {
+        Some(dot) if matches!(&path[dot..], ".gb" | ".gbc") => &path[..dot],
+        _ => panic!("Invalid file extension in path: {}", path),
+    };
 
     let mut rom = Vec::<u8>::new();
     let mut file = match File::open(path) {
@@ -56,10 +188,6 @@ fn main() {
         Err(e) => panic!("{}", e),
         Ok(_) => (),
     }
-    let mut cartridge = match Cartridge::new(rom) {
-        Err(e) => panic!("Error loading cartridge: {:?}", e),
-        Ok(c) => c,
-    };
 
     let mut boot = Vec::<u8>::new();
     let boot_path = "roms/DMG_ROM.bin";
@@ -72,72 +200,169 @@ fn main() {
         Ok(_) => (),
     }
 
-    let title = {
-        let mut end_of_title: usize = 1;
-        let cart_title = cartridge.title.as_bytes();
-        while cart_title[end_of_title - 1] != 0 {
-            end_of_title += 1;
-            if end_of_title == cartridge.title.len() {
-                cartridge.title.push_str("\0");
-                end_of_title += 1;
-                break;
-            }
-        }
-
-        let title_bytes = &cartridge.title[0..end_of_title];
-        match CStr::from_bytes_with_nul(title_bytes.as_ref()) {
-            Err(_) => "Couldn't parse title",
-            Ok(cstr) => match cstr.to_str() {
-                Err(e) => panic!("{}", e),
-                Ok(s) => s,
-            },
-        }
+    let config = match Config::load(CONFIG_PATH) {
+        Err(e) => panic!("Error reading {}: {:?}", CONFIG_PATH, e),
+        Ok(c) => c,
     };
+    let palettes = palettes(&config);
+    let mut palette = 0;
+
+    let mut gameboy = GameBoy::new(boot);
+    gameboy.set_palette(config.palette);
+    if let Err(e) = gameboy.load_rom(rom) {
+        panic!("Error loading cartridge: {:?}", e);
+    }
+
+    let battery = gameboy.mmu.cartridge.has_battery();
+    let sav_path = format!("{}.sav", base);
+    if battery {
+        read_sav(&mut gameboy, &sav_path);
+    }
+    let mut last_flush = Instant::now();
+
+    let mut filter = VideoFilter::new(config.scale, config.filter);
+    filter.grid = config.lcd_grid;
+    filter.ghosting = config.ghosting;
+    let ghosting = if config.ghosting > 0 { config.ghosting } else { DEFAULT_GHOSTING };
 
-    let mut buffer: [u32; SCREEN_W * SCREEN_H] = [0; SCREEN_H * SCREEN_W];
     let mut window = Window::new(
-        title,
-        SCREEN_W,
-        SCREEN_H,
+        gameboy.title(),
+        filter.width(),
+        filter.height(),
         WindowOptions {
-            scale: Scale::X2,
+            scale: Scale::X1,
             ..WindowOptions::default()
         },
     ).unwrap_or_else(|e| { panic!("{}", e) });
 
-    let intr = Rc::new(RefCell::new(IntReq::new()));
-    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
-    let mut cpu = CPU::new(timer.clone());
-    let mut ppu = PPU::new(intr.clone());
-
-    mmu.read_boot(&boot);
+    // emulation is paced by the audio queue when there's a device to play
+    // to, and by the emulated refresh rate otherwise
+    window.limit_update_rate(None);
+    let mut audio = open_audio();
+    let rate_control = RateControl::new(AUDIO_TARGET_QUEUED * audio.sample_rate() as usize
+        / DEFAULT_SAMPLE_RATE as usize);
+    gameboy.set_sample_rate(Some(audio.sample_rate()));
+    let mut next_frame = Instant::now();
 
-    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
+    let mut slot = 0;
+    let mut recording: Option<Recording> = None;
+    // after breaking on a lockup, until a state is loaded
+    let mut paused = false;
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        cpu.step(&mut mmu);
-        let m_clocks = cpu.clocks_elapsed;
-        ppu.tick_n(&mut mmu, m_clocks);
-
-        if ppu.update_screen {
-            let mut i = 0;
-            for row in ppu.data.iter() {
-                for pixel in row.iter() {
-                    buffer[i as usize] = *pixel;
-                    i += 1;
-                }
+        if !paused {
+            gameboy.run_frame();
+        }
+        if let Some(lockup) = gameboy.take_lockup() {
+            eprintln!("{}", lockup);
+            if config.on_lockup == LockupAction::Break {
+                eprintln!("{:?}", gameboy.cpu.reg);
+                paused = true;
+            }
+        }
+        let samples = gameboy.take_samples();
+        audio.write(&samples);
+        if let (Some(recording), false) = (recording.as_mut(), paused) {
+            recording.video.write_frame(gameboy.framebuffer());
+            if let Some(ref mut wav) = recording.audio {
+                wav.write(&samples);
             }
-            window.update_with_buffer(&buffer, SCREEN_W, SCREEN_H).unwrap();
-            ppu.update_screen = false;
         }
 
+        // while paused the audio queue runs dry, so it can't pace the loop
+        match audio.queued() {
+            Some(queued) if !paused => {
+                gameboy.set_rate_ratio(rate_control.ratio(queued));
+                while audio.queued().unwrap_or(0) > rate_control.target {
+                    thread::sleep(Duration::from_millis(1));
+                }
+            },
+            _ => {
+                next_frame += frame_duration();
+                let now = Instant::now();
+                if next_frame > now {
+                    thread::sleep(next_frame - now);
+                } else if now - next_frame > frame_duration() * 4 {
+                    // too far behind to catch up, don't run fast forward
+                    next_frame = now;
+                }
+            },
+        }
+
+        let (width, height) = (filter.width(), filter.height());
+        window.update_with_buffer(filter.apply(gameboy.framebuffer()), width, height).unwrap();
+
         for (k,b) in &BUTTONS {
             if window.is_key_down(*k) {
-                mmu.joypad.button_down(*b);
+                gameboy.press(*b);
             } else {
-                mmu.joypad.button_up(*b);
+                gameboy.release(*b);
+            }
+        }
+
+        for (n, k) in SLOT_KEYS.iter().enumerate() {
+            if window.is_key_pressed(*k, KeyRepeat::No) {
+                slot = n;
             }
         }
+        let state_path = format!("{}.ss{}", base, slot);
+        if window.is_key_pressed(SAVE_STATE_KEY, KeyRepeat::No) {
+            save_state(&gameboy, &state_path);
+        }
+        if window.is_key_pressed(LOAD_STATE_KEY, KeyRepeat::No) && load_state(&mut gameboy, &state_path) {
+            paused = false;
+        }
+
+        if window.is_key_pressed(PALETTE_KEY, KeyRepeat::No) {
+            palette = (palette + 1) % palettes.len();
+            let (name, colors) = palettes[palette];
+            gameboy.set_palette(colors);
+            println!("Palette: {}", name);
+        }
+
+        if window.is_key_pressed(FILTER_KEY, KeyRepeat::No) {
+            let fitting: Vec<Upscaler> = Upscaler::ALL.iter().copied()
+                .filter(|u| u.fits(filter.scale))
+                .collect();
+            let current = fitting.iter().position(|u| *u == filter.upscaler).unwrap_or(0);
+            filter.upscaler = fitting[(current + 1) % fitting.len()];
+            println!("Filter: {}", filter.upscaler.name());
+        }
+        if window.is_key_pressed(GRID_KEY, KeyRepeat::No) {
+            filter.grid = !filter.grid;
+        }
+        if window.is_key_pressed(GHOSTING_KEY, KeyRepeat::No) {
+            filter.ghosting = if filter.ghosting > 0 { 0 } else { ghosting };
+            println!("Ghosting: {}%", filter.ghosting);
+        }
+
+        if window.is_key_pressed(SCREENSHOT_KEY, KeyRepeat::No) {
+            match gameboy.save_screenshot(".", config.screenshot_scale) {
+                Err(e) => eprintln!("Couldn't write screenshot: {}", e),
+                Ok(path) => println!("Saved screenshot to {}", path),
+            }
+        }
+
+        if window.is_key_pressed(RECORD_KEY, KeyRepeat::No) {
+            recording = match recording.take() {
+                Some(r) => {
+                    stop_recording(r);
+                    None
+                },
+                None => start_recording(&gameboy, config.record_format, audio.sample_rate()),
+            };
+        }
+
+        if battery && gameboy.mmu.cartridge.ram_dirty && last_flush.elapsed() >= SAV_FLUSH_INTERVAL {
+            write_sav(&mut gameboy, &sav_path);
+            last_flush = Instant::now();
+        }
+    }
+
+    if let Some(r) = recording {
+        stop_recording(r);
+    }
+    if battery && gameboy.mmu.cartridge.ram_dirty {
+        write_sav(&mut gameboy, &sav_path);
     }
 }
@@ -2,14 +2,42 @@ use crate::cartridge::Cartridge;
 use crate::timer::Timer;
 use crate::interrupts::IntReq;
 use crate::joypad::Joypad;
+use crate::serial::Serial;
+use crate::apu::APU;
+use crate::ppu::PaletteRam;
+use crate::hdma::Hdma;
+use crate::dma::{OamDma, OAM_DMA_LENGTH};
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+use crate::cpu::Bus;
 use std::rc::Rc;
 use std::cell::RefCell;
 
-pub struct MMU<'a> {
-    boot: [u8; 0x100],
-    cartridge: &'a mut Cartridge,
-    vram: [u8; 0x2000],
-    wram: [u8; 0x2000],
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Model {
+    DMG,
+    CGB,
+}
+
+pub struct MMU {
+    boot: Vec<u8>,
+    pub cartridge: Cartridge,
+    pub model: Model,
+    // a CGB runs DMG cartridges in a compatibility mode with the CGB
+    // registers locked, and the DMG palettes picking colours from palette RAM
+    pub cgb_mode: bool,
+    pub double_speed: bool,
+    speed_switch_armed: bool,
+    vram: [u8; 0x4000],
+    vbk: u8,
+    wram: [u8; 0x8000],
+    svbk: u8,
+    pub bg_palettes: PaletteRam,
+    pub obj_palettes: PaletteRam,
+    pub hdma: Hdma,
+    pub oam_dma: OamDma,
+    // whether the CPU is kept out of VRAM and OAM while the PPU uses
+    // them. a setting for debugging rather than part of the state
+    pub restrict_access: bool,
     oam: [u8; 0xA0],
     io_reg: [u8; 0x80],
     hram: [u8; 0x80],
@@ -17,59 +45,245 @@ pub struct MMU<'a> {
     pub timer: Rc<RefCell<Timer>>,
     pub intr: Rc<RefCell<IntReq>>,
     pub joypad: Joypad,
+    pub serial: Serial,
+    pub apu: APU,
 }
 
-impl<'a> MMU<'a> {
-    pub fn new(cartridge: &'a mut Cartridge, timer: Rc<RefCell<Timer>>) -> MMU {
+impl MMU {
+    pub fn new(cartridge: Cartridge, timer: Rc<RefCell<Timer>>) -> MMU {
         let intr = timer.borrow_mut().intr.clone();
         MMU {
-            boot: [0; 0x100],
+            boot: Vec::new(),
             cartridge,
-            vram: [0; 0x2000],
-            wram: [0; 0x2000],
+            model: Model::DMG,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            vram: [0; 0x4000],
+            vbk: 0,
+            wram: [0; 0x8000],
+            svbk: 0,
+            bg_palettes: PaletteRam::new(),
+            obj_palettes: PaletteRam::new(),
+            hdma: Hdma::new(),
+            oam_dma: OamDma::new(),
+            restrict_access: true,
             oam: [0; 0xA0],
             io_reg: [0; 0x80],
             hram: [0; 0x80],
             ie: 0,
             timer,
             joypad: Joypad::new(intr.clone()),
+            serial: Serial::new(intr.clone()),
+            apu: APU::new(),
             intr,
         }
     }
 
-    pub fn read_boot(&mut self, boot: &Vec<u8>) {
-        let mut i: usize = 0x0000;
-        for &byte in boot.iter() {
-            self.boot[i] = byte;
-            i += 1;
+    pub fn read_boot(&mut self, boot: &[u8]) {
+        self.boot = boot.to_vec();
+    }
+
+    // the DMG boot rom covers 0x0000-0x00FF, the CGB one also 0x0200-0x08FF
+    fn boot_mapped(&self, address: usize) -> bool {
+        self.io_reg[0x50] == 0 && address < self.boot.len() && !(0x100..0x200).contains(&address)
+    }
+
+    // reads VRAM the way the PPU sees it, from either bank
+    pub fn vram(&self, bank: u8, address: u16) -> u8 {
+        self.vram[((bank as usize & 1) << 13) | (address as usize & 0x1FFF)]
+    }
+
+    // reads OAM the way the PPU sees it, which is not at all during OAM DMA
+    pub fn oam(&self, address: u16) -> u8 {
+        if self.oam_dma.active {
+            0xFF
+        } else {
+            self.oam[address as usize & 0xFF]
+        }
+    }
+
+    fn vram_index(&self, address: usize) -> usize {
+        ((self.vbk as usize) << 13) | (address & 0x1FFF)
+    }
+
+    // 0xD000-0xDFFF is switchable on the CGB, where bank 0 selects bank 1
+    fn wram_index(&self, address: usize) -> usize {
+        if address & 0x1000 == 0 {
+            return address & 0xFFF;
+        }
+        let bank = if self.cgb_mode { (self.svbk as usize).max(1) } else { 1 };
+        (bank << 12) | (address & 0xFFF)
+    }
+
+    // copies one block of 16 bytes into the selected VRAM bank. the CPU
+    // waits 8 M-cycles for it, which is twice as many at double speed
+    fn hdma_block(&mut self) {
+        let (source, dest) = self.hdma.next_block();
+        for i in 0..0x10 {
+            let byte = self.read(source.wrapping_add(i));
+            let index = self.vram_index((dest + i) as usize);
+            self.vram[index] = byte;
         }
+        self.hdma.stall += if self.double_speed { 16 } else { 8 };
     }
 
+    // called by the PPU whenever it enters HBlank on a visible line
+    pub fn hblank_dma(&mut self) {
+        if self.hdma.active && self.hdma.hblank {
+            self.hdma_block();
+        }
+    }
+
+    // how the PPU updates LY and STAT, which the CPU can't write
+    pub fn set_lcd_status(&mut self, ly: u8, stat: u8) {
+        self.io_reg[0x44] = ly;
+        self.io_reg[0x41] = stat;
+    }
+
+    // a STOP while KEY1 is armed switches speeds, returning whether it did
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.speed_switch_armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.cgb_mode);
+        w.write_bool(self.double_speed);
+        w.write_bool(self.speed_switch_armed);
+        w.write_bytes(&self.vram);
+        w.write_u8(self.vbk);
+        w.write_bytes(&self.wram);
+        w.write_u8(self.svbk);
+        self.bg_palettes.save_state(w);
+        self.obj_palettes.save_state(w);
+        self.hdma.save_state(w);
+        self.oam_dma.save_state(w);
+        w.write_bytes(&self.oam);
+        w.write_bytes(&self.io_reg);
+        w.write_bytes(&self.hram);
+        w.write_u8(self.ie);
+        w.write_u8(self.intr.borrow().flags);
+        self.timer.borrow().save_state(w);
+        self.joypad.save_state(w);
+        self.serial.save_state(w);
+        self.apu.save_state(w);
+        self.cartridge.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.cgb_mode = r.read_bool()?;
+        self.double_speed = r.read_bool()?;
+        self.speed_switch_armed = r.read_bool()?;
+        r.read_bytes(&mut self.vram)?;
+        self.vbk = r.read_u8()? & 1;
+        r.read_bytes(&mut self.wram)?;
+        self.svbk = r.read_u8()? & 0b111;
+        self.bg_palettes.load_state(r)?;
+        self.obj_palettes.load_state(r)?;
+        self.hdma.load_state(r)?;
+        self.oam_dma.load_state(r)?;
+        r.read_bytes(&mut self.oam)?;
+        r.read_bytes(&mut self.io_reg)?;
+        r.read_bytes(&mut self.hram)?;
+        self.ie = r.read_u8()?;
+        self.intr.borrow_mut().flags = r.read_u8()?;
+        self.timer.borrow_mut().load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.apu.load_state(r)?;
+        self.cartridge.load_state(r)
+    }
+
+    // runs OAM DMA alongside the CPU, one byte per M-cycle
+    pub fn tick_oam_dma(&mut self, m_clocks: u8) {
+        for _ in 0..m_clocks {
+            if self.oam_dma.active {
+                let index = self.oam_dma.index;
+                let byte = self.read(self.oam_dma.source + index);
+                self.oam[index as usize] = byte;
+                self.oam_dma.last_byte = byte;
+                self.oam_dma.index += 1;
+                if self.oam_dma.index == OAM_DMA_LENGTH {
+                    self.oam_dma.active = false;
+                }
+            }
+            if let Some(source) = self.oam_dma.starting.take() {
+                self.oam_dma.source = source;
+                self.oam_dma.index = 0;
+                self.oam_dma.active = true;
+            }
+        }
+    }
+
+    // the CPU's view of memory, which OAM DMA can get in the way of
     pub fn rb(&self, address: u16) -> u8 {
+        match self.oam_dma.conflict(address) {
+            Some(byte) => byte,
+            None if self.locked(address) => 0xFF,
+            None => self.read(address),
+        }
+    }
+
+    pub fn wb(&mut self, address: u16, value: u8) {
+        if self.oam_dma.conflict(address).is_none() && !self.locked(address) {
+            self.write(address, value);
+        }
+    }
+
+    // the PPU keeps VRAM to itself during pixel transfer,
+    // and OAM during OAM search as well
+    fn locked(&self, address: u16) -> bool {
+        if !self.restrict_access {
+            return false;
+        }
+        let mode = self.io_reg[0x41] & 0b11;
+        match address {
+            0x8000..=0x9FFF => mode == 3,
+            0xFE00..=0xFE9F => mode >= 2,
+            _ => false,
+        }
+    }
+
+    fn read(&self, address: u16) -> u8 {
         let address = address as usize;
-        if address < 0x100 {
-            return if self.rb(0xFF50) == 0 {
-                self.boot[address]
-            } else {
-                self.cartridge.read_lower_rom(address as u16)
-            }
+        if self.boot_mapped(address) {
+            return self.boot[address];
         }
 
         match address {
             0x0000..=0x3FFF => self.cartridge.read_lower_rom(address as u16),
             0x4000..=0x7FFF => self.cartridge.read_upper_rom(address as u16),
-            0x8000..=0x9FFF => self.vram[address & 0x1FFF],
+            0x8000..=0x9FFF => self.vram[self.vram_index(address)],
             0xA000..=0xBFFF => self.cartridge.read_ram(address as u16),
-            0xC000..=0xDFFF => self.wram[address & 0x1FFF],
-            0xE000..=0xFDFF => self.wram[address & 0x1FFF], // echo
+            0xC000..=0xDFFF => self.wram[self.wram_index(address)],
+            0xE000..=0xFDFF => self.wram[self.wram_index(address)], // echo
             0xFE00..=0xFE9F => self.oam[address & 0xFF],
             0xFEA0..=0xFEFF => 0xFF, // unusable area returns FFh
             0xFF00 => self.joypad.state(),
+            0xFF01 => self.serial.sb,
+            0xFF02 => self.serial.control(),
             0xFF04 => self.timer.borrow_mut().div,
             0xFF05 => self.timer.borrow_mut().tima,
             0xFF06 => self.timer.borrow_mut().tma,
             0xFF07 => self.timer.borrow_mut().tac,
             0xFF0F => self.intr.borrow_mut().flags,
+            0xFF10..=0xFF3F => self.apu.read(address as u16),
+            0xFF4D if self.cgb_mode => {
+                0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_armed as u8
+            },
+            0xFF4F if self.cgb_mode => 0xFE | self.vbk,
+            0xFF51..=0xFF55 if self.cgb_mode => self.hdma.read(address as u16),
+            0xFF68 if self.cgb_mode => self.bg_palettes.spec(),
+            0xFF69 if self.cgb_mode => self.bg_palettes.read_data(),
+            0xFF6A if self.cgb_mode => self.obj_palettes.spec(),
+            0xFF6B if self.cgb_mode => self.obj_palettes.read_data(),
+            0xFF70 if self.cgb_mode => 0xF8 | self.svbk,
+            0xFF4C..=0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFF70 => 0xFF,
             0xFF00..=0xFF7F => self.io_reg[address & 0x7F],
             0xFF80..=0xFFFE => self.hram[address & 0x7F],
             0xFFFF => self.ie,
@@ -77,17 +291,19 @@ impl<'a> MMU<'a> {
         }
     }
 
-    pub fn wb(&mut self, address: u16, value: u8) {
+    fn write(&mut self, address: u16, value: u8) {
         let address = address as usize;
         match address {
             0x0000..=0x7FFF => self.cartridge.write_rom(address as u16, value),
-            0x8000..=0x9FFF => self.vram[address & 0x1FFF] = value,
+            0x8000..=0x9FFF => self.vram[self.vram_index(address)] = value,
             0xA000..=0xBFFF => self.cartridge.write_ram(address as u16, value),
-            0xC000..=0xDFFF => self.wram[address & 0x1FFF] = value,
+            0xC000..=0xDFFF => self.wram[self.wram_index(address)] = value,
             0xE000..=0xFDFF => (), // echo not writable
             0xFE00..=0xFE9F => self.oam[address & 0xFF] = value,
             0xFEA0..=0xFEFF => (), // writes to unusable area have no effect
             0xFF00 => self.joypad.set_select(value),
+            0xFF01 => self.serial.sb = value,
+            0xFF02 => self.serial.set_control(value),
             0xFF04 => {
                 self.timer.borrow_mut().counter = 0;
                 self.timer.borrow_mut().div = 0;
@@ -96,15 +312,39 @@ impl<'a> MMU<'a> {
             0xFF06 => self.timer.borrow_mut().tma = value,
             0xFF07 => self.timer.borrow_mut().tac = value,
             0xFF0F => self.intr.borrow_mut().flags = 0b1110_0000 | value,
+            0xFF10..=0xFF3F => self.apu.write(address as u16, value),
+            // KEY0, the CGB boot rom drops into compatibility mode through it
+            0xFF4C => {
+                if self.model == Model::CGB && self.boot_mapped(0) {
+                    self.cgb_mode = (value & 0b0100) == 0;
+                }
+            },
+            0xFF4D if self.cgb_mode => self.speed_switch_armed = (value & 1) != 0,
+            0xFF4F if self.cgb_mode => self.vbk = value & 1,
+            0xFF51..=0xFF55 if self.cgb_mode => {
+                // a general purpose transfer copies everything at once
+                if self.hdma.write(address as u16, value) {
+                    while self.hdma.active {
+                        self.hdma_block();
+                    }
+                } else if address == 0xFF55 && self.hdma.active && (self.io_reg[0x40] & 0x80) == 0 {
+                    // with the LCD off there are no HBlanks, the first block goes right away
+                    self.hdma_block();
+                }
+            },
+            0xFF68 if self.cgb_mode => self.bg_palettes.set_spec(value),
+            0xFF69 if self.cgb_mode => self.bg_palettes.write_data(value),
+            0xFF6A if self.cgb_mode => self.obj_palettes.set_spec(value),
+            0xFF6B if self.cgb_mode => self.obj_palettes.write_data(value),
+            0xFF70 if self.cgb_mode => self.svbk = value & 0b111,
+            0xFF4D..=0xFF4F | 0xFF51..=0xFF55 | 0xFF68..=0xFF6B | 0xFF70 => (),
             0xFF46 => {
-                assert!(value <= 0xF1);
                 self.io_reg[address & 0x7F] = value;
-                let hi = (value as u16) << 8;
-                for lo in 0..0xA0 {
-                    let byte = self.rb(hi | lo);
-                    self.wb(0xFE00 | lo, byte);
-                }
-            }
+                self.oam_dma.start(value);
+            },
+            // the mode and coincidence bits of STAT and all of LY belong to the PPU
+            0xFF41 => self.io_reg[0x41] = 0x80 | (value & 0x78) | (self.io_reg[0x41] & 0b111),
+            0xFF44 => (),
             0xFF00..=0xFF7F => self.io_reg[address & 0x7F] = value,
             0xFF80..=0xFFFE => self.hram[address & 0x7F] = value,
             0xFFFF => self.ie = value,
@@ -112,3 +352,16 @@ impl<'a> MMU<'a> {
         }
     }
 }
+
+// on its own the MMU only has the timer and OAM DMA to run alongside
+// the CPU, which is all the CPU's tests need
+impl Bus for MMU {
+    fn mmu(&mut self) -> &mut MMU {
+        self
+    }
+
+    fn tick(&mut self) {
+        self.timer.borrow_mut().tick();
+        self.tick_oam_dma(1);
+    }
+}
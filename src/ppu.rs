@@ -1,26 +1,105 @@
-use crate::mmu::MMU;
+use crate::mmu::{MMU, Model};
 use crate::interrupts::*;
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::collections::VecDeque;
 
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
 
-//const DARKEST: u32  = 0xFF0F380F;
-//const DARK: u32     = 0xFF306230;
-//const LIGHT: u32    = 0xFF8BAC0F;
-//const LIGHTEST: u32 = 0xFF9BBC0F;
-const DARKEST: u32  = 0xFF000000;
-const DARK: u32     = 0xFF606060;
-const LIGHT: u32    = 0xFFC0C0C0;
-const LIGHTEST: u32 = 0xFFFFFFFF;
-
-const GREYS: [u32; 4] = [
-    LIGHTEST,
-    LIGHT,
-    DARK,
-    DARKEST,
-];
+// the colours a DMG's four shades are shown as, for the background and
+// window and for either sprite palette
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DmgPalette {
+    pub bg: [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+impl DmgPalette {
+    pub fn uniform(colors: [u32; 4]) -> DmgPalette {
+        DmgPalette {
+            bg: colors,
+            obj0: colors,
+            obj1: colors,
+        }
+    }
+}
+
+impl DmgPalette {
+    // the colour a pixel from the raw framebuffer comes out as
+    pub fn colorize(&self, pixel: RawPixel) -> u32 {
+        let colors = match (pixel.layer, pixel.palette) {
+            (Layer::Object, 0) => &self.obj0,
+            (Layer::Object, _) => &self.obj1,
+            _ => &self.bg,
+        };
+        colors[pixel.shade as usize & 0b11]
+    }
+}
+
+impl Default for DmgPalette {
+    fn default() -> DmgPalette {
+        Preset::Grey.palette()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub enum Layer {
+    #[default]
+    Background,
+    Window,
+    Object,
+}
+
+// what a pixel on screen was made from, before it's given a colour
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct RawPixel {
+    // the colour index in the tile
+    pub color: u8,
+    // the colour index after going through BGP, OBP0 or OBP1. in CGB mode,
+    // which has no such registers, the same as the colour index
+    pub shade: u8,
+    pub layer: Layer,
+    // OBP0 or OBP1 for DMG sprites, 0-7 in CGB mode
+    pub palette: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Preset {
+    Grey,
+    Green,
+    Pocket,
+    Light,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 4] = [Preset::Grey, Preset::Green, Preset::Pocket, Preset::Light];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::Grey => "grey",
+            Preset::Green => "green",
+            Preset::Pocket => "pocket",
+            Preset::Light => "light",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Preset> {
+        Preset::ALL.iter().copied().find(|p| p.name().eq_ignore_ascii_case(name))
+    }
+
+    // lightest first
+    pub fn palette(self) -> DmgPalette {
+        DmgPalette::uniform(match self {
+            Preset::Grey => [0xFFFFFFFF, 0xFFC0C0C0, 0xFF606060, 0xFF000000],
+            Preset::Green => [0xFF9BBC0F, 0xFF8BAC0F, 0xFF306230, 0xFF0F380F],
+            Preset::Pocket => [0xFFC4CFA1, 0xFF8B956D, 0xFF4D533C, 0xFF1F1F1F],
+            Preset::Light => [0xFF00B581, 0xFF009A71, 0xFF00694A, 0xFF004F3B],
+        })
+    }
+}
 
 const TILE_MAP0: u16 = 0x9800;
 const TILE_MAP1: u16 = 0x9C00;
@@ -28,7 +107,87 @@ const TILE_SET0: u16 = 0x9000;
 const TILE_SET1: u16 = 0x8000;
 const OAM_START: u16 = 0xFE00;
 const OAM_SEARCH_END: u16 = 80;
-const PIXEL_TRANSFER_END: u16 = 80 + 172;
+const MAX_SPRITES_PER_LINE: usize = 10;
+const LINE_DOTS: u16 = 456;
+// the fetcher spends two dots on each of the tile number, low and high bytes
+const FETCH_DOTS: u8 = 6;
+const OBJ_FETCH_DOTS: u8 = 6;
+
+// converts a little endian RGB555 colour from palette RAM
+pub fn rgb555(color: u16) -> u32 {
+    let scale = |c: u16| -> u32 {
+        let c = (c & 0x1F) as u32;
+        (c << 3) | (c >> 2)
+    };
+    0xFF00_0000 | (scale(color) << 16) | (scale(color >> 5) << 8) | scale(color >> 10)
+}
+
+// 8 palettes of 4 colours each, accessed through BCPS/BCPD or OCPS/OCPD
+#[derive(Debug, Clone)]
+pub struct PaletteRam {
+    pub data: [u8; 64],
+    index: u8,
+    auto_increment: bool,
+}
+
+impl PaletteRam {
+    pub fn new() -> PaletteRam {
+        PaletteRam {
+            data: [0xFF; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    pub fn spec(&self) -> u8 {
+        0x40 | ((self.auto_increment as u8) << 7) | self.index
+    }
+
+    pub fn set_spec(&mut self, value: u8) {
+        self.auto_increment = (value & 0x80) != 0;
+        self.index = value & 0x3F;
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3F;
+        }
+    }
+
+    pub fn set_palette(&mut self, palette: usize, colors: [u16; 4]) {
+        for (i, color) in colors.iter().enumerate() {
+            let offset = palette * 8 + i * 2;
+            self.data[offset..offset + 2].copy_from_slice(&color.to_le_bytes());
+        }
+    }
+
+    pub fn color(&self, palette: u8, index: u8) -> u32 {
+        let offset = (palette as usize & 0b111) * 8 + (index as usize & 0b11) * 2;
+        rgb555(u16::from_le_bytes([self.data[offset], self.data[offset + 1]]))
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.data);
+        w.write_u8(self.spec());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.read_bytes(&mut self.data)?;
+        self.set_spec(r.read_u8()?);
+        Ok(())
+    }
+}
+
+impl Default for PaletteRam {
+    fn default() -> PaletteRam {
+        PaletteRam::new()
+    }
+}
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Mode {
@@ -98,15 +257,99 @@ impl From<Stat> for u8 {
     }
 }
 
+#[derive(Debug, Copy, Clone, Default)]
+struct BgPixel {
+    color: u8,
+    palette: u8,
+    // CGB attribute bit 7, the tile goes over sprites
+    priority: bool,
+    window: bool,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+struct ObjPixel {
+    color: u8,
+    palette: u8,
+    behind_bg: bool,
+    // position in OAM, which decides overlaps in CGB mode
+    index: u8,
+}
+
+// a sprite picked during OAM search for the current line
+#[derive(Debug, Copy, Clone)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attr: u8,
+    index: u8,
+    fetched: bool,
+}
+
+// fetches a row of 8 background or window pixels at a time. every step
+// takes two dots, then it waits for the FIFO to empty before pushing
+#[derive(Debug, Clone)]
+struct Fetcher {
+    dots: u8,
+    // tile column, counted from the left of the background or window
+    x: u8,
+    window: bool,
+    // the first fetch of every line is thrown away
+    discard: bool,
+    // line within the tile map
+    y: u8,
+    tile: u8,
+    attr: u8,
+    lo: u8,
+    hi: u8,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Fetcher {
+        Fetcher {
+            dots: 0,
+            x: 0,
+            window,
+            discard: !window,
+            y: 0,
+            tile: 0,
+            attr: 0,
+            lo: 0,
+            hi: 0,
+        }
+    }
+}
+
 pub struct PPU {
     pub mode_clock: u16,
     intr: Rc<RefCell<IntReq>>,
     pub data: [[u32; SCREEN_W]; SCREEN_H],
+    // the same frame as colour indices, layers and palettes
+    pub raw: Box<[[RawPixel; SCREEN_W]; SCREEN_H]>,
     pub update_screen: bool,
     pub stat: Stat,
-    bg_palette: [u32; 4],
-    obp0: [u32; 4],
-    obp1: [u32; 4],
+    // the colours used for DMG shades, not part of the state
+    pub palette: DmgPalette,
+    bg_fifo: VecDeque<BgPixel>,
+    obj_fifo: VecDeque<ObjPixel>,
+    fetcher: Fetcher,
+    sprites: Vec<Sprite>,
+    // the sprite being fetched and the dots it has left
+    obj_fetch: Option<(usize, u8)>,
+    // the next pixel to go out on the current line
+    lx: u8,
+    // pixels still to be dropped, for the fine SCX scroll or a window
+    // starting left of the screen
+    discard: u8,
+    // the window's own line, which only moves on lines it was drawn on
+    window_line: u8,
+    // set once LY has matched WY this frame
+    wy_triggered: bool,
+    ly: u8,
+    lcd_on: bool,
+    // nothing gets drawn until the end of this frame
+    blank_frame: bool,
+    stat_line: bool,
 }
 
 impl PPU {
@@ -115,28 +358,204 @@ impl PPU {
             mode_clock: 0,
             intr,
             data: [[0; SCREEN_W]; SCREEN_H],
+            raw: Box::new([[RawPixel::default(); SCREEN_W]; SCREEN_H]),
             update_screen: false,
             stat: Stat::new(),
-            bg_palette: [0; 4],
-            obp0: [0; 4],
-            obp1: [0; 4],
+            palette: DmgPalette::default(),
+            bg_fifo: VecDeque::with_capacity(8),
+            obj_fifo: VecDeque::with_capacity(8),
+            fetcher: Fetcher::new(false),
+            sprites: Vec::with_capacity(40),
+            obj_fetch: None,
+            lx: 0,
+            discard: 0,
+            window_line: 0,
+            wy_triggered: false,
+            ly: 0,
+            // as if it was already on, so that starting with it on doesn't
+            // lose a frame
+            lcd_on: true,
+            blank_frame: false,
+            stat_line: false,
         }
     }
 
-    pub fn get_ly(&self, mmu: &MMU) -> u8 {
-        mmu.rb(0xFF44)
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.mode_clock);
+        w.write_u8(self.ly);
+        w.write_bool(self.lcd_on);
+        w.write_bool(self.blank_frame);
+        w.write_bool(self.stat_line);
+        w.write_u8(u8::from(self.stat.clone()));
+        w.write_u8(self.lx);
+        w.write_u8(self.discard);
+        w.write_u8(self.window_line);
+        w.write_bool(self.wy_triggered);
+
+        let f = &self.fetcher;
+        w.write_u8(f.dots);
+        w.write_u8(f.x);
+        w.write_bool(f.window);
+        w.write_bool(f.discard);
+        w.write_u8(f.y);
+        w.write_u8(f.tile);
+        w.write_u8(f.attr);
+        w.write_u8(f.lo);
+        w.write_u8(f.hi);
+
+        w.write_u8(self.bg_fifo.len() as u8);
+        for p in self.bg_fifo.iter() {
+            w.write_u8(p.color);
+            w.write_u8(p.palette);
+            w.write_bool(p.priority);
+            w.write_bool(p.window);
+        }
+        w.write_u8(self.obj_fifo.len() as u8);
+        for p in self.obj_fifo.iter() {
+            w.write_u8(p.color);
+            w.write_u8(p.palette);
+            w.write_bool(p.behind_bg);
+            w.write_u8(p.index);
+        }
+        w.write_u8(self.sprites.len() as u8);
+        for s in self.sprites.iter() {
+            w.write_u8(s.y);
+            w.write_u8(s.x);
+            w.write_u8(s.tile);
+            w.write_u8(s.attr);
+            w.write_u8(s.index);
+            w.write_bool(s.fetched);
+        }
+        let (sprite, dots) = self.obj_fetch.unwrap_or((0, 0));
+        w.write_bool(self.obj_fetch.is_some());
+        w.write_u8(sprite as u8);
+        w.write_u8(dots);
+
+        for row in self.data.iter() {
+            for pixel in row.iter() {
+                w.write_u32(*pixel);
+            }
+        }
+        for row in self.raw.iter() {
+            for pixel in row.iter() {
+                w.write_u8(pixel.color);
+                w.write_u8(pixel.shade);
+                w.write_u8(pixel.layer as u8);
+                w.write_u8(pixel.palette);
+            }
+        }
     }
 
-    pub fn inc_ly(&self, mmu: &mut MMU) {
-        mmu.wb(0xFF44, (self.get_ly(mmu) + 1) % 154);
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.mode_clock = r.read_u16()?;
+        if self.mode_clock >= LINE_DOTS {
+            return Err(SaveStateError::InvalidData);
+        }
+        self.ly = r.read_u8()?;
+        if self.ly >= 154 {
+            return Err(SaveStateError::InvalidData);
+        }
+        self.lcd_on = r.read_bool()?;
+        self.blank_frame = r.read_bool()?;
+        self.stat_line = r.read_bool()?;
+        self.stat = Stat::from(r.read_u8()?);
+        self.lx = r.read_u8()?;
+        self.discard = r.read_u8()?;
+        self.window_line = r.read_u8()?;
+        self.wy_triggered = r.read_bool()?;
+
+        let f = &mut self.fetcher;
+        f.dots = r.read_u8()?;
+        f.x = r.read_u8()?;
+        f.window = r.read_bool()?;
+        f.discard = r.read_bool()?;
+        f.y = r.read_u8()?;
+        f.tile = r.read_u8()?;
+        f.attr = r.read_u8()?;
+        f.lo = r.read_u8()?;
+        f.hi = r.read_u8()?;
+
+        self.bg_fifo.clear();
+        let len = r.read_u8()?;
+        if len > 8 {
+            return Err(SaveStateError::InvalidData);
+        }
+        for _ in 0..len {
+            self.bg_fifo.push_back(BgPixel {
+                color: r.read_u8()? & 0b11,
+                palette: r.read_u8()? & 0b111,
+                priority: r.read_bool()?,
+                window: r.read_bool()?,
+            });
+        }
+        self.obj_fifo.clear();
+        let len = r.read_u8()?;
+        if len > 8 {
+            return Err(SaveStateError::InvalidData);
+        }
+        for _ in 0..len {
+            self.obj_fifo.push_back(ObjPixel {
+                color: r.read_u8()? & 0b11,
+                palette: r.read_u8()? & 0b111,
+                behind_bg: r.read_bool()?,
+                index: r.read_u8()?,
+            });
+        }
+        self.sprites.clear();
+        let len = r.read_u8()?;
+        if len as usize > MAX_SPRITES_PER_LINE {
+            return Err(SaveStateError::InvalidData);
+        }
+        for _ in 0..len {
+            self.sprites.push(Sprite {
+                y: r.read_u8()?,
+                x: r.read_u8()?,
+                tile: r.read_u8()?,
+                attr: r.read_u8()?,
+                index: r.read_u8()?,
+                fetched: r.read_bool()?,
+            });
+        }
+        let fetching = r.read_bool()?;
+        let sprite = r.read_u8()? as usize;
+        let dots = r.read_u8()?;
+        self.obj_fetch = match fetching {
+            true if sprite < self.sprites.len() => Some((sprite, dots)),
+            true => return Err(SaveStateError::InvalidData),
+            false => None,
+        };
+
+        for row in self.data.iter_mut() {
+            for pixel in row.iter_mut() {
+                *pixel = r.read_u32()?;
+            }
+        }
+        for row in self.raw.iter_mut() {
+            for pixel in row.iter_mut() {
+                pixel.color = r.read_u8()? & 0b11;
+                pixel.shade = r.read_u8()? & 0b11;
+                pixel.layer = match r.read_u8()? {
+                    0 => Layer::Background,
+                    1 => Layer::Window,
+                    2 => Layer::Object,
+                    _ => return Err(SaveStateError::InvalidData),
+                };
+                pixel.palette = r.read_u8()? & 0b111;
+            }
+        }
+        Ok(())
     }
 
-    pub fn tick_n(&mut self, mmu: &mut MMU, m_clocks: u8) {
-        let lcdc = mmu.rb(0xFF40);
-        if (lcdc & 0x80) == 0 || m_clocks == 0 {
-            return;
+    // what LY reads. line 153 only lasts an M-cycle before it reads 0
+    pub fn get_ly(&self) -> u8 {
+        if self.ly == 153 && self.mode_clock >= 4 {
+            0
+        } else {
+            self.ly
         }
+    }
 
+    pub fn tick_n(&mut self, mmu: &mut MMU, m_clocks: u8) {
         for _ in 0..m_clocks {
             self.tick(mmu);
         }
@@ -145,214 +564,393 @@ impl PPU {
     pub fn tick(&mut self, mmu: &mut MMU) {
         let lcdc = mmu.rb(0xFF40);
         if (lcdc & 0x80) == 0 {
+            if self.lcd_on {
+                self.lcd_off(mmu);
+            }
             return;
         }
+        if !self.lcd_on {
+            self.lcd_on = true;
+            self.blank_frame = true;
+        }
 
         let stat = mmu.rb(0xFF41);
         self.stat = Stat::from(stat);
 
-        self.mode_clock += 4;
-        if self.mode_clock >= 456 {
-            self.mode_clock %= 456;
-            self.inc_ly(mmu);
-            let lyc = mmu.rb(0xFF45);
-            if self.get_ly(mmu) == lyc {
-                self.stat.coincidence = true;
-                if self.stat.enable_coincidence {
-                    self.intr.borrow_mut().set_flag(IntFlag::Stat);
-                }
-            } else {
-                self.stat.coincidence = false;
-            }
+        for _ in 0..4 {
+            self.dot(mmu);
         }
 
-        if self.get_ly(mmu) >= 144 {
+        let new_stat = self.stat.clone();
+        mmu.set_lcd_status(self.get_ly(), u8::from(new_stat));
+    }
+
+    // LY and the mode drop to 0 and the screen goes blank until the
+    // LCD is turned back on, which starts again at the top of a frame
+    fn lcd_off(&mut self, mmu: &mut MMU) {
+        self.lcd_on = false;
+        self.ly = 0;
+        self.mode_clock = 0;
+        self.window_line = 0;
+        self.wy_triggered = false;
+        self.stat_line = false;
+        self.stat = Stat::from(mmu.rb(0xFF41));
+        self.stat.mode = Mode::HBlank;
+        let blank = match mmu.model {
+            Model::DMG => self.palette.bg[0],
+            Model::CGB => rgb555(0x7FFF),
+        };
+        self.data = [[blank; SCREEN_W]; SCREEN_H];
+        *self.raw = [[RawPixel::default(); SCREEN_W]; SCREEN_H];
+        mmu.set_lcd_status(0, u8::from(self.stat.clone()));
+    }
+
+    fn dot(&mut self, mmu: &mut MMU) {
+        self.mode_clock += 1;
+        if self.mode_clock == LINE_DOTS {
+            self.mode_clock = 0;
+            self.ly = (self.ly + 1) % 154;
+        }
+
+        let ly = self.ly;
+        if ly >= 144 {
             if self.stat.mode != Mode::VBlank {
+                self.window_line = 0;
+                self.wy_triggered = false;
+                // the first frame after turning the LCD on isn't shown
                 self.update_screen = true;
+                self.blank_frame = false;
                 self.stat.mode = Mode::VBlank;
                 self.intr.borrow_mut().set_flag(IntFlag::VBlank);
-                if self.stat.enable_vblank {
-                    self.intr.borrow_mut().set_flag(IntFlag::Stat);
-                }
             }
         } else if self.mode_clock <= OAM_SEARCH_END {
-            if self.stat.mode != Mode::OAMSearch {
-                self.stat.mode = Mode::OAMSearch;
-                if self.stat.enable_oam_seach {
-                    self.intr.borrow_mut().set_flag(IntFlag::Stat);
-                }
+            self.stat.mode = Mode::OAMSearch;
+            if self.mode_clock == OAM_SEARCH_END {
+                self.oam_search(mmu, ly);
             }
-        } else if self.mode_clock <= PIXEL_TRANSFER_END {
-            if self.stat.mode != Mode::PixelTransfer {
-                self.stat.mode = Mode::PixelTransfer;
-
-                if lcdc & 0b01 != 0 {
-                    self.draw_bg(mmu);
-                }
-                if lcdc & 0b10 != 0 {
-                    self.draw_obj(mmu);
+        } else if self.stat.mode == Mode::OAMSearch {
+            self.stat.mode = Mode::PixelTransfer;
+            self.start_line(mmu);
+            self.transfer_dot(mmu, ly);
+        } else if self.stat.mode == Mode::PixelTransfer {
+            // mode 3 lasts as long as it takes to push out the whole line
+            if self.lx as usize == SCREEN_W {
+                if self.fetcher.window {
+                    self.window_line += 1;
                 }
-            }
-        } else {
-            if self.stat.mode != Mode::HBlank {
                 self.stat.mode = Mode::HBlank;
-                if self.stat.enable_hblank {
-                    self.intr.borrow_mut().set_flag(IntFlag::Stat);
-                }
+                mmu.hblank_dma();
+            } else {
+                self.transfer_dot(mmu, ly);
             }
         }
 
-        let new_stat = self.stat.clone();
-        mmu.wb(0xFF41, u8::from(new_stat))
+        self.stat.coincidence = self.get_ly() == mmu.rb(0xFF45);
+        self.update_stat_line();
     }
 
-    pub fn draw_bg(&mut self, mmu: &mut MMU) {
-        let bgp = mmu.rb(0xFF47) as usize;
-        self.bg_palette[3] = GREYS[(bgp & 0xC0) >> 6];
-        self.bg_palette[2] = GREYS[(bgp & 0x30) >> 4];
-        self.bg_palette[1] = GREYS[(bgp & 0x0C) >> 2];
-        self.bg_palette[0] = GREYS[(bgp & 0x03)];
+    // every STAT source feeds the same line, so the interrupt is only
+    // requested when it goes from low to high. a source that becomes
+    // active while another one already is gets blocked
+    fn update_stat_line(&mut self) {
+        let stat = &self.stat;
+        let line = (stat.enable_coincidence && stat.coincidence) || match stat.mode {
+            Mode::HBlank => stat.enable_hblank,
+            Mode::VBlank => stat.enable_vblank,
+            Mode::OAMSearch => stat.enable_oam_seach,
+            Mode::PixelTransfer => false,
+        };
+        if line && !self.stat_line {
+            self.intr.borrow_mut().set_flag(IntFlag::Stat);
+        }
+        self.stat_line = line;
+    }
 
-        let scx = mmu.rb(0xFF43);
-        let scy = mmu.rb(0xFF42);
-        let wy = mmu.rb(0xFF4A);
-        let wx = mmu.rb(0xFF4B).wrapping_sub(7);
+    // picks the first 10 sprites in OAM that cover this line. ones off
+    // screen horizontally still count
+    fn oam_search(&mut self, mmu: &MMU, ly: u8) {
         let lcdc = mmu.rb(0xFF40);
-        let ly = self.get_ly(mmu);
+        let sprite_size = if lcdc & (1 << 2) == 0 { 8 } else { 16 };
+        let line = ly as u16 + 16;
 
-        let bg_map0 = lcdc & (1 << 3) == 0;
-        let win_map0 = lcdc & (1 << 6) == 0;
-        let tile_set0 = lcdc & (1 << 4) == 0;
-        let tile_start = if tile_set0 {
-            TILE_SET0
-        } else {
-            TILE_SET1
-        };
+        self.sprites.clear();
+        for i in 0..40 {
+            if self.sprites.len() == MAX_SPRITES_PER_LINE {
+                break;
+            }
+            // each sprite has 4 bytes associated with it in the OAM:
+            // byte 0: Y coordinate (minus 16)
+            // byte 1: X coordinate (minus 8)
+            // byte 2: Tile number
+            // byte 3: Priority, y-flip, x-flip, palette, CGB VRAM bank, CGB palette
+            let address = OAM_START + i * 4;
+            let y = mmu.oam(address);
+            if line >= y as u16 && line < y as u16 + sprite_size {
+                self.sprites.push(Sprite {
+                    y,
+                    x: mmu.oam(address + 1),
+                    tile: mmu.oam(address + 2),
+                    attr: mmu.oam(address + 3),
+                    index: i as u8,
+                    fetched: false,
+                });
+            }
+        }
+    }
 
-        let y_in_win = ly >= wy && lcdc & (1 << 5) != 0;
-        let y = if y_in_win {
-            ly - wy
-        } else {
-            ly.wrapping_add(scy)
-        };
+    fn start_line(&mut self, mmu: &MMU) {
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.fetcher = Fetcher::new(false);
+        self.obj_fetch = None;
+        self.lx = 0;
+        self.discard = mmu.rb(0xFF43) & 0b111;
+        if self.ly == mmu.rb(0xFF4A) {
+            self.wy_triggered = true;
+        }
+    }
 
-        for i in 0..SCREEN_W {
-            let writing_win = (i as u8) >= wx && y_in_win;
-            let x = if writing_win {
-                (i as u8) - wx
-            } else {
-                (i as u8).wrapping_add(scx)
-            };
+    fn transfer_dot(&mut self, mmu: &MMU, ly: u8) {
+        let lcdc = mmu.rb(0xFF40);
 
-            let map_start = if writing_win {
-                if win_map0 { TILE_MAP0 } else { TILE_MAP1 }
-            } else {
-                if bg_map0 { TILE_MAP0 } else { TILE_MAP1 }
-            };
+        // reaching WX restarts the fetcher on the window's tiles. WX=166
+        // only reaches the last pixel, but still counts as a window line
+        let wx = mmu.rb(0xFF4B);
+        if !self.fetcher.window && self.discard == 0 && lcdc & (1 << 5) != 0
+            && self.wy_triggered && self.lx as u16 + 7 >= wx as u16 {
+            self.fetcher = Fetcher::new(true);
+            self.bg_fifo.clear();
+            // below 7 the window starts left of the screen
+            if self.lx == 0 {
+                self.discard = 7u8.saturating_sub(wx);
+            }
+        }
 
-            // grab tile num from tile map
-            // tile map is 32x32 tiles in length
-            let map_y = (y / 8) as u16;
-            let map_x = (x / 8) as u16;
-            let tile_map_address = map_start + (map_y * 32 + map_x);
-            let tile_map_index = mmu.rb(tile_map_address);
-
-            // grab two bytes
-            // each tile is 16 bytes long (8x8 pixels of 2-bit color)
-            // if TILE_SET0 in use, the indicies are signed
-            let tile_map_offset = if tile_set0 {
-                tile_map_index as i8 as u16
+        // a sprite starting here holds up the pixels until it's fetched
+        if self.obj_fetch.is_none() && lcdc & 0b10 != 0 && self.discard == 0 {
+            let lx = self.lx as u16;
+            let mut waiting = self.sprites.iter().enumerate()
+                .filter(|(_, s)| !s.fetched && s.x as u16 <= lx + 8);
+            // on the DMG the sprite further left goes first, so it wins
+            // overlaps. sprites hanging off the left edge all start at once
+            let next = if mmu.cgb_mode {
+                waiting.next()
             } else {
-                tile_map_index as u16
+                waiting.min_by_key(|(_, s)| s.x)
             };
+            if let Some((i, _)) = next {
+                self.obj_fetch = Some((i, OBJ_FETCH_DOTS));
+            }
+        }
 
-            let tile_idx = tile_map_offset.wrapping_mul(16);
-            let tile_row = (y as u16 % 8) * 2;
-            let index = tile_start.wrapping_add(tile_idx) + tile_row;
-            let byte1 = mmu.rb(index);
-            let byte2 = mmu.rb(index + 1);
-            // convert bits to color
-            let mask = 1 << (7 - (x % 8));
-            let bit1 = byte1 & mask != 0;
-            let bit2 = byte2 & mask != 0;
-            let pair = ((bit1 as u8) << 1) | bit2 as u8;
+        self.fetch_bg(mmu, ly);
 
-            let color = self.bg_palette[pair as usize];
-            self.data[self.get_ly(mmu) as usize][i] = color;
+        if let Some((i, dots)) = self.obj_fetch {
+            // the background fetcher has to finish its tile first
+            if self.fetcher.dots == FETCH_DOTS {
+                if dots == 1 {
+                    self.fetch_obj(mmu, ly, i);
+                    self.obj_fetch = None;
+                } else {
+                    self.obj_fetch = Some((i, dots - 1));
+                }
+            }
+            return;
         }
+
+        self.push_pixel(mmu, ly);
     }
 
-    pub fn draw_obj(&mut self, mmu: &mut MMU) {
-        let obp0 = mmu.rb(0xFF48) as usize;
-        self.obp0[3] = GREYS[(obp0 & 0xC0) >> 6];
-        self.obp0[2] = GREYS[(obp0 & 0x30) >> 4];
-        self.obp0[1] = GREYS[(obp0 & 0x0C) >> 2];
-        self.obp0[0] = GREYS[(obp0 & 0x03)];
+    fn fetch_bg(&mut self, mmu: &MMU, ly: u8) {
+        if self.fetcher.dots == FETCH_DOTS {
+            if !self.bg_fifo.is_empty() {
+                return;
+            }
+            if self.fetcher.discard {
+                self.fetcher.discard = false;
+            } else {
+                self.push_tile();
+            }
+            self.fetcher.dots = 0;
+        }
 
-        let obp1 = mmu.rb(0xFF49) as usize;
-        self.obp1[3] = GREYS[(obp1 & 0xC0) >> 6];
-        self.obp1[2] = GREYS[(obp1 & 0x30) >> 4];
-        self.obp1[1] = GREYS[(obp1 & 0x0C) >> 2];
-        self.obp1[0] = GREYS[(obp1 & 0x03)];
+        match self.fetcher.dots {
+            1 => self.fetch_tile(mmu, ly),
+            3 => self.fetcher.lo = mmu.vram(self.tile_bank(), self.tile_address(mmu)),
+            5 => self.fetcher.hi = mmu.vram(self.tile_bank(), self.tile_address(mmu) + 1),
+            _ => {},
+        }
+        self.fetcher.dots += 1;
+    }
 
+    fn fetch_tile(&mut self, mmu: &MMU, ly: u8) {
         let lcdc = mmu.rb(0xFF40);
-        let ly = mmu.rb(0xFF44);
+        let window_line = self.window_line;
+        let f = &mut self.fetcher;
+        let (map_start, x) = if f.window {
+            f.y = window_line;
+            let map = if lcdc & (1 << 6) == 0 { TILE_MAP0 } else { TILE_MAP1 };
+            (map, f.x)
+        } else {
+            // the coarse scroll is picked up again for every tile
+            f.y = ly.wrapping_add(mmu.rb(0xFF42));
+            let map = if lcdc & (1 << 3) == 0 { TILE_MAP0 } else { TILE_MAP1 };
+            (map, (mmu.rb(0xFF43) / 8).wrapping_add(f.x))
+        };
 
-        let sprite_size = if lcdc & (1 << 2) == 0 { 8 } else { 16 };
+        // tile map is 32x32 tiles in length
+        let address = map_start + (f.y / 8) as u16 * 32 + (x & 31) as u16;
+        f.tile = mmu.vram(0, address);
+        // on the CGB, VRAM bank 1 holds an attribute for every tile in the map:
+        // bit 7: priority over sprites
+        // bit 6: y-flip
+        // bit 5: x-flip
+        // bit 3: VRAM bank of the tile
+        // bit 0-2: palette
+        f.attr = if mmu.cgb_mode { mmu.vram(1, address) } else { 0 };
+    }
 
-        for i in 0..40 {
-            // each sprite has 4 bytes associated with it in the OAM:
-            // byte 0: Y coordinate (minus 16)
-            // byte 1: X coordinate (minus 8)
-            // byte 2: Tile number
-            // byte 3: Priority, y-flip, x-flip, palette
-            let sprite_data_address = OAM_START + (i as u16) * 4;
-            let sprite_y = mmu.rb(sprite_data_address).wrapping_sub(16);
-            let sprite_x = mmu.rb(sprite_data_address + 1).wrapping_sub(8);
-            let sprite_n = mmu.rb(sprite_data_address + 2);
-            let sprite_o = mmu.rb(sprite_data_address + 3);
-
-            let palette = if (sprite_o & (1 << 4)) == 0 {
-                self.obp0
-            } else {
-                self.obp1
-            };
+    fn tile_bank(&self) -> u8 {
+        (self.fetcher.attr >> 3) & 1
+    }
 
-            if sprite_y <= ly && (sprite_y + sprite_size) > ly {
-                let y_flip = (sprite_o & (1 << 6)) != 0;
-                let x_flip = (sprite_o & (1 << 5)) != 0;
+    fn tile_address(&self, mmu: &MMU) -> u16 {
+        let f = &self.fetcher;
+        // each tile is 16 bytes long (8x8 pixels of 2-bit color)
+        // if TILE_SET0 in use, the indicies are signed
+        let (start, offset) = if mmu.rb(0xFF40) & (1 << 4) == 0 {
+            (TILE_SET0, f.tile as i8 as u16)
+        } else {
+            (TILE_SET1, f.tile as u16)
+        };
+        let row = if f.attr & (1 << 6) != 0 { 7 - f.y % 8 } else { f.y % 8 };
+        start.wrapping_add(offset.wrapping_mul(16)) + row as u16 * 2
+    }
 
-                let tile_y = if y_flip {
-                    sprite_size - ly.wrapping_sub(sprite_y) - 1
-                } else {
-                    ly.wrapping_sub(sprite_y)
+    fn push_tile(&mut self) {
+        let f = &mut self.fetcher;
+        let x_flip = f.attr & (1 << 5) != 0;
+        for x in 0..8 {
+            let bit = if x_flip { x } else { 7 - x };
+            self.bg_fifo.push_back(BgPixel {
+                color: PPU::color_index(f.lo, f.hi, bit),
+                palette: f.attr & 0b111,
+                priority: f.attr & 0x80 != 0,
+                window: f.window,
+            });
+        }
+        f.x = f.x.wrapping_add(1);
+    }
+
+    // the first byte of a tile row holds the low bits
+    fn color_index(lo: u8, hi: u8, bit: u8) -> u8 {
+        (((hi >> bit) & 1) << 1) | ((lo >> bit) & 1)
+    }
+
+    fn fetch_obj(&mut self, mmu: &MMU, ly: u8, i: usize) {
+        self.sprites[i].fetched = true;
+        let sprite = self.sprites[i];
+        let cgb = mmu.cgb_mode;
+        let lcdc = mmu.rb(0xFF40);
+        let sprite_size = if lcdc & (1 << 2) == 0 { 8 } else { 16 };
+        // tall sprites ignore the low bit of the tile number
+        let tile = if sprite_size == 16 { sprite.tile & 0xFE } else { sprite.tile };
+
+        let y = (ly as u16 + 16 - sprite.y as u16) as u8;
+        let y = if sprite.attr & (1 << 6) != 0 { sprite_size - 1 - y } else { y };
+        let bank = if cgb { (sprite.attr >> 3) & 1 } else { 0 };
+        let address = TILE_SET1 + tile as u16 * 16 + y as u16 * 2;
+        let lo = mmu.vram(bank, address);
+        let hi = mmu.vram(bank, address + 1);
+
+        let palette = if cgb { sprite.attr & 0b111 } else { (sprite.attr >> 4) & 1 };
+        let x_flip = sprite.attr & (1 << 5) != 0;
+        // sprites hanging off the left edge lose their first pixels
+        let skip = 8u8.saturating_sub(sprite.x);
+
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjPixel::default());
+        }
+        for x in skip..8 {
+            let bit = if x_flip { x } else { 7 - x };
+            let color = PPU::color_index(lo, hi, bit);
+            let slot = &mut self.obj_fifo[(x - skip) as usize];
+            // a sprite already in the FIFO wins, except in CGB mode where
+            // the one earlier in OAM does
+            if color != 0 && (slot.color == 0 || (cgb && sprite.index < slot.index)) {
+                *slot = ObjPixel {
+                    color,
+                    palette,
+                    behind_bg: sprite.attr & 0x80 != 0,
+                    index: sprite.index,
                 };
+            }
+        }
+    }
 
-                let tile_idx = TILE_SET1 + (sprite_n as u16) * 16;
-                let tile_row = (tile_y as u16) * 2;
-                let index = tile_idx + tile_row;
-                let byte1 = mmu.rb(index);
-                let byte2 = mmu.rb(index + 1);
-
-                for x in 0..8 {
-                    let bit_idx = if x_flip { x } else { 7 - x };
-                    let mask = 1 << bit_idx;
-                    let bit1 = byte1 & mask != 0;
-                    let bit2 = byte2 & mask != 0;
-                    let pair = ((bit1 as u8) << 1) | bit2 as u8;
-
-                    if sprite_x.wrapping_add(x) < (SCREEN_W as u8) && pair != 0b00 {
-                        let col = sprite_x.wrapping_add(x) as usize;
-                        let sprite_has_priority = sprite_o & 0x80 == 0;
-                        let bg_color0 = self.data[ly as usize][col] == LIGHTEST;
-
-                        if sprite_has_priority || bg_color0 {
-                            let color = palette[pair as usize];
-                            self.data[ly as usize][col] = color;
-                        }
-                    }
-                }
+    fn push_pixel(&mut self, mmu: &MMU, ly: u8) {
+        let bg = match self.bg_fifo.pop_front() {
+            Some(pixel) => pixel,
+            None => return,
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
+        let obj = self.obj_fifo.pop_front().unwrap_or_default();
+
+        let cgb = mmu.cgb_mode;
+        let lcdc = mmu.rb(0xFF40);
+        // outside CGB mode, LCDC bit 0 blanks the background and window.
+        // in CGB mode it only takes away their priority over sprites
+        let bg_enabled = lcdc & 1 != 0;
+        let bg_color = if cgb || bg_enabled { bg.color } else { 0 };
+
+        let behind_bg = obj.behind_bg || bg.priority;
+        let show_obj = obj.color != 0 && lcdc & 0b10 != 0
+            && (!behind_bg || bg_color == 0 || (cgb && !bg_enabled));
+
+        let raw = if show_obj {
+            let register = mmu.rb(0xFF48 + obj.palette as u16);
+            RawPixel {
+                color: obj.color,
+                shade: if cgb { obj.color } else { PPU::shade(register, obj.color) },
+                layer: Layer::Object,
+                palette: obj.palette,
             }
+        } else {
+            let register = if bg_enabled { mmu.rb(0xFF47) } else { 0 };
+            let window = bg.window && (cgb || bg_enabled);
+            RawPixel {
+                color: bg_color,
+                shade: if cgb { bg_color } else { PPU::shade(register, bg_color) },
+                layer: if window { Layer::Window } else { Layer::Background },
+                palette: bg.palette,
+            }
+        };
+        let color = match (cgb, raw.layer) {
+            (true, Layer::Object) => mmu.obj_palettes.color(raw.palette, raw.color),
+            (true, _) => mmu.bg_palettes.color(raw.palette, raw.color),
+            (false, _) => self.dmg_color(mmu, raw),
+        };
+        if !self.blank_frame {
+            self.data[ly as usize][self.lx as usize] = color;
+            self.raw[ly as usize][self.lx as usize] = raw;
+        }
+        self.lx += 1;
+    }
+
+    fn shade(register: u8, index: u8) -> u8 {
+        (register >> (index * 2)) & 0b11
+    }
+
+    // DMG palettes are made of shades of grey, except on a CGB running a
+    // DMG cartridge, where they pick from the first palettes in palette RAM
+    fn dmg_color(&self, mmu: &MMU, pixel: RawPixel) -> u32 {
+        match (mmu.model, pixel.layer) {
+            (Model::DMG, _) => self.palette.colorize(pixel),
+            (Model::CGB, Layer::Object) => mmu.obj_palettes.color(pixel.palette, pixel.shade),
+            (Model::CGB, _) => mmu.bg_palettes.color(0, pixel.shade),
         }
     }
 }
@@ -0,0 +1,426 @@
+use crate::ppu::{SCREEN_W, SCREEN_H};
+use crate::apu::CPU_CLOCK;
+use crate::gameboy::CYCLES_PER_FRAME;
+use crate::screenshot::capture_name;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::sync::mpsc::{self, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+pub type Frame = [[u32; SCREEN_W]; SCREEN_H];
+
+// how far the encoder thread can fall behind before emulation waits for it
+const MAX_QUEUED_FRAMES: usize = 120;
+// viewers treat anything shorter than 2 centiseconds as 10, so GIFs only
+// get every other frame
+const GIF_FRAME_STEP: u64 = 2;
+const GIF_MAX_COLORS: usize = 256;
+const LZW_MAX_CODE: u16 = 4095;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RecordingFormat {
+    Gif,
+    // YUV 4:4:4, which ffmpeg and most players read as is
+    Y4m,
+    // headerless 24 bit RGB, for `-f rawvideo -pixel_format rgb24
+    // -video_size 160x144 -framerate 59.7275`
+    Raw,
+}
+
+impl RecordingFormat {
+    pub const ALL: [RecordingFormat; 3] = [RecordingFormat::Gif, RecordingFormat::Y4m, RecordingFormat::Raw];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RecordingFormat::Gif => "gif",
+            RecordingFormat::Y4m => "y4m",
+            RecordingFormat::Raw => "raw",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<RecordingFormat> {
+        RecordingFormat::ALL.iter().copied().find(|f| f.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Gif => "gif",
+            RecordingFormat::Y4m => "y4m",
+            RecordingFormat::Raw => "rgb",
+        }
+    }
+
+    pub fn from_path(path: &str) -> Option<RecordingFormat> {
+        let extension = path.rsplit_once('.')?.1;
+        RecordingFormat::ALL.iter().copied().find(|f| f.extension().eq_ignore_ascii_case(extension))
+    }
+
+    // none of the formats carry audio, but the lossless ones are meant to be
+    // muxed with it later, so they get a .wav of the same name written alongside
+    pub fn wants_wav(self) -> bool {
+        self != RecordingFormat::Gif
+    }
+
+    pub fn encoder<W: Write + Send + 'static>(self, out: W) -> Box<dyn FrameEncoder + Send> {
+        match self {
+            RecordingFormat::Gif => Box::new(GifEncoder::new(out)),
+            RecordingFormat::Y4m => Box::new(Y4mEncoder::new(out)),
+            RecordingFormat::Raw => Box::new(RawEncoder::new(out)),
+        }
+    }
+}
+
+// named like screenshots, next to which they go
+pub fn recording_name(title: &str, time: SystemTime, format: RecordingFormat) -> String {
+    capture_name(title, "recording", time, format.extension())
+}
+
+// gets every frame the emulator shows, one per CYCLES_PER_FRAME
+pub trait FrameEncoder {
+    fn encode(&mut self, frame: &Frame) -> io::Result<()>;
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl<E: FrameEncoder + ?Sized> FrameEncoder for Box<E> {
+    fn encode(&mut self, frame: &Frame) -> io::Result<()> {
+        (**self).encode(frame)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+// runs the encoder on its own thread so a slow one doesn't hold up
+// emulation. the first error stops the recording and is reported by finish
+pub struct Recorder<E: FrameEncoder + Send + 'static = Box<dyn FrameEncoder + Send>> {
+    sender: Option<SyncSender<Box<Frame>>>,
+    thread: Option<JoinHandle<(E, io::Result<()>)>>,
+}
+
+impl Recorder {
+    pub fn create(path: &str, format: RecordingFormat) -> io::Result<Recorder> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Recorder::start(format.encoder(file)))
+    }
+}
+
+impl<E: FrameEncoder + Send + 'static> Recorder<E> {
+    pub fn start(mut encoder: E) -> Recorder<E> {
+        let (sender, receiver) = mpsc::sync_channel::<Box<Frame>>(MAX_QUEUED_FRAMES);
+        let thread = thread::spawn(move || {
+            let mut result = Ok(());
+            for frame in receiver.iter() {
+                result = encoder.encode(&frame);
+                if result.is_err() {
+                    break;
+                }
+            }
+            if result.is_ok() {
+                result = encoder.finish();
+            }
+            (encoder, result)
+        });
+        Recorder {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn write_frame(&mut self, frame: &Frame) {
+        let sent = match self.sender {
+            Some(ref sender) => sender.send(Box::new(*frame)).is_ok(),
+            None => return,
+        };
+        // the encoder gave up, finish has the reason
+        if !sent {
+            self.sender = None;
+        }
+    }
+
+    // waits for the queued frames to be encoded
+    pub fn finish(mut self) -> io::Result<E> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<E> {
+        self.sender = None;
+        let thread = self.thread.take().unwrap();
+        match thread.join() {
+            Ok((encoder, result)) => result.map(|_| encoder),
+            Err(_) => Err(io::Error::other("recording thread panicked")),
+        }
+    }
+}
+
+impl<E: FrameEncoder + Send + 'static> Drop for Recorder<E> {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            let _ = self.stop();
+        }
+    }
+}
+
+// the time at the start of a frame, rounded to the centisecond
+fn centiseconds(frame: u64) -> u64 {
+    let clock = CPU_CLOCK as u64;
+    (frame * CYCLES_PER_FRAME as u64 * 4 * 100 + clock / 2) / clock
+}
+
+// an animated GIF that loops forever. frames that don't change are merged
+// into the one before, which keeps still screens nearly free
+pub struct GifEncoder<W: Write> {
+    pub out: W,
+    started: bool,
+    frames: u64,
+    // the frame waiting for its delay, and the frame it was shown on
+    pending: Option<(Box<Frame>, u64)>,
+}
+
+impl<W: Write> GifEncoder<W> {
+    pub fn new(out: W) -> GifEncoder<W> {
+        GifEncoder {
+            out,
+            started: false,
+            frames: 0,
+            pending: None,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        self.out.write_all(b"GIF89a")?;
+        self.out.write_all(&(SCREEN_W as u16).to_le_bytes())?;
+        self.out.write_all(&(SCREEN_H as u16).to_le_bytes())?;
+        self.out.write_all(&[0, 0, 0])?; // no global colour table
+        // loop forever
+        self.out.write_all(&[0x21, 0xFF, 11])?;
+        self.out.write_all(b"NETSCAPE2.0")?;
+        self.out.write_all(&[3, 1, 0, 0, 0])
+    }
+
+    fn flush_pending(&mut self, end: u64) -> io::Result<()> {
+        let (frame, start) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        let delay = (centiseconds(end) - centiseconds(start)).min(u16::MAX as u64) as u16;
+
+        let (colors, indices) = GifEncoder::<W>::index(&frame);
+        let bits = (usize::BITS - (colors.len() - 1).leading_zeros()).max(1);
+
+        // graphic control: leave the frame in place, no transparency
+        self.out.write_all(&[0x21, 0xF9, 4, 0x04])?;
+        self.out.write_all(&delay.to_le_bytes())?;
+        self.out.write_all(&[0, 0])?;
+
+        self.out.write_all(&[0x2C, 0, 0, 0, 0])?;
+        self.out.write_all(&(SCREEN_W as u16).to_le_bytes())?;
+        self.out.write_all(&(SCREEN_H as u16).to_le_bytes())?;
+        self.out.write_all(&[0x80 | (bits - 1) as u8])?;
+        let mut table = vec![0; 3 << bits];
+        for (i, color) in colors.iter().enumerate() {
+            table[i * 3..i * 3 + 3].copy_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, *color as u8]);
+        }
+        self.out.write_all(&table)?;
+
+        let min_code_size = bits.max(2) as u8;
+        self.out.write_all(&[min_code_size])?;
+        for block in lzw_encode(&indices, min_code_size).chunks(255) {
+            self.out.write_all(&[block.len() as u8])?;
+            self.out.write_all(block)?;
+        }
+        self.out.write_all(&[0])
+    }
+
+    // a frame's colours and each pixel's index into them. the odd frame
+    // with more than a GIF can hold, from palettes changed mid-frame,
+    // falls back to 3 bits of red and green and 2 of blue
+    fn index(frame: &Frame) -> (Vec<u32>, Vec<u8>) {
+        let mut colors = Vec::new();
+        let mut lookup = HashMap::new();
+        let mut indices = Vec::with_capacity(SCREEN_W * SCREEN_H);
+        for pixel in frame.iter().flat_map(|row| row.iter()) {
+            let color = pixel & 0xFF_FFFF;
+            let index = *lookup.entry(color).or_insert_with(|| {
+                colors.push(color);
+                colors.len() - 1
+            });
+            if colors.len() > GIF_MAX_COLORS {
+                break;
+            }
+            indices.push(index as u8);
+        }
+        if colors.len() <= GIF_MAX_COLORS {
+            return (colors, indices);
+        }
+
+        let colors = (0..GIF_MAX_COLORS as u32).map(|i| {
+            let r = (i >> 5) * 255 / 7;
+            let g = (i >> 2 & 7) * 255 / 7;
+            let b = (i & 3) * 255 / 3;
+            r << 16 | g << 8 | b
+        }).collect();
+        let indices = frame.iter().flat_map(|row| row.iter())
+            .map(|p| ((p >> 16 & 0xE0) | (p >> 11 & 0x1C) | (p >> 6 & 0x03)) as u8)
+            .collect();
+        (colors, indices)
+    }
+}
+
+impl<W: Write> FrameEncoder for GifEncoder<W> {
+    fn encode(&mut self, frame: &Frame) -> io::Result<()> {
+        if !self.started {
+            self.write_header()?;
+            self.started = true;
+        }
+        if self.frames.is_multiple_of(GIF_FRAME_STEP) {
+            let unchanged = matches!(self.pending, Some((ref pending, _)) if **pending == *frame);
+            if !unchanged {
+                self.flush_pending(self.frames)?;
+                self.pending = Some((Box::new(*frame), self.frames));
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.write_header()?;
+            self.started = true;
+        }
+        self.flush_pending(self.frames)?;
+        self.out.write_all(&[0x3B])?;
+        self.out.flush()
+    }
+}
+
+// variable length codes packed from the lowest bit up, starting over
+// once all 4096 codes are taken
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut out = Vec::new();
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    let mut emit = |code: u16, size: u8, out: &mut Vec<u8>| {
+        bits |= (code as u32) << bit_count;
+        bit_count += size;
+        while bit_count >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            bit_count -= 8;
+        }
+    };
+
+    let mut codes: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut size = min_code_size + 1;
+    let mut last = end;
+    emit(clear, size, &mut out);
+
+    let mut iter = indices.iter();
+    if let Some(&first) = iter.next() {
+        let mut prefix = first as u16;
+        for &index in iter {
+            if let Some(&code) = codes.get(&(prefix, index)) {
+                prefix = code;
+                continue;
+            }
+            emit(prefix, size, &mut out);
+            last += 1;
+            codes.insert((prefix, index), last);
+            if last >= 1 << size {
+                size += 1;
+            }
+            if last == LZW_MAX_CODE {
+                emit(clear, size, &mut out);
+                codes.clear();
+                size = min_code_size + 1;
+                last = end;
+            }
+            prefix = index as u16;
+        }
+        emit(prefix, size, &mut out);
+    }
+    emit(end, size, &mut out);
+    if bit_count > 0 {
+        out.push(bits as u8);
+    }
+    out
+}
+
+// the stream header gives the exact rate, 4194304 / 70224 frames a second
+pub struct Y4mEncoder<W: Write> {
+    pub out: W,
+    started: bool,
+}
+
+impl<W: Write> Y4mEncoder<W> {
+    pub fn new(out: W) -> Y4mEncoder<W> {
+        Y4mEncoder {
+            out,
+            started: false,
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        writeln!(self.out, "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+            SCREEN_W, SCREEN_H, CPU_CLOCK, CYCLES_PER_FRAME as u64 * 4)
+    }
+}
+
+impl<W: Write> FrameEncoder for Y4mEncoder<W> {
+    fn encode(&mut self, frame: &Frame) -> io::Result<()> {
+        if !self.started {
+            self.write_header()?;
+            self.started = true;
+        }
+        // BT.601, studio range
+        let pixels = SCREEN_W * SCREEN_H;
+        let mut planes = vec![0; pixels * 3];
+        for (i, pixel) in frame.iter().flat_map(|row| row.iter()).enumerate() {
+            let r = (pixel >> 16 & 0xFF) as i32;
+            let g = (pixel >> 8 & 0xFF) as i32;
+            let b = (pixel & 0xFF) as i32;
+            planes[i] = (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8;
+            planes[pixels + i] = (((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128) as u8;
+            planes[pixels * 2 + i] = (((112 * r - 94 * g - 18 * b + 128) >> 8) + 128) as u8;
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&planes)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.started {
+            self.write_header()?;
+            self.started = true;
+        }
+        self.out.flush()
+    }
+}
+
+pub struct RawEncoder<W: Write> {
+    pub out: W,
+}
+
+impl<W: Write> RawEncoder<W> {
+    pub fn new(out: W) -> RawEncoder<W> {
+        RawEncoder { out }
+    }
+}
+
+impl<W: Write> FrameEncoder for RawEncoder<W> {
+    fn encode(&mut self, frame: &Frame) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(SCREEN_W * SCREEN_H * 3);
+        for pixel in frame.iter().flat_map(|row| row.iter()) {
+            bytes.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+        }
+        self.out.write_all(&bytes)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+
 #[derive(Debug, Copy, Clone)]
 pub struct Registers {
     pub a: u8,
@@ -97,4 +99,26 @@ impl Registers {
             }
         }
     }
-}
\ No newline at end of file
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&[self.a, self.f, self.b, self.c, self.d, self.e, self.h, self.l]);
+        w.write_u16(self.pc);
+        w.write_u16(self.sp);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        let mut regs = [0; 8];
+        r.read_bytes(&mut regs)?;
+        self.a = regs[0];
+        self.f = regs[1] & 0xF0;
+        self.b = regs[2];
+        self.c = regs[3];
+        self.d = regs[4];
+        self.e = regs[5];
+        self.h = regs[6];
+        self.l = regs[7];
+        self.pc = r.read_u16()?;
+        self.sp = r.read_u16()?;
+        Ok(())
+    }
+}
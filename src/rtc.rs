@@ -0,0 +1,227 @@
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// the RTC runs off its own 32768Hz crystal; in emulated time
+// that is one second for every 2^20 M-cycles
+const CYCLES_PER_SECOND: u32 = 1 << 20;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// seconds, minutes, hours, day counter low, day counter high
+const REG_MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+const S: usize = 0;
+const M: usize = 1;
+const H: usize = 2;
+const DL: usize = 3;
+const DH: usize = 4;
+
+const DH_DAY_HI: u8 = 0b0000_0001;
+const DH_HALT: u8   = 0b0100_0000;
+const DH_CARRY: u8  = 0b1000_0000;
+
+// the footer VBA-M, BGB, mGBA and most other emulators append to the
+// RAM in .sav files: 5 live and 5 latched registers as u32s followed by
+// a 64 bit UNIX timestamp. some older ones only write 32 bits of it
+pub const RTC_FOOTER_SIZE: usize = 48;
+pub const RTC_FOOTER_SIZE_OLD: usize = 44;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RtcClock {
+    // follows the real time, even while the emulator isn't running
+    WallClock,
+    // only advances with emulated cycles, deterministic for testing
+    Emulated,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Rtc {
+    pub clock: RtcClock,
+    regs: [u8; 5],
+    latched: [u8; 5],
+    latch_armed: bool,
+    cycles: u32,
+    last_sync: u64,
+}
+
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Rtc {
+        Rtc {
+            clock,
+            regs: [0; 5],
+            latched: [0; 5],
+            latch_armed: false,
+            cycles: 0,
+            last_sync: unix_time(),
+        }
+    }
+
+    fn halted(&self) -> bool {
+        (self.regs[DH] & DH_HALT) != 0
+    }
+
+    pub fn tick(&mut self, m_clocks: u8) {
+        if self.clock != RtcClock::Emulated || self.halted() {
+            return;
+        }
+
+        self.cycles += m_clocks as u32;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.tick_second();
+        }
+    }
+
+    // catches the registers up with the wall clock
+    pub fn sync(&mut self) {
+        if self.clock != RtcClock::WallClock {
+            return;
+        }
+
+        let now = unix_time();
+        if now > self.last_sync && !self.halted() {
+            self.advance(now - self.last_sync);
+        }
+        self.last_sync = now;
+    }
+
+    // latching happens on a write of 0x00 followed by 0x01
+    pub fn latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.sync();
+            self.latched = self.regs;
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    // reg is the RAM bank number minus 8
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched[reg as usize]
+    }
+
+    pub fn write(&mut self, reg: u8, value: u8) {
+        let reg = reg as usize;
+        self.sync();
+        self.regs[reg] = value & REG_MASKS[reg];
+        // writing the seconds resets the sub-second divider
+        if reg == S {
+            self.cycles = 0;
+        }
+    }
+
+    // registers that were written with out of range values keep counting
+    // up to the limit of their bits before wrapping without a carry
+    fn tick_second(&mut self) {
+        self.regs[S] = (self.regs[S] + 1) & REG_MASKS[S];
+        if self.regs[S] != 60 {
+            return;
+        }
+        self.regs[S] = 0;
+
+        self.regs[M] = (self.regs[M] + 1) & REG_MASKS[M];
+        if self.regs[M] != 60 {
+            return;
+        }
+        self.regs[M] = 0;
+
+        self.regs[H] = (self.regs[H] + 1) & REG_MASKS[H];
+        if self.regs[H] != 24 {
+            return;
+        }
+        self.regs[H] = 0;
+
+        let days = self.days() + 1;
+        if days == 512 {
+            self.regs[DH] |= DH_CARRY;
+        }
+        self.set_days(days % 512);
+    }
+
+    fn days(&self) -> u64 {
+        (((self.regs[DH] & DH_DAY_HI) as u64) << 8) | self.regs[DL] as u64
+    }
+
+    fn set_days(&mut self, days: u64) {
+        self.regs[DL] = days as u8;
+        self.regs[DH] = (self.regs[DH] & !DH_DAY_HI) | ((days >> 8) as u8 & DH_DAY_HI);
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // step through the odd cases one second at a time
+        while seconds > 0 && (self.regs[S] >= 60 || self.regs[M] >= 60 || self.regs[H] >= 24) {
+            self.tick_second();
+            seconds -= 1;
+        }
+
+        let total = self.days() * SECONDS_PER_DAY
+            + self.regs[H] as u64 * 3600
+            + self.regs[M] as u64 * 60
+            + self.regs[S] as u64
+            + seconds;
+        let days = total / SECONDS_PER_DAY;
+        if days >= 512 {
+            self.regs[DH] |= DH_CARRY;
+        }
+        self.set_days(days % 512);
+        self.regs[H] = ((total % SECONDS_PER_DAY) / 3600) as u8;
+        self.regs[M] = ((total % 3600) / 60) as u8;
+        self.regs[S] = (total % 60) as u8;
+    }
+
+    pub fn footer(&self) -> Vec<u8> {
+        let mut rtc = *self;
+        rtc.sync();
+
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for &reg in rtc.regs.iter().chain(rtc.latched.iter()) {
+            footer.extend_from_slice(&(reg as u32).to_le_bytes());
+        }
+        // emulated time doesn't follow last_sync, but whoever loads the save
+        // next might, and should only count the time since it was written
+        let timestamp = match rtc.clock {
+            RtcClock::WallClock => rtc.last_sync,
+            RtcClock::Emulated => unix_time(),
+        };
+        footer.extend_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    // in emulated time the clock doesn't move while the emulator is closed
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        if footer.len() != RTC_FOOTER_SIZE && footer.len() != RTC_FOOTER_SIZE_OLD {
+            return;
+        }
+
+        for i in 0..5 {
+            self.regs[i] = footer[i * 4] & REG_MASKS[i];
+            self.latched[i] = footer[20 + i * 4] & REG_MASKS[i];
+        }
+        let mut timestamp = [0; 8];
+        timestamp[..footer.len() - 40].copy_from_slice(&footer[40..]);
+        self.last_sync = u64::from_le_bytes(timestamp);
+        self.cycles = 0;
+        self.sync();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.regs);
+        w.write_bytes(&self.latched);
+        w.write_bool(self.latch_armed);
+        w.write_u32(self.cycles);
+        w.write_u64(self.last_sync);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        r.read_bytes(&mut self.regs)?;
+        r.read_bytes(&mut self.latched)?;
+        self.latch_armed = r.read_bool()?;
+        self.cycles = r.read_u32()?;
+        self.last_sync = r.read_u64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+use crate::ppu::{SCREEN_W, SCREEN_H};
+use std::fs::File;
+use std::io::{self, Write, BufWriter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+// the most a stored deflate block can hold
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+// writes the frame as an 8 bit RGB PNG, every pixel blown up to a
+// scale x scale square. the image data is stored without compression,
+// which keeps this small and is plenty for a 160x144 screen
+pub fn write_png<W: Write>(out: &mut W, frame: &[[u32; SCREEN_W]; SCREEN_H], scale: usize) -> io::Result<()> {
+    let scale = scale.max(1);
+    let width = SCREEN_W * scale;
+    let height = SCREEN_H * scale;
+
+    let mut pixels = Vec::with_capacity((width * 3 + 1) * height);
+    for row in frame.iter() {
+        let start = pixels.len();
+        pixels.push(0); // no filter
+        for pixel in row.iter() {
+            for _ in 0..scale {
+                pixels.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, *pixel as u8]);
+            }
+        }
+        let end = pixels.len();
+        for _ in 1..scale {
+            pixels.extend_from_within(start..end);
+        }
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits, RGB, deflate, no filter, no interlace
+
+    out.write_all(&PNG_SIGNATURE)?;
+    write_chunk(out, b"IHDR", &ihdr)?;
+    write_chunk(out, b"IDAT", &zlib_stored(&pixels))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+pub fn save_png(path: &str, frame: &[[u32; SCREEN_W]; SCREEN_H], scale: usize) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_png(&mut file, frame, scale)?;
+    file.flush()
+}
+
+pub fn screenshot_name(title: &str, time: SystemTime) -> String {
+    capture_name(title, "screenshot", time, "png")
+}
+
+// <title>-<YYYYMMDD>-<HHMMSS>.<extension> in UTC, with anything that
+// doesn't belong in a file name replaced, and fallback for untitled games
+pub fn capture_name(title: &str, fallback: &str, time: SystemTime, extension: &str) -> String {
+    let mut name: String = title.trim().chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.is_empty() {
+        name = fallback.to_string();
+    }
+
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (year, month, day) = civil_date((secs / 86400) as i64);
+    let secs = secs % 86400;
+    format!("{}-{:04}{:02}{:02}-{:02}{:02}{:02}.{}", name, year, month, day,
+        secs / 3600, secs / 60 % 60, secs % 60, extension)
+}
+
+// days since 1970-01-01 to a year, month and day
+fn civil_date(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(&[&kind[..], data]);
+    out.write_all(&crc.to_be_bytes())
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len() / STORED_BLOCK_SIZE + 1;
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 6);
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(STORED_BLOCK_SIZE).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,51 @@
+use crate::interrupts::*;
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+pub struct Serial {
+    intr: Rc<RefCell<IntReq>>,
+    pub sb: u8,
+    pub sc: u8,
+    pub output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new(intr: Rc<RefCell<IntReq>>) -> Serial {
+        Serial {
+            intr,
+            sb: 0,
+            sc: 0,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn control(&self) -> u8 {
+        0b0111_1110 | self.sc
+    }
+
+    pub fn set_control(&mut self, value: u8) {
+        self.sc = value & 0b1000_0001;
+
+        // no link cable is ever attached, so a transfer on the internal
+        // clock finishes right away and shifts in 1s. the outgoing bytes
+        // are kept around since test ROMs print their results this way
+        if self.sc == 0b1000_0001 {
+            self.output.push(self.sb);
+            self.sb = 0xFF;
+            self.sc &= 0b0111_1111;
+            self.intr.borrow_mut().set_flag(IntFlag::Serial);
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u8(self.sb);
+        w.write_u8(self.sc);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.sb = r.read_u8()?;
+        self.sc = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,148 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::apu::{APU, CPU_CLOCK};
+use crate::savestate::{StateWriter, StateReader};
+
+fn powered() -> APU {
+    let mut apu = APU::new();
+    apu.write(0xFF26, 0x80);
+    apu
+}
+
+// one frame sequencer step
+fn step(apu: &mut APU) {
+    for _ in 0..2048 {
+        apu.tick();
+    }
+}
+
+#[test]
+fn register_read_masks() {
+    let mut apu = powered();
+    apu.write(0xFF11, 0x00);
+    assert_eq!(apu.read(0xFF10), 0x80);
+    assert_eq!(apu.read(0xFF11), 0x3F);
+    assert_eq!(apu.read(0xFF13), 0xFF);
+    assert_eq!(apu.read(0xFF15), 0xFF);
+    assert_eq!(apu.read(0xFF1A), 0x7F);
+    assert_eq!(apu.read(0xFF26), 0xF0);
+    assert_eq!(apu.read(0xFF2F), 0xFF);
+}
+
+#[test]
+fn power_off_clears_registers() {
+    let mut apu = powered();
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF30, 0x12);
+    apu.write(0xFF26, 0x00);
+    assert_eq!(apu.read(0xFF26), 0x70);
+    assert_eq!(apu.read(0xFF12), 0x00);
+    assert_eq!(apu.read(0xFF24), 0x00);
+    assert_eq!(apu.read(0xFF30), 0x12);
+
+    // ignored while powered off
+    apu.write(0xFF12, 0xF0);
+    assert_eq!(apu.read(0xFF12), 0x00);
+}
+
+#[test]
+fn trigger_and_dac() {
+    let mut apu = powered();
+    // DAC off, triggering doesn't enable the channel
+    apu.write(0xFF17, 0x00);
+    apu.write(0xFF19, 0x80);
+    assert_eq!(apu.read(0xFF26) & 0b10, 0);
+
+    apu.write(0xFF17, 0xF0);
+    apu.write(0xFF19, 0x80);
+    assert_eq!(apu.read(0xFF26) & 0b10, 0b10);
+
+    // turning the DAC off disables it again
+    apu.write(0xFF17, 0x00);
+    assert_eq!(apu.read(0xFF26) & 0b10, 0);
+}
+
+#[test]
+fn length_counter() {
+    let mut apu = powered();
+    apu.write(0xFF21, 0xF0);
+    apu.write(0xFF20, 62);
+    apu.write(0xFF23, 0xC0);
+    assert_eq!(apu.read(0xFF26) & 0b1000, 0b1000);
+
+    // two length clocks take eight frame sequencer steps at most
+    for _ in 0..8 {
+        step(&mut apu);
+    }
+    assert_eq!(apu.read(0xFF26) & 0b1000, 0);
+}
+
+#[test]
+fn sweep_overflow() {
+    let mut apu = powered();
+    apu.write(0xFF12, 0xF0);
+    // period 1, adding, shift 1
+    apu.write(0xFF10, 0x11);
+    apu.write(0xFF13, 0x00);
+    apu.write(0xFF14, 0x85);
+    assert_eq!(apu.read(0xFF26) & 1, 1);
+
+    // 0x500 becomes 0x780, and the check after it overflows
+    for _ in 0..8 {
+        step(&mut apu);
+    }
+    assert_eq!(apu.read(0xFF26) & 1, 0);
+}
+
+#[test]
+fn samples() {
+    let mut apu = powered();
+    apu.set_sample_rate(Some(48000));
+    apu.write(0xFF24, 0x77);
+    apu.write(0xFF25, 0x10);
+    apu.write(0xFF12, 0xF0);
+    apu.write(0xFF11, 0x80);
+    apu.write(0xFF13, 0x00);
+    apu.write(0xFF14, 0x87);
+
+    // one second of M-cycles
+    for _ in 0..CPU_CLOCK / 4 {
+        apu.tick();
+    }
+    let samples = apu.take_samples();
+    assert_eq!(samples.len(), 48000 * 2);
+    // only the left output is panned to channel 1
+    assert!(samples.iter().step_by(2).any(|&s| s.abs() > 0.1));
+    assert!(samples.iter().skip(1).step_by(2).all(|&s| s.abs() < 0.01));
+    assert!(apu.take_samples().is_empty());
+}
+
+#[test]
+fn no_samples_without_rate() {
+    let mut apu = powered();
+    apu.tick_n(100);
+    assert!(apu.take_samples().is_empty());
+}
+
+#[test]
+fn save_state() {
+    let mut apu = powered();
+    apu.write(0xFF25, 0xFF);
+    apu.write(0xFF30, 0xAB);
+    apu.write(0xFF21, 0xF0);
+    apu.write(0xFF23, 0x80);
+    apu.tick_n(200);
+
+    let mut w = StateWriter::new();
+    apu.save_state(&mut w);
+    let data = w.into_bytes();
+
+    let mut loaded = APU::new();
+    let mut r = StateReader::new(&data);
+    loaded.load_state(&mut r).unwrap();
+    assert!(r.is_empty());
+    for address in 0xFF10..=0xFF3F {
+        assert_eq!(loaded.read(address), apu.read(address), "{:#06X}", address);
+    }
+}
@@ -0,0 +1,61 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::audio::{AudioSink, NullSink, WavSink, RateControl};
+use crate::gameboy::GameBoy;
+use super::gameboy::rom;
+use std::io::Cursor;
+
+#[test]
+fn wav_header() {
+    let mut sink = WavSink::new(Cursor::new(Vec::new()), 44100).unwrap();
+    sink.write(&[0.0, 1.0, -1.0, 0.5]);
+    let data = sink.finish().unwrap().into_inner();
+
+    assert_eq!(data.len(), 44 + 8);
+    assert_eq!(&data[0..4], b"RIFF");
+    assert_eq!(&data[4..8], &44u32.to_le_bytes());
+    assert_eq!(&data[8..16], b"WAVEfmt ");
+    assert_eq!(&data[22..24], &2u16.to_le_bytes());
+    assert_eq!(&data[24..28], &44100u32.to_le_bytes());
+    assert_eq!(&data[36..40], b"data");
+    assert_eq!(&data[40..44], &8u32.to_le_bytes());
+    assert_eq!(&data[44..46], &0i16.to_le_bytes());
+    assert_eq!(&data[46..48], &i16::MAX.to_le_bytes());
+    assert_eq!(&data[48..50], &(-i16::MAX).to_le_bytes());
+}
+
+#[test]
+fn rate_control() {
+    let control = RateControl::new(1000);
+    assert!((control.ratio(1000) - 1.0).abs() < 1e-9);
+    assert!((control.ratio(0) - 1.005).abs() < 1e-9);
+    assert!((control.ratio(5000) - 0.995).abs() < 1e-9);
+    assert!(control.ratio(500) > 1.0);
+    assert!(control.ratio(1500) < 1.0);
+}
+
+#[test]
+fn drain_audio() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.set_sample_rate(Some(48000));
+    // the sample rate survives power cycles and loading states
+    gameboy.load_rom(rom()).unwrap();
+    let state = gameboy.save_state();
+    gameboy.load_state(&state).unwrap();
+
+    let mut sink = WavSink::new(Cursor::new(Vec::new()), 48000).unwrap();
+    gameboy.run_frame();
+    gameboy.drain_audio(&mut sink);
+    let data = sink.finish().unwrap().into_inner();
+    // a frame lasts about 1/60th of a second
+    assert_eq!((data.len() - 44) / 4, 803);
+
+    let mut null = NullSink::new(48000);
+    gameboy.drain_audio(&mut null);
+    assert_eq!(null.queued(), None);
+
+    // or taken to go to more than one sink, left and right interleaved
+    assert!(gameboy.take_samples().is_empty());
+    gameboy.run_frame();
+    assert!((803 * 2..=804 * 2).contains(&gameboy.take_samples().len()));
+}
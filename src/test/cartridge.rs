@@ -64,8 +64,7 @@ fn cartridge_mbc2() -> Cartridge {
 fn no_mbc_read() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge_0b();
-    let mmu = MMU::new(&mut cartridge, timer.clone());
+    let mmu = MMU::new(cartridge_0b(), timer.clone());
     let lower_1 = mmu.rb(0x27EB);
     let expected_lower_1 = 0x20;
     assert_eq!(lower_1, expected_lower_1);
@@ -84,12 +83,11 @@ fn no_mbc_read() {
 fn mbc1_bank_mode_on() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge_4b();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge_4b(), timer.clone());
     mmu.wb(0x3FFF, 0b0001_0010); // Bank 1 register
     mmu.wb(0x5FFF, 0b1111_0101); // Bank 2 register
     mmu.wb(0x7FFF, 0b1000_0001); // Mode register ON
-    let (lower, upper) = cartridge.rom_offsets;
+    let (lower, upper) = mmu.cartridge.rom_offsets;
     assert_eq!(0b0010_0000, lower / ROM_BANK_SIZE);
     assert_eq!(0b0011_0010, upper / ROM_BANK_SIZE);
 }
@@ -98,12 +96,11 @@ fn mbc1_bank_mode_on() {
 fn mbc1_bank_mode_off() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge_4b();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge_4b(), timer.clone());
     mmu.wb(0x3FFF, 0b0001_0010); // Bank 1 register
     mmu.wb(0x5FFF, 0b1111_0101); // Bank 2 register
     mmu.wb(0x7FFF, 0b1000_0000); // Mode register OFF
-    let (lower, upper) = cartridge.rom_offsets;
+    let (lower, upper) = mmu.cartridge.rom_offsets;
     assert_eq!(0b0000_0000, lower / ROM_BANK_SIZE);
     assert_eq!(0b0011_0010, upper / ROM_BANK_SIZE);
 }
@@ -112,13 +109,12 @@ fn mbc1_bank_mode_off() {
 fn mbc1_read_bank() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge_128b();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge_128b(), timer.clone());
     mmu.wb(0x3FFF, 0b0000_0100); // Bank 1 register
     mmu.wb(0x5FFF, 0b0000_0010); // Bank 2 register
     mmu.wb(0x7FFF, 0b1000_0000); // Mode register OFF
     let read_value = mmu.rb(0x72A7);
-    let (_, bank_number) = cartridge.rom_offsets;
+    let (_, bank_number) = mmu.cartridge.rom_offsets;
 
     let expected_read_value: u8 = 0x20;        // see 'cartridge_4b' function
     let expected_bank_number: u32 = 0b0100_0100;
@@ -130,8 +126,7 @@ fn mbc1_read_bank() {
 fn mbc2_read_ram() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge_mbc2();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge_mbc2(), timer.clone());
     mmu.wb(0x3EFF, 0b1010); // should enable ram
     mmu.wb(0xA000, 0x2F);
 
@@ -144,3 +139,35 @@ fn mbc2_read_ram() {
     let read_value2 = mmu.rb(0xA400);
     assert_eq!(expected_undefined, read_value2);
 }
+
+#[test]
+fn battery_save() {
+    let mut rom: Vec<u8> = vec![0; 32 * KILOBYTE as usize];
+    rom[0x147] = 0x03; // MBC1+RAM+BATTERY
+    rom[0x149] = 0x02;
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut mmu = MMU::new(Cartridge::new(rom.clone()).unwrap(), timer.clone());
+    assert!(mmu.cartridge.has_battery());
+    assert!(!mmu.cartridge.ram_dirty);
+
+    mmu.wb(0xA000, 0x12); // RAM still disabled
+    assert!(!mmu.cartridge.ram_dirty);
+    mmu.wb(0x0000, 0x0A);
+    mmu.wb(0xA000, 0x12);
+    mmu.wb(0xBFFF, 0x34);
+    assert!(mmu.cartridge.ram_dirty);
+
+    let sav = mmu.cartridge.battery_save();
+    assert_eq!(sav.len(), 8 * KILOBYTE as usize);
+    assert_eq!((sav[0x0000], sav[0x1FFF]), (0x12, 0x34));
+
+    let mut mmu = MMU::new(Cartridge::new(rom.clone()).unwrap(), timer.clone());
+    mmu.cartridge.load_battery_save(&sav);
+    mmu.wb(0x0000, 0x0A);
+    assert_eq!(mmu.rb(0xA000), 0x12);
+    assert_eq!(mmu.rb(0xBFFF), 0x34);
+
+    rom[0x147] = 0x02; // MBC1+RAM
+    assert!(!Cartridge::new(rom).unwrap().has_battery());
+}
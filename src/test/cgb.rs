@@ -0,0 +1,190 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::gameboy::{GameBoy, CGB_BOOT_SIZE};
+use crate::mmu::Model;
+use crate::ppu::rgb555;
+use super::ppu::render_line;
+use super::gameboy::{rom_with, gameboy_with};
+
+fn rom(cgb_flag: u8) -> Vec<u8> {
+    rom_with(b"TETRA", &[(0x143, cgb_flag)])
+}
+
+fn gameboy(cgb_flag: u8) -> GameBoy {
+    gameboy_with(rom(cgb_flag))
+}
+
+#[test]
+fn model_selection() {
+    assert_eq!(gameboy(0x00).model(), Model::DMG);
+
+    let cgb = gameboy(0xC0);
+    assert_eq!(cgb.model(), Model::CGB);
+    assert!(cgb.mmu.cgb_mode);
+    // the boot rom is skipped, leaving 0x11 in A to tell games they run on a CGB
+    assert_eq!(cgb.cpu.reg.a, 0x11);
+    assert_eq!(cgb.cpu.reg.pc, 0x0100);
+
+    // a CGB boot rom makes DMG cartridges run on a CGB too
+    let mut gameboy = GameBoy::new(vec![0; CGB_BOOT_SIZE]);
+    gameboy.load_rom(rom(0x00)).unwrap();
+    assert_eq!(gameboy.model(), Model::CGB);
+    assert_eq!(gameboy.cpu.reg.pc, 0x0000);
+
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.force_model(Some(Model::DMG));
+    gameboy.load_rom(rom(0x80)).unwrap();
+    assert_eq!(gameboy.model(), Model::DMG);
+    assert!(!gameboy.mmu.cgb_mode);
+}
+
+#[test]
+fn vram_banks() {
+    let mut gameboy = gameboy(0x80);
+    let mmu = &mut gameboy.mmu;
+    mmu.wb(0x8000, 0x11);
+    mmu.wb(0xFF4F, 0x01);
+    assert_eq!(mmu.rb(0xFF4F), 0xFF);
+    assert_eq!(mmu.rb(0x8000), 0x00);
+    mmu.wb(0x8000, 0x22);
+    assert_eq!(mmu.vram(0, 0x8000), 0x11);
+    assert_eq!(mmu.vram(1, 0x8000), 0x22);
+
+    // DMG cartridges only see bank 0
+    let mut gameboy = gameboy_dmg_on_cgb();
+    gameboy.mmu.wb(0xFF4F, 0x01);
+    assert_eq!(gameboy.mmu.rb(0xFF4F), 0xFF);
+    gameboy.mmu.wb(0x8000, 0x33);
+    assert_eq!(gameboy.mmu.vram(0, 0x8000), 0x33);
+}
+
+fn gameboy_dmg_on_cgb() -> GameBoy {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.force_model(Some(Model::CGB));
+    gameboy.load_rom(rom(0x00)).unwrap();
+    gameboy
+}
+
+#[test]
+fn wram_banks() {
+    let mut gameboy = gameboy(0x80);
+    let mmu = &mut gameboy.mmu;
+    for bank in 1..8 {
+        mmu.wb(0xFF70, bank);
+        mmu.wb(0xD000, bank * 0x10);
+    }
+    mmu.wb(0xC000, 0xAA);
+
+    // bank 0 selects bank 1
+    mmu.wb(0xFF70, 0);
+    assert_eq!(mmu.rb(0xFF70), 0xF8);
+    assert_eq!(mmu.rb(0xD000), 0x10);
+    mmu.wb(0xFF70, 5);
+    assert_eq!(mmu.rb(0xD000), 0x50);
+    assert_eq!(mmu.rb(0xF000), 0x50);
+    assert_eq!(mmu.rb(0xC000), 0xAA);
+}
+
+#[test]
+fn palette_ram() {
+    let mut gameboy = gameboy(0x80);
+    let mmu = &mut gameboy.mmu;
+    mmu.wb(0xFF68, 0x80 | 0x3E);
+    mmu.wb(0xFF69, 0x1F);
+    mmu.wb(0xFF69, 0x00);
+    mmu.wb(0xFF69, 0xE0);
+    assert_eq!(mmu.rb(0xFF68), 0xC1);
+    assert_eq!(mmu.bg_palettes.color(7, 3), rgb555(0x001F));
+    // the index wrapped around to the start
+    assert_eq!(mmu.bg_palettes.data[0], 0xE0);
+
+    // without auto increment the index stays put
+    mmu.wb(0xFF6A, 0x02);
+    mmu.wb(0xFF6B, 0x12);
+    mmu.wb(0xFF6B, 0x34);
+    assert_eq!(mmu.rb(0xFF6A), 0x42);
+    assert_eq!(mmu.rb(0xFF6B), 0x34);
+
+    assert_eq!(rgb555(0x7FFF), 0xFFFFFFFF);
+    assert_eq!(rgb555(0x001F), 0xFFFF0000);
+    assert_eq!(rgb555(0x03E0), 0xFF00FF00);
+    assert_eq!(rgb555(0x7C00), 0xFF0000FF);
+}
+
+#[test]
+fn speed_switch() {
+    let mut rom = rom(0x80);
+    // LD A,1; LDH (0x4D),A; STOP; NOP
+    rom[0x100..0x106].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.load_rom(rom).unwrap();
+
+    gameboy.step();
+    gameboy.step();
+    assert_eq!(gameboy.mmu.rb(0xFF4D), 0x7F);
+    gameboy.step();
+    assert_eq!(gameboy.mmu.rb(0xFF4D), 0xFE);
+    assert_eq!(gameboy.cpu.reg.pc, 0x0106);
+
+    // every other CPU M-cycle reaches the PPU and APU
+    let total: u32 = (0..10).map(|_| gameboy.step() as u32).sum();
+    assert_eq!(total, 5);
+}
+
+#[test]
+fn bg_attributes() {
+    let mut gameboy = gameboy(0x80);
+    let mmu = &mut gameboy.mmu;
+    // tile 1 in VRAM bank 1, a single pixel of colour 1 on its left edge
+    mmu.wb(0xFF4F, 1);
+    mmu.wb(0x8010, 0x80);
+    // tile map entry 0 uses tile 1 from bank 1, palette 2, flipped horizontally
+    mmu.wb(0x9800, 0b0010_1010);
+    mmu.wb(0xFF4F, 0);
+    mmu.wb(0x9800, 0x01);
+    mmu.wb(0xFF40, 0x91);
+
+    mmu.wb(0xFF68, 0x80 | (2 * 8 + 2));
+    mmu.wb(0xFF69, 0x1F);
+    mmu.wb(0xFF69, 0x00);
+
+    let ly = render_line(&mut gameboy.mmu, &mut gameboy.ppu);
+    assert_eq!(gameboy.ppu.data[ly][7], rgb555(0x001F));
+    assert_eq!(gameboy.ppu.data[ly][0], rgb555(0x7FFF));
+}
+
+#[test]
+fn compat_palettes() {
+    let mut gameboy = gameboy_dmg_on_cgb();
+    assert!(!gameboy.mmu.cgb_mode);
+    // BGP maps colour 0 to shade 3, which comes from BG palette 0
+    gameboy.mmu.wb(0xFF47, 0b11);
+    let ly = render_line(&mut gameboy.mmu, &mut gameboy.ppu);
+    assert_eq!(gameboy.ppu.data[ly][0], rgb555(0x0000));
+    gameboy.mmu.wb(0xFF47, 0b01);
+    let ly = render_line(&mut gameboy.mmu, &mut gameboy.ppu);
+    assert_eq!(gameboy.ppu.data[ly][0], rgb555(0x1BEF));
+}
+
+#[test]
+fn obj_priority() {
+    let mut gameboy = gameboy(0x80);
+    let mmu = &mut gameboy.mmu;
+    for i in 0..16 {
+        mmu.wb(0x8010 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        mmu.wb(0x8020 + i, 0xFF);
+    }
+    mmu.obj_palettes.set_palette(0, [0x7FFF, 0x001F, 0x03E0, 0x7C00]);
+    // earlier in OAM wins, wherever it is
+    mmu.wb(0xFE00, 16);
+    mmu.wb(0xFE01, 20);
+    mmu.wb(0xFE02, 2);
+    mmu.wb(0xFE04, 16);
+    mmu.wb(0xFE05, 16);
+    mmu.wb(0xFE06, 1);
+    mmu.wb(0xFF40, 0x93);
+
+    let ly = render_line(&mut gameboy.mmu, &mut gameboy.ppu);
+    assert_eq!(gameboy.ppu.data[ly][11], rgb555(0x001F));
+    assert_eq!(gameboy.ppu.data[ly][12], rgb555(0x7C00));
+}
@@ -0,0 +1,76 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::config::{Config, ConfigError, LockupAction};
+use crate::ppu::{DmgPalette, Preset};
+use crate::gameboy::GameBoy;
+use super::gameboy::rom;
+use crate::recording::RecordingFormat;
+
+#[test]
+fn defaults() {
+    assert_eq!(Config::parse("").unwrap().palette, Preset::Grey.palette());
+    assert_eq!(Config::load("/nonexistent/ironboy.cfg").unwrap(), Config::new());
+}
+
+#[test]
+fn presets() {
+    let config = Config::parse("# comment\n\n palette = Pocket \n").unwrap();
+    assert_eq!(config.palette, Preset::Pocket.palette());
+    for preset in Preset::ALL.iter() {
+        assert_eq!(Preset::from_name(preset.name()), Some(*preset));
+    }
+}
+
+#[test]
+fn custom_palettes() {
+    let config = Config::parse("palette = green\nobj1_palette = #ffffff aa0000 #550000 000000").unwrap();
+    let green = Preset::Green.palette();
+    assert_eq!(config.palette.bg, green.bg);
+    assert_eq!(config.palette.obj0, green.obj0);
+    assert_eq!(config.palette.obj1, [0xFFFFFFFF, 0xFFAA0000, 0xFF550000, 0xFF000000]);
+}
+
+#[test]
+fn screenshot_scale() {
+    assert_eq!(Config::new().screenshot_scale, 1);
+    assert_eq!(Config::parse("screenshot_scale = 3").unwrap().screenshot_scale, 3);
+    assert!(Config::parse("screenshot_scale = 0").is_err());
+    assert!(Config::parse("screenshot_scale = big").is_err());
+}
+
+#[test]
+fn record_format() {
+    assert_eq!(Config::new().record_format, RecordingFormat::Gif);
+    assert_eq!(Config::parse("record_format = Y4M").unwrap().record_format, RecordingFormat::Y4m);
+    assert!(Config::parse("record_format = mp4").is_err());
+}
+
+#[test]
+fn on_lockup() {
+    assert_eq!(Config::new().on_lockup, LockupAction::Freeze);
+    assert_eq!(Config::parse("on_lockup = break").unwrap().on_lockup, LockupAction::Break);
+    assert!(matches!(Config::parse("on_lockup = crash"), Err(ConfigError::InvalidValue(1, _))));
+}
+
+#[test]
+fn errors() {
+    assert!(matches!(Config::parse("volume = 3"), Err(ConfigError::UnknownKey(1, _))));
+    assert!(matches!(Config::parse("\npalette = sepia"), Err(ConfigError::InvalidValue(2, _))));
+    assert!(matches!(Config::parse("bg_palette = ffffff 000000"), Err(ConfigError::InvalidValue(1, _))));
+    assert!(matches!(Config::parse("bg_palette = ffffff 0 0 0"), Err(ConfigError::InvalidValue(1, _))));
+    assert!(matches!(Config::parse("palette"), Err(ConfigError::InvalidValue(1, _))));
+}
+
+#[test]
+fn palette_survives_reset() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.set_palette(Preset::Light.palette());
+    gameboy.load_rom(rom()).unwrap();
+    let state = gameboy.save_state();
+    gameboy.load_state(&state).unwrap();
+    assert_eq!(gameboy.palette(), Preset::Light.palette());
+
+    // the LCD is off, so the screen shows the lightest colour
+    gameboy.run_frame();
+    assert_eq!(gameboy.framebuffer()[0][0], 0xFF00B581);
+}
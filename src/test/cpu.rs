@@ -2,7 +2,7 @@
 
 use crate::mmu::MMU;
 use crate::cartridge::Cartridge;
-use crate::cpu::{ CPU, Instruction, Target };
+use crate::cpu::{ CPU, Instruction, Target, Lockup };
 use crate::registers::{ Flag };
 use crate::interrupts::IntReq;
 use crate::timer::Timer;
@@ -24,8 +24,7 @@ fn ld() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
 
     // LD r r
     cpu.reg.a = 0x20;
@@ -76,8 +75,7 @@ fn rla() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x95;
     cpu.reg.set_flag(Flag::C, true);
     cpu.execute(&mut mmu, Instruction::RLA);
@@ -90,8 +88,7 @@ fn rlca() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x85;
     cpu.execute(&mut mmu, Instruction::RLCA);
     assert_eq!(cpu.reg.a, 0x0B);
@@ -103,8 +100,7 @@ fn rrca() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x3B;
     cpu.execute(&mut mmu, Instruction::RRCA);
     assert_eq!(cpu.reg.a, 0x9D);
@@ -116,8 +112,7 @@ fn rra() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x81;
     cpu.reg.set_flag(Flag::C, false);
     cpu.execute(&mut mmu, Instruction::RRA);
@@ -130,8 +125,7 @@ fn rl() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.l = 0x80;
     cpu.execute(&mut mmu, Instruction::RL(Target::L));
     assert_eq!(cpu.reg.l, 0x0);
@@ -150,8 +144,7 @@ fn rlc() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x85;
     cpu.execute(&mut mmu, Instruction::RLC(Target::A));
     assert_eq!(cpu.reg.a, 0x0B);
@@ -170,8 +163,7 @@ fn rr() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.l = 0x01;
     cpu.execute(&mut mmu, Instruction::RR(Target::L));
     assert_eq!(cpu.reg.l, 0x0);
@@ -190,8 +182,7 @@ fn rrc() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.c = 0x01;
     cpu.execute(&mut mmu, Instruction::RRC(Target::C));
     assert_eq!(cpu.reg.c, 0x80);
@@ -210,8 +201,7 @@ fn sla() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.d = 0x80;
     cpu.execute(&mut mmu, Instruction::SLA(Target::D));
     assert_eq!(cpu.reg.a, 0x00);
@@ -231,8 +221,7 @@ fn sra() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x8A;
     cpu.execute(&mut mmu, Instruction::SRA(Target::A));
     assert_eq!(cpu.reg.a, 0xC5);
@@ -252,8 +241,7 @@ fn srl() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x01;
     cpu.execute(&mut mmu, Instruction::SRL(Target::A));
     assert_eq!(cpu.reg.a, 0x0);
@@ -273,8 +261,7 @@ fn and() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x5A;
     cpu.reg.b = 0x3F;
     cpu.reg.c = 0x38;
@@ -302,8 +289,7 @@ fn or() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x5A;
     cpu.reg.b = 0x03;
     cpu.reg.c = 0x0F;
@@ -324,8 +310,7 @@ fn xor() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0xFF;
     cpu.reg.b = 0x0F;
     cpu.reg.c = 0x8A;
@@ -349,8 +334,7 @@ fn inc() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0xFF;
     cpu.execute(&mut mmu, Instruction::INC(Target::A));
     assert_eq!(cpu.reg.a, 0x0);
@@ -368,8 +352,7 @@ fn dec() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x01;
     cpu.execute(&mut mmu, Instruction::DEC(Target::A));
     assert_eq!(cpu.reg.a, 0x0);
@@ -387,8 +370,7 @@ fn add() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.set_hl(0x8000);
     cpu.reg.a = 0x3A;
     mmu.wb(cpu.reg.hl(), 0xC6);
@@ -406,8 +388,7 @@ fn adc() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0xE1;
     cpu.reg.b = 0x0F;
     cpu.reg.c = 0x3B;
@@ -442,8 +423,7 @@ fn add_hl() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.set_hl(0x8A23);
     cpu.reg.set_de(0x0605);
     cpu.execute(&mut mmu, Instruction::ADDHL(Target::DE));
@@ -466,8 +446,7 @@ fn sub() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x3E;
     cpu.reg.b = 0x3E;
     cpu.reg.c = 0x0F;
@@ -501,8 +480,7 @@ fn sbc() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x3B;
     cpu.reg.b = 0x2A;
     cpu.reg.c = 0x4F;
@@ -537,8 +515,7 @@ fn cp() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x3C;
     cpu.reg.b = 0x2F;
     cpu.reg.c = 0x3C;
@@ -570,8 +547,7 @@ fn swap() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x00;
     cpu.execute(&mut mmu, Instruction::SWAP(Target::A));
     assert_eq!(cpu.reg.a, 0x00);
@@ -589,8 +565,7 @@ fn daa() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x45;
     cpu.reg.b = 0x38;
     cpu.execute(&mut mmu, Instruction::ADD(Target::B));
@@ -610,8 +585,7 @@ fn bit() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x80;
     cpu.execute(&mut mmu, Instruction::BIT(7, Target::A));
     assert_eq!(cpu.reg.get_flag(Flag::Z), false);
@@ -626,8 +600,7 @@ fn res() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x80;
     cpu.execute(&mut mmu, Instruction::RES(7, Target::A));
     assert_eq!(cpu.reg.a, 0x0);
@@ -647,8 +620,7 @@ fn set() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x80;
     cpu.execute(&mut mmu, Instruction::SET(2, Target::A));
     assert_eq!(cpu.reg.a, 0x84);
@@ -668,8 +640,7 @@ fn cpl() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.a = 0x35;
     cpu.execute(&mut mmu, Instruction::CPL);
     assert_eq!(cpu.reg.a, 0xCA);
@@ -682,8 +653,7 @@ fn ccf() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.set_flag(Flag::C, true);
     cpu.execute(&mut mmu, Instruction::CCF);
     assert_eq!(cpu.reg.get_flag(Flag::N), false);
@@ -702,8 +672,7 @@ fn scf() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.set_flag(Flag::C, true);
     cpu.reg.set_flag(Flag::N, true);
     cpu.reg.set_flag(Flag::H, true);
@@ -726,8 +695,7 @@ fn ei() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.pc = 0x8000;
     cpu.reg.sp = 0xC000;
     mmu.wb(0xFF0F, 4);
@@ -754,8 +722,7 @@ fn jp() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.pc = 0x8000;
     mmu.wb(0x8000, 0xC3); // JP(A)
     mmu.wb(0x8001, 0xD7); // lo
@@ -804,8 +771,7 @@ fn jr() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.pc = 0x8000;
     mmu.wb(0x8000, 0x18);
     mmu.wb(0x8001, 0x60);
@@ -818,8 +784,7 @@ fn call() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.pc = 0x8000;
     cpu.reg.sp = 0xFFFE;
     mmu.wb(0x8000, 0xCD); // CALL(A)
@@ -836,8 +801,7 @@ fn ret() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
     let mut cpu = CPU::new(timer.clone());
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     cpu.reg.pc = 0x8000;
     cpu.reg.sp = 0xFFFE;
     mmu.wb(0x8000, 0xCD); // CALL(A)
@@ -850,3 +814,283 @@ fn ret() {
     cpu.step(&mut mmu);
     assert_eq!(cpu.reg.pc, 0x8003);
 }
+
+#[test]
+fn access_timing() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    cpu.reg.pc = 0x8000;
+    cpu.reg.sp = 0x1234;
+    mmu.wb(0x8000, 0x08); // LD (nn),SP
+    mmu.wb(0x8001, 0x04);
+    mmu.wb(0x8002, 0xFF);
+
+    // the low byte resets DIV on the 4th of 5 cycles, so the
+    // timer has run for one more by the time the instruction ends
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.clocks_elapsed, 5);
+    assert_eq!(timer.borrow().counter, 4);
+    assert_eq!(timer.borrow().tima, 0x12);
+}
+
+// M-cycles taken by the instruction at 0x8000, with the given flags
+fn timing(code: &[u8], f: u8) -> u8 {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer);
+    cpu.reg.pc = 0x8000;
+    cpu.reg.sp = 0xD000;
+    cpu.reg.f = f;
+    for (i, byte) in code.iter().enumerate() {
+        mmu.wb(0x8000 + i as u16, *byte);
+    }
+    cpu.step(&mut mmu);
+    cpu.clocks_elapsed
+}
+
+#[test]
+fn branch_timing() {
+    let z = 0x80;
+    // not taken, then taken
+    assert_eq!((timing(&[0x20, 0x05], z), timing(&[0x20, 0x05], 0)), (2, 3)); // JR NZ,e
+    assert_eq!((timing(&[0xCA, 0x00, 0x90], 0), timing(&[0xCA, 0x00, 0x90], z)), (3, 4)); // JP Z,nn
+    assert_eq!((timing(&[0xC4, 0x00, 0x90], z), timing(&[0xC4, 0x00, 0x90], 0)), (3, 6)); // CALL NZ,nn
+    assert_eq!((timing(&[0xC8], 0), timing(&[0xC8], z)), (2, 5)); // RET Z
+
+    assert_eq!(timing(&[0x18, 0x05], 0), 3); // JR e
+    assert_eq!(timing(&[0xC3, 0x00, 0x90], 0), 4); // JP nn
+    assert_eq!(timing(&[0xE9], 0), 1); // JP HL
+    assert_eq!(timing(&[0xCD, 0x00, 0x90], 0), 6); // CALL nn
+    assert_eq!(timing(&[0xC9], 0), 4); // RET
+    assert_eq!(timing(&[0xD9], 0), 4); // RETI
+    assert_eq!(timing(&[0xFF], 0), 4); // RST 38h
+}
+
+#[test]
+fn internal_cycle_timing() {
+    assert_eq!(timing(&[0xC5], 0), 4); // PUSH BC
+    assert_eq!(timing(&[0xC1], 0), 3); // POP BC
+    assert_eq!(timing(&[0x03], 0), 2); // INC BC
+    assert_eq!(timing(&[0x3B], 0), 2); // DEC SP
+    assert_eq!(timing(&[0x09], 0), 2); // ADD HL,BC
+    assert_eq!(timing(&[0xE8, 0x01], 0), 4); // ADD SP,e
+    assert_eq!(timing(&[0xF8, 0x01], 0), 3); // LD HL,SP+e
+    assert_eq!(timing(&[0xF9], 0), 2); // LD SP,HL
+    assert_eq!(timing(&[0xCB, 0x46], 0), 3); // BIT 0,(HL)
+    assert_eq!(timing(&[0xCB, 0x06], 0), 4); // RLC (HL)
+}
+
+#[test]
+fn stop() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    cpu.reg.pc = 0x8000;
+    mmu.wb(0x8000, 0x10); // STOP
+    mmu.wb(0x8002, 0x10); // STOP
+    mmu.wb(0xFF00, 0b0010_0000);
+    timer.borrow_mut().counter = 0x1234;
+
+    // the padding byte is skipped and DIV reset
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x8002);
+    assert_eq!(timer.borrow().div, 0);
+    // the timer doesn't run while stopped
+    let counter = timer.borrow().counter;
+    for _ in 0..10 {
+        cpu.step(&mut mmu);
+    }
+    assert_eq!(cpu.reg.pc, 0x8002);
+    assert_eq!(timer.borrow().counter, counter);
+
+    mmu.joypad.button_down(crate::joypad::Button::Right);
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x8004);
+}
+
+#[test]
+fn stop_speed_switch() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    mmu.cgb_mode = true;
+    cpu.reg.pc = 0x8000;
+    mmu.wb(0x8000, 0x10); // STOP
+    mmu.wb(0xFF4D, 0x01);
+
+    // with KEY1 armed the CPU carries on at the other speed
+    cpu.step(&mut mmu);
+    cpu.step(&mut mmu);
+    assert!(mmu.double_speed);
+    assert_eq!(mmu.rb(0xFF4D) & 0x81, 0x80);
+    assert_eq!(cpu.reg.pc, 0x8003);
+}
+
+#[test]
+fn lockup() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    cpu.reg.pc = 0x8000;
+    mmu.wb(0x8000, 0xD3); // unused
+    mmu.wb(0xFFFF, 0x01);
+
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.lockup, Some(Lockup { pc: 0x8000, opcode: 0xD3 }));
+    assert_eq!(cpu.lockup.unwrap().to_string(), "CPU locked at PC=0x8000 opcode=0xD3");
+
+    // nothing gets it going again, but time still passes
+    mmu.wb(0xFF0F, 0x01);
+    let pc = cpu.reg.pc;
+    for _ in 0..100 {
+        cpu.step(&mut mmu);
+        assert_eq!(cpu.clocks_elapsed, 1);
+    }
+    assert_eq!(cpu.reg.pc, pc);
+    assert_eq!(timer.borrow().counter, 101 * 4);
+
+    // it's the byte the CPU fetched that's reported, which after the HALT
+    // bug isn't the one before PC
+    let mut cpu = CPU::new(timer.clone());
+    cpu.reg.pc = 0x8000;
+    mmu.wb(0x8000, 0x76); // HALT
+    mmu.wb(0x8001, 0xE4); // unused
+    cpu.step(&mut mmu);
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x8001);
+    assert_eq!(cpu.lockup, Some(Lockup { pc: 0x8001, opcode: 0xE4 }));
+}
+
+#[test]
+fn interrupt_timing() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    cpu.reg.pc = 0x8000;
+    cpu.reg.sp = 0xD000;
+    mmu.wb(0x8000, 0xFB); // EI
+    mmu.wb(0x8001, 0x00); // NOP
+    mmu.wb(0xFFFF, 0x04);
+    mmu.wb(0xFF0F, 0x04);
+
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.clocks_elapsed, 1);
+    // the NOP, then 5 cycles of dispatch
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.clocks_elapsed, 6);
+    assert_eq!(cpu.reg.pc, 0x50);
+    assert_eq!(cpu.reg.sp, 0xCFFE);
+    assert_eq!((mmu.rb(0xCFFF), mmu.rb(0xCFFE)), (0x80, 0x02));
+    assert_eq!(mmu.rb(0xFF0F) & 0x1F, 0);
+}
+
+#[test]
+fn halt() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    cpu.reg.pc = 0x8000;
+    cpu.reg.sp = 0xD000;
+    mmu.wb(0x8000, 0x76); // HALT
+    mmu.wb(0x8001, 0x3C); // INC A
+    mmu.wb(0xFFFF, 0x04);
+
+    cpu.step(&mut mmu);
+    for _ in 0..10 {
+        cpu.step(&mut mmu);
+        assert_eq!(cpu.clocks_elapsed, 1);
+    }
+    assert_eq!(cpu.reg.pc, 0x8001);
+
+    // with interrupts disabled it carries on without dispatching
+    mmu.wb(0xFF0F, 0x04);
+    cpu.step(&mut mmu);
+    assert_eq!((cpu.clocks_elapsed, cpu.reg.pc), (1, 0x8001));
+    cpu.step(&mut mmu);
+    assert_eq!((cpu.reg.a, cpu.reg.pc), (1, 0x8002));
+
+    // and with them enabled, leaving HALT takes a cycle before the dispatch
+    mmu.wb(0xFF0F, 0x00);
+    mmu.wb(0x8002, 0xFB); // EI
+    mmu.wb(0x8003, 0x76); // HALT
+    cpu.step(&mut mmu);
+    cpu.step(&mut mmu);
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x8004);
+    mmu.wb(0xFF0F, 0x04);
+    cpu.step(&mut mmu);
+    assert_eq!((cpu.clocks_elapsed, cpu.reg.pc), (6, 0x50));
+    assert_eq!((mmu.rb(0xCFFF), mmu.rb(0xCFFE)), (0x80, 0x04));
+
+    // an interrupt already pending when HALT runs is dispatched straight
+    // away, and the handler runs rather than halting at the vector
+    cpu.reg.pc = 0x8002;
+    mmu.wb(0xFF0F, 0x04);
+    cpu.step(&mut mmu);
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x50);
+    assert_eq!((mmu.rb(0xCFFD), mmu.rb(0xCFFC)), (0x80, 0x04));
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x51);
+}
+
+#[test]
+fn halt_bug() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    cpu.reg.pc = 0x8000;
+    mmu.wb(0x8000, 0x76); // HALT
+    mmu.wb(0x8001, 0x3C); // INC A
+    mmu.wb(0xFFFF, 0x04);
+    mmu.wb(0xFF0F, 0x04);
+
+    // interrupts are disabled with one pending, so INC A runs twice
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x8001);
+    cpu.step(&mut mmu);
+    assert_eq!((cpu.reg.a, cpu.reg.pc), (1, 0x8001));
+    cpu.step(&mut mmu);
+    assert_eq!((cpu.reg.a, cpu.reg.pc), (2, 0x8002));
+}
+
+#[test]
+fn ie_push() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut cpu = CPU::new(timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    cpu.reg.pc = 0xC100;
+    cpu.reg.sp = 0x0000;
+    mmu.wb(0xC100, 0xFB); // EI
+    mmu.wb(0xC101, 0x00); // NOP
+    mmu.wb(0xFFFF, 0x04);
+    mmu.wb(0xFF0F, 0x05);
+
+    // pushing 0xC1 into IE switches the dispatch over to VBlank
+    cpu.step(&mut mmu);
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x40);
+    assert_eq!(mmu.rb(0xFFFF), 0xC1);
+    assert_eq!(mmu.rb(0xFF0F) & 0x1F, 0x04);
+
+    // and pushing 0x80 into it leaves nothing to dispatch
+    cpu.reg.pc = 0x8000;
+    cpu.reg.sp = 0x0000;
+    mmu.wb(0x8000, 0xFB); // EI
+    mmu.wb(0x8001, 0x00); // NOP
+    mmu.wb(0xFFFF, 0x04);
+    cpu.step(&mut mmu);
+    cpu.step(&mut mmu);
+    assert_eq!(cpu.reg.pc, 0x0000);
+    assert_eq!(mmu.rb(0xFF0F) & 0x1F, 0x04);
+}
@@ -0,0 +1,92 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::mmu::MMU;
+use crate::cartridge::Cartridge;
+use crate::interrupts::IntReq;
+use crate::timer::Timer;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn mmu() -> MMU {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr)));
+    let mut mmu = MMU::new(Cartridge::empty(), timer);
+    for i in 0..0xA0 {
+        mmu.wb(0xC000 + i, i as u8);
+        mmu.wb(0xD000 + i, 0x80 | i as u8);
+    }
+    mmu
+}
+
+#[test]
+fn timing() {
+    let mut mmu = mmu();
+    mmu.wb(0xFF46, 0xC0);
+    assert_eq!(mmu.rb(0xFF46), 0xC0);
+
+    // one cycle of setup, then a byte every cycle
+    mmu.tick_oam_dma(1);
+    assert!(mmu.oam_dma.active);
+    mmu.tick_oam_dma(1);
+    mmu.tick_oam_dma(158);
+    assert!(mmu.oam_dma.active);
+    assert_eq!(mmu.rb(0xFE9F), 0xFF);
+    mmu.tick_oam_dma(1);
+    assert!(!mmu.oam_dma.active);
+    for i in 0..0xA0 {
+        assert_eq!(mmu.rb(0xFE00 + i), i as u8);
+    }
+}
+
+#[test]
+fn bus_conflicts() {
+    let mut mmu = mmu();
+    mmu.wb(0xFF80, 0x12);
+    mmu.wb(0x8000, 0x34);
+    mmu.wb(0xFF46, 0xC0);
+    mmu.tick_oam_dma(4);
+
+    // the CPU sees what the DMA is copying on the same bus
+    assert_eq!(mmu.rb(0xC010), 0x02);
+    assert_eq!(mmu.rb(0x0000), 0x02);
+    mmu.wb(0xC010, 0xAA);
+    // OAM is unavailable, while VRAM, HRAM and IO aren't on that bus
+    assert_eq!(mmu.rb(0xFE00), 0xFF);
+    assert_eq!(mmu.rb(0x8000), 0x34);
+    assert_eq!(mmu.rb(0xFF80), 0x12);
+    assert_eq!(mmu.rb(0xFF46), 0xC0);
+
+    mmu.tick_oam_dma(160);
+    assert_eq!(mmu.rb(0xC010), 0x10);
+}
+
+#[test]
+fn echo_sources() {
+    let mut mmu = mmu();
+    // 0xF0 copies from the echo of 0xD000
+    mmu.wb(0xFF46, 0xF0);
+    mmu.tick_oam_dma(161);
+    assert_eq!(mmu.rb(0xFE00), 0x80);
+    assert_eq!(mmu.rb(0xFE9F), 0x80 | 0x9F);
+
+    // 0xFE and 0xFF no longer crash
+    mmu.wb(0xFF46, 0xFF);
+    mmu.tick_oam_dma(161);
+    assert!(!mmu.oam_dma.active);
+}
+
+#[test]
+fn restart() {
+    let mut mmu = mmu();
+    mmu.wb(0xFF46, 0xC0);
+    mmu.tick_oam_dma(11);
+    mmu.wb(0xFF46, 0xD0);
+    // the first transfer still copies during the second one's setup
+    mmu.tick_oam_dma(1);
+    assert!(mmu.oam_dma.active);
+    assert_eq!(mmu.oam_dma.index, 0);
+    mmu.tick_oam_dma(160);
+    assert!(!mmu.oam_dma.active);
+    assert_eq!(mmu.rb(0xFE00), 0x80);
+    assert_eq!(mmu.rb(0xFE0A), 0x8A);
+}
@@ -0,0 +1,128 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::filter::{VideoFilter, Upscaler};
+use crate::config::{Config, ConfigError};
+use crate::ppu::{SCREEN_W, SCREEN_H};
+
+const WHITE: u32 = 0xFFFFFFFF;
+const BLACK: u32 = 0xFF000000;
+
+fn blank(color: u32) -> Box<[[u32; SCREEN_W]; SCREEN_H]> {
+    Box::new([[color; SCREEN_W]; SCREEN_H])
+}
+
+// a black staircase going down and right from the top left corner,
+// filled in below the diagonal
+fn diagonal() -> Box<[[u32; SCREEN_W]; SCREEN_H]> {
+    let mut frame = blank(WHITE);
+    for (y, row) in frame.iter_mut().enumerate() {
+        for pixel in row.iter_mut().take(y) {
+            *pixel = BLACK;
+        }
+    }
+    frame
+}
+
+fn block(out: &[u32], width: usize, x: usize, y: usize, size: usize) -> Vec<u32> {
+    (0..size).flat_map(|dy| out[(y + dy) * width + x..][..size].to_vec()).collect()
+}
+
+#[test]
+fn nearest() {
+    let mut frame = blank(WHITE);
+    frame[1][2] = BLACK;
+    let mut filter = VideoFilter::new(3, Upscaler::None);
+    assert_eq!((filter.width(), filter.height()), (480, 432));
+    let out = filter.apply(&frame).to_vec();
+    assert_eq!(out.len(), 480 * 432);
+    assert_eq!(block(&out, 480, 6, 3, 3), vec![BLACK; 9]);
+    assert_eq!(block(&out, 480, 3, 3, 3), vec![WHITE; 9]);
+    assert_eq!(out[5 * 480 + 9], WHITE);
+}
+
+#[test]
+fn scale2x() {
+    let mut filter = VideoFilter::new(2, Upscaler::Scale2x);
+    let out = filter.apply(&diagonal()).to_vec();
+    let width = SCREEN_W * 2;
+    // the pixel just above the step gets its lower left corner filled in,
+    // the one just below it its upper right corner cut
+    assert_eq!(block(&out, width, 2 * 4, 2 * 4, 2), vec![WHITE, WHITE, BLACK, WHITE]);
+    assert_eq!(block(&out, width, 2 * 4, 2 * 5, 2), vec![BLACK, WHITE, BLACK, BLACK]);
+    // away from edges nothing changes
+    assert_eq!(block(&out, width, 2 * 50, 2 * 4, 2), vec![WHITE; 4]);
+    assert_eq!(block(&out, width, 0, 2 * 100, 2), vec![BLACK; 4]);
+}
+
+#[test]
+fn smooth2x() {
+    let mut filter = VideoFilter::new(4, Upscaler::Smooth2x);
+    let out = filter.apply(&diagonal()).to_vec();
+    let width = SCREEN_W * 4;
+    let grey = 0xFF7F7F7F;
+    // the corner Scale2x would fill is blended instead, and doubled
+    assert_eq!(block(&out, width, 4 * 4, 4 * 4 + 2, 2), vec![grey; 4]);
+    assert_eq!(block(&out, width, 4 * 4 + 2, 4 * 4 + 2, 2), vec![WHITE; 4]);
+}
+
+#[test]
+fn scale3x() {
+    let mut filter = VideoFilter::new(3, Upscaler::Scale3x);
+    let out = filter.apply(&diagonal()).to_vec();
+    let width = SCREEN_W * 3;
+    assert_eq!(block(&out, width, 3 * 4, 3 * 4, 3), vec![
+        WHITE, WHITE, WHITE,
+        WHITE, WHITE, WHITE,
+        BLACK, WHITE, WHITE,
+    ]);
+    assert_eq!(block(&out, width, 3 * 4, 3 * 5, 3), vec![
+        BLACK, BLACK, WHITE,
+        BLACK, BLACK, BLACK,
+        BLACK, BLACK, BLACK,
+    ]);
+}
+
+#[test]
+fn upscaler_must_fit() {
+    // scale2x can't fill 3x, so it's left out
+    let mut filter = VideoFilter::new(3, Upscaler::Scale2x);
+    let out = filter.apply(&diagonal()).to_vec();
+    assert_eq!(block(&out, SCREEN_W * 3, 3 * 4, 3 * 4, 3), vec![WHITE; 9]);
+    assert!(Upscaler::Scale3x.fits(6));
+    assert!(!Upscaler::Smooth2x.fits(3));
+}
+
+#[test]
+fn lcd_grid() {
+    let mut filter = VideoFilter::new(3, Upscaler::None);
+    filter.grid = true;
+    let out = filter.apply(&blank(WHITE)).to_vec();
+    let gap = 0xFFAFAFAF;
+    assert_eq!(block(&out, 480, 3, 3, 3), vec![WHITE, WHITE, gap, WHITE, WHITE, gap, gap, gap, gap]);
+}
+
+#[test]
+fn ghosting() {
+    let mut filter = VideoFilter::new(1, Upscaler::None);
+    filter.ghosting = 25;
+    // nothing to blend the first frame with
+    assert_eq!(filter.apply(&blank(WHITE))[0], WHITE);
+    assert_eq!(filter.apply(&blank(BLACK))[0], 0xFF3F3F3F);
+    assert_eq!(filter.apply(&blank(BLACK))[0], BLACK);
+    filter.ghosting = 0;
+    assert_eq!(filter.apply(&blank(WHITE))[0], WHITE);
+}
+
+#[test]
+fn config() {
+    let config = Config::new();
+    assert_eq!((config.scale, config.filter, config.lcd_grid, config.ghosting), (2, Upscaler::None, false, 0));
+
+    let config = Config::parse("scale = 6\nfilter = Scale3x\nlcd_grid = on\nghosting = 40%").unwrap();
+    assert_eq!((config.scale, config.filter, config.lcd_grid, config.ghosting), (6, Upscaler::Scale3x, true, 40));
+
+    assert!(matches!(Config::parse("filter = scale3x"), Err(ConfigError::InvalidValue(1, _))));
+    assert!(matches!(Config::parse("scale = 9"), Err(ConfigError::InvalidValue(1, _))));
+    assert!(matches!(Config::parse("ghosting = 101"), Err(ConfigError::InvalidValue(1, _))));
+    assert!(matches!(Config::parse("lcd_grid = maybe"), Err(ConfigError::InvalidValue(1, _))));
+}
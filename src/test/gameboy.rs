@@ -0,0 +1,128 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::gameboy::{GameBoy, CYCLES_PER_FRAME};
+use crate::cartridge::KILOBYTE;
+use crate::joypad::Button;
+
+// a blank 32KB cartridge with the given title and header bytes set
+pub(super) fn rom_with(title: &[u8], header: &[(usize, u8)]) -> Vec<u8> {
+    let mut rom: Vec<u8> = vec![0; 32 * KILOBYTE as usize];
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    for &(address, value) in header {
+        rom[address] = value;
+    }
+    rom
+}
+
+pub(super) fn rom() -> Vec<u8> {
+    rom_with(b"TETRA", &[])
+}
+
+// the cartridge in a machine with a blank boot rom
+pub(super) fn gameboy_with(rom: Vec<u8>) -> GameBoy {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.load_rom(rom).unwrap();
+    gameboy
+}
+
+#[test]
+fn load_rom() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    assert_eq!(gameboy.mmu.rb(0x0150), 0xFF);
+    assert_eq!(gameboy.title(), "");
+
+    assert!(gameboy.load_rom(vec![0; 0x100]).is_err());
+    assert!(gameboy.load_rom(rom()).is_ok());
+    assert_eq!(gameboy.mmu.rb(0x0150), 0x00);
+    assert_eq!(gameboy.title(), "TETRA");
+}
+
+#[test]
+fn run_frame_lcd_off() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.load_rom(rom()).unwrap();
+
+    // NOPs with the LCD off still only run for a single frame
+    gameboy.run_frame();
+    assert_eq!(gameboy.cpu.reg.pc as u32, CYCLES_PER_FRAME);
+    assert!(!gameboy.ppu.update_screen);
+}
+
+#[test]
+fn run_frame_lcd_on() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.load_rom(rom()).unwrap();
+    gameboy.mmu.wb(0xFF40, 0x80);
+
+    gameboy.run_frame();
+    assert_eq!(gameboy.mmu.rb(0xFF44), 144);
+    assert!(!gameboy.ppu.update_screen);
+}
+
+#[test]
+fn buttons() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    gameboy.load_rom(rom()).unwrap();
+
+    // nothing is held to begin with
+    gameboy.mmu.wb(0xFF00, 0b0001_0000);
+    assert_eq!(gameboy.mmu.rb(0xFF00) & 0x0F, 0b1111);
+    gameboy.press(Button::Start);
+    assert_eq!(gameboy.mmu.rb(0xFF00) & 0x0F, 0b0111);
+    gameboy.release(Button::Start);
+    assert_eq!(gameboy.mmu.rb(0xFF00) & 0x0F, 0b1111);
+}
+
+#[test]
+fn stop() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    let mut rom = rom();
+    rom[0x100] = 0x10; // STOP
+    gameboy.load_rom(rom).unwrap();
+    gameboy.mmu.wb(0xFF40, 0x80);
+    gameboy.mmu.wb(0xFF00, 0b0001_0000);
+    gameboy.cpu.reg.pc = 0x100;
+
+    // a whole frame passes with the CPU and LCD stopped
+    gameboy.step();
+    assert_eq!(gameboy.cpu.reg.pc, 0x102);
+    let ly = gameboy.mmu.rb(0xFF44);
+    gameboy.run_frame();
+    assert_eq!(gameboy.cpu.reg.pc, 0x102);
+    assert_eq!(gameboy.mmu.rb(0xFF44), ly);
+    assert_eq!(gameboy.mmu.rb(0xFF04), 0);
+
+    // a button on an unselected line doesn't wake it up
+    gameboy.press(Button::Up);
+    gameboy.step();
+    assert_eq!(gameboy.cpu.reg.pc, 0x102);
+    gameboy.press(Button::A);
+    gameboy.step();
+    assert_eq!(gameboy.cpu.reg.pc, 0x103);
+}
+
+#[test]
+fn lockup() {
+    let mut gameboy = GameBoy::new(vec![0; 0x100]);
+    let mut rom = rom();
+    rom[0x100] = 0xFD; // unused
+    gameboy.load_rom(rom).unwrap();
+    gameboy.mmu.wb(0xFF40, 0x80);
+    gameboy.cpu.reg.pc = 0x100;
+
+    assert_eq!(gameboy.take_lockup(), None);
+    gameboy.step();
+    let lockup = gameboy.take_lockup().unwrap();
+    assert_eq!((lockup.pc, lockup.opcode), (0x100, 0xFD));
+    // it's only reported once
+    assert_eq!(gameboy.take_lockup(), None);
+
+    // the PPU keeps going without the CPU, also after a save state
+    let state = gameboy.save_state();
+    gameboy.run_frame();
+    gameboy.run_frame();
+    assert_eq!(gameboy.mmu.rb(0xFF44), 144);
+    assert_eq!(gameboy.cpu.reg.pc, 0x101);
+    gameboy.load_state(&state).unwrap();
+    assert_eq!(gameboy.take_lockup(), Some(lockup));
+}
@@ -0,0 +1,95 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::gameboy::GameBoy;
+use super::gameboy::{rom_with, gameboy_with};
+
+// a CGB cartridge with 0x00 to 0xFF at the start of bank 1
+fn gameboy() -> GameBoy {
+    let mut rom = rom_with(b"", &[(0x143, 0x80)]);
+    for i in 0..0x100 {
+        rom[0x4000 + i] = i as u8;
+    }
+    gameboy_with(rom)
+}
+
+fn start(gameboy: &mut GameBoy, source: u16, dest: u16, control: u8) {
+    let mmu = &mut gameboy.mmu;
+    mmu.wb(0xFF51, (source >> 8) as u8);
+    mmu.wb(0xFF52, source as u8);
+    mmu.wb(0xFF53, (dest >> 8) as u8);
+    mmu.wb(0xFF54, dest as u8);
+    mmu.wb(0xFF55, control);
+}
+
+#[test]
+fn general_purpose() {
+    let mut gameboy = gameboy();
+    // the low 4 bits of both addresses are ignored
+    start(&mut gameboy, 0x400F, 0x8105, 0x01);
+    assert_eq!(gameboy.mmu.rb(0xFF55), 0xFF);
+    for i in 0..0x20 {
+        assert_eq!(gameboy.mmu.vram(0, 0x8100 + i), i as u8);
+    }
+    assert_eq!(gameboy.mmu.vram(0, 0x8120), 0);
+
+    // the CPU waits 8 M-cycles per block
+    let pc = gameboy.cpu.reg.pc;
+    assert_eq!(gameboy.step(), 16);
+    assert_eq!(gameboy.cpu.reg.pc, pc);
+}
+
+#[test]
+fn vram_bank() {
+    let mut gameboy = gameboy();
+    gameboy.mmu.wb(0xFF4F, 1);
+    start(&mut gameboy, 0x4000, 0x9000, 0x00);
+    assert_eq!(gameboy.mmu.vram(1, 0x9001), 0x01);
+    assert_eq!(gameboy.mmu.vram(0, 0x9001), 0x00);
+}
+
+#[test]
+fn hblank() {
+    let mut gameboy = gameboy();
+    gameboy.mmu.wb(0xFF40, 0x00);
+    gameboy.mmu.wb(0xFF40, 0x80);
+    start(&mut gameboy, 0x4000, 0x8000, 0x82);
+    assert_eq!(gameboy.mmu.rb(0xFF55), 0x02);
+    assert_eq!(gameboy.mmu.vram(0, 0x8000), 0x00);
+
+    // one block per line
+    for line in 1..=3 {
+        for _ in 0..114 {
+            gameboy.ppu.tick(&mut gameboy.mmu);
+        }
+        assert_eq!(gameboy.mmu.vram(0, 0x8000 + (line - 1) * 0x10 + 1), ((line - 1) * 0x10 + 1) as u8);
+        assert_eq!(gameboy.mmu.vram(0, 0x8000 + line * 0x10 + 1), 0);
+    }
+    assert_eq!(gameboy.mmu.rb(0xFF55), 0xFF);
+}
+
+#[test]
+fn cancel() {
+    let mut gameboy = gameboy();
+    gameboy.mmu.wb(0xFF40, 0x80);
+    start(&mut gameboy, 0x4000, 0x8000, 0x85);
+    for _ in 0..114 {
+        gameboy.ppu.tick(&mut gameboy.mmu);
+    }
+    gameboy.mmu.wb(0xFF55, 0x00);
+    // bit 7 set with the blocks that were left
+    assert_eq!(gameboy.mmu.rb(0xFF55), 0x84);
+    for _ in 0..114 {
+        gameboy.ppu.tick(&mut gameboy.mmu);
+    }
+    assert_eq!(gameboy.mmu.vram(0, 0x8011), 0);
+}
+
+#[test]
+fn lcd_off() {
+    let mut gameboy = gameboy();
+    gameboy.mmu.wb(0xFF40, 0x00);
+    start(&mut gameboy, 0x4000, 0x8000, 0x81);
+    assert_eq!(gameboy.mmu.vram(0, 0x800F), 0x0F);
+    assert_eq!(gameboy.mmu.vram(0, 0x8010), 0x00);
+    assert_eq!(gameboy.mmu.rb(0xFF55), 0x00);
+}
@@ -22,8 +22,7 @@ fn cartridge() -> Cartridge {
 fn tick_clocks() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     let mut ppu = PPU::new(intr.clone());
 
     mmu.wb(0xFF40, 0x80);
@@ -58,8 +57,7 @@ fn tick_clocks() {
 fn vblank_clocks() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     let mut ppu = PPU::new(intr.clone());
 
     mmu.wb(0xFF40, 0x80);
@@ -78,8 +76,462 @@ fn vblank_clocks() {
         assert_eq!(ppu.stat.mode, Mode::VBlank);
     }
 
+    // LY already reads 0 for most of line 153
+    assert_eq!(mmu.rb(0xFF44), 0);
+    ppu.tick(&mut mmu);
+    assert_eq!(ppu.stat.mode, Mode::OAMSearch);
+    assert_eq!(mmu.rb(0xFF44), 0);
+}
+
+fn lcd_on() -> (MMU, PPU) {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut mmu = MMU::new(cartridge(), timer.clone());
+    let ppu = PPU::new(intr.clone());
+    mmu.wb(0xFF40, 0x93);
+    (mmu, ppu)
+}
+
+// M-cycles of pixel transfer on the first line
+fn transfer_clocks(mmu: &mut MMU, ppu: &mut PPU) -> u32 {
+    while ppu.stat.mode != Mode::PixelTransfer {
+        ppu.tick(mmu);
+    }
+    let mut clocks = 1;
+    ppu.tick(mmu);
+    while ppu.stat.mode == Mode::PixelTransfer {
+        clocks += 1;
+        ppu.tick(mmu);
+    }
+    clocks
+}
+
+#[test]
+fn fine_scroll_lengthens_transfer() {
+    let (mut mmu, mut ppu) = lcd_on();
+    assert_eq!(transfer_clocks(&mut mmu, &mut ppu), 43);
+
+    // 7 more dots spent throwing pixels away
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0xFF43, 7);
+    assert_eq!(transfer_clocks(&mut mmu, &mut ppu), 44);
+
+    // only the fine scroll matters
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0xFF43, 8);
+    assert_eq!(transfer_clocks(&mut mmu, &mut ppu), 43);
+}
+
+#[test]
+fn sprites_lengthen_transfer() {
+    let (mut mmu, mut ppu) = lcd_on();
+    for i in 0..10 {
+        mmu.wb(0xFE00 + i * 4, 16);
+        mmu.wb(0xFE01 + i * 4, 8 + i as u8 * 16);
+    }
+    let clocks = transfer_clocks(&mut mmu, &mut ppu);
+    // every sprite costs between 6 and 11 dots
+    assert!((43 + 15..=43 + 28).contains(&clocks), "{}", clocks);
+
+    // with sprites off they cost nothing
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0xFF40, 0x91);
+    mmu.wb(0xFE00, 16);
+    mmu.wb(0xFE01, 8);
+    assert_eq!(transfer_clocks(&mut mmu, &mut ppu), 43);
+}
+
+#[test]
+fn window_lengthens_transfer() {
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0xFF40, 0xB1);
+    mmu.wb(0xFF4A, 0);
+    mmu.wb(0xFF4B, 87);
+    // the fetcher starts over when it reaches the window
+    assert_eq!(transfer_clocks(&mut mmu, &mut ppu), 44);
+}
+
+#[test]
+fn mid_line_palette_change() {
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0xFF47, 0b00);
+    while ppu.stat.mode != Mode::PixelTransfer {
+        ppu.tick(&mut mmu);
+    }
+    // about halfway through the line
+    for _ in 0..23 {
+        ppu.tick(&mut mmu);
+    }
+    mmu.wb(0xFF47, 0b11);
+    while ppu.stat.mode == Mode::PixelTransfer {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(ppu.data[0][0], 0xFFFFFFFF);
+    assert_eq!(ppu.data[0][159], 0xFF000000);
+}
+
+#[test]
+fn sprite_priority() {
+    let (mut mmu, mut ppu) = lcd_on();
+    // tile 1 is solid colour 1, tile 2 solid colour 3
+    for i in 0..16 {
+        mmu.wb(0x8010 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        mmu.wb(0x8020 + i, 0xFF);
+    }
+    mmu.wb(0xFF47, 0b1110_1000);
+    mmu.wb(0xFF48, 0b1110_0100);
+    // background is colour 0, except colour 1 from x=16 to 23
+    mmu.wb(0x9802, 1);
+
+    // the sprite further left wins where the two overlap,
+    // even though it's behind the background
+    mmu.wb(0xFE00, 16);
+    mmu.wb(0xFE01, 12);
+    mmu.wb(0xFE02, 2);
+    mmu.wb(0xFE04, 16);
+    mmu.wb(0xFE05, 8);
+    mmu.wb(0xFE06, 1);
+    mmu.wb(0xFE07, 0x80);
+    mmu.wb(0xFE08, 16);
+    mmu.wb(0xFE09, 24);
+    mmu.wb(0xFE0A, 1);
+    mmu.wb(0xFE0B, 0x80);
+
+    while ppu.stat.mode != Mode::HBlank {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(ppu.data[0][0], 0xFFC0C0C0);
+    assert_eq!(ppu.data[0][7], 0xFFC0C0C0);
+    assert_eq!(ppu.data[0][8], 0xFF000000);
+    assert_eq!(ppu.data[0][11], 0xFF000000);
+    assert_eq!(ppu.data[0][12], 0xFFFFFFFF);
+    // hidden behind non-zero background
+    assert_eq!(ppu.data[0][16], 0xFF606060);
+}
+
+#[test]
+fn sprite_limit() {
+    let (mut mmu, mut ppu) = lcd_on();
+    for i in 0..16 {
+        mmu.wb(0x8010 + i, 0xFF);
+    }
+    mmu.wb(0xFF48, 0b1110_0100);
+    // 11 sprites side by side, the last one isn't picked
+    for i in 0..11 {
+        mmu.wb(0xFE00 + i * 4, 16);
+        mmu.wb(0xFE01 + i * 4, 8 + i as u8 * 8);
+        mmu.wb(0xFE02 + i * 4, 1);
+    }
+    // but the first ten are, even when off screen
+    mmu.wb(0xFE01, 0);
+
+    while ppu.stat.mode != Mode::HBlank {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(ppu.data[0][0], 0xFFFFFFFF);
+    assert_eq!(ppu.data[0][8], 0xFF000000);
+    assert_eq!(ppu.data[0][79], 0xFF000000);
+    assert_eq!(ppu.data[0][80], 0xFFFFFFFF);
+}
+
+#[test]
+fn dmg_x_priority() {
+    let (mut mmu, mut ppu) = lcd_on();
+    for i in 0..16 {
+        mmu.wb(0x8010 + i, if i % 2 == 0 { 0xFF } else { 0x00 });
+        mmu.wb(0x8020 + i, 0xFF);
+    }
+    mmu.wb(0xFF48, 0b1110_0100);
+
+    // later in OAM, but further left
+    let sprites = [(5, 2), (2, 1), (20, 2), (16, 1)];
+    for (i, (x, tile)) in sprites.iter().enumerate() {
+        mmu.wb(0xFE00 + i as u16 * 4, 16);
+        mmu.wb(0xFE01 + i as u16 * 4, *x);
+        mmu.wb(0xFE02 + i as u16 * 4, *tile);
+    }
+
+    while ppu.stat.mode != Mode::HBlank {
+        ppu.tick(&mut mmu);
+    }
+    // both hang off the left edge
+    assert_eq!(ppu.data[0][1], 0xFFC0C0C0);
+    assert_eq!(ppu.data[0][2], 0xFF000000);
+    assert_eq!(ppu.data[0][15], 0xFFC0C0C0);
+    assert_eq!(ppu.data[0][16], 0xFF000000);
+}
+
+// runs the PPU through the next line's pixel transfer, returning the line drawn
+pub(super) fn render_line(mmu: &mut MMU, ppu: &mut PPU) -> usize {
+    while ppu.stat.mode != Mode::PixelTransfer {
+        ppu.tick(mmu);
+    }
+    let ly = mmu.rb(0xFF44) as usize;
+    while ppu.stat.mode == Mode::PixelTransfer {
+        ppu.tick(mmu);
+    }
+    ly
+}
+
+// the window uses the second map, with tile 1 (colour 3 on its right
+// half) all over its second row
+fn window_setup() -> (MMU, PPU) {
+    let (mut mmu, ppu) = lcd_on();
+    for i in 0..16 {
+        mmu.wb(0x8010 + i, 0x0F);
+    }
+    for i in 0..32 {
+        mmu.wb(0x9C20 + i, 1);
+    }
+    mmu.wb(0xFF47, 0b1110_0100);
+    mmu.wb(0xFF4A, 0);
+    mmu.wb(0xFF4B, 7);
+    (mmu, ppu)
+}
+
+#[test]
+fn window_line_counter() {
+    let (mut mmu, mut ppu) = window_setup();
+    // the window is turned on at line 8, and starts from its own first row
+    while render_line(&mut mmu, &mut ppu) < 7 {}
+    mmu.wb(0xFF40, 0xF3);
+    for _ in 0..8 {
+        let ly = render_line(&mut mmu, &mut ppu);
+        assert_eq!(ppu.data[ly][4], 0xFFFFFFFF, "line {}", ly);
+    }
+    let ly = render_line(&mut mmu, &mut ppu);
+    assert_eq!(ly, 16);
+    assert_eq!(ppu.data[ly][4], 0xFF000000);
+}
+
+#[test]
+fn window_wy_trigger() {
+    let (mut mmu, mut ppu) = window_setup();
+    mmu.wb(0xFF40, 0xF3);
+    // WY has to match LY at some point in the frame
+    mmu.wb(0xFF4A, 100);
+    render_line(&mut mmu, &mut ppu);
+    mmu.wb(0xFF4A, 0);
+    for _ in 0..10 {
+        let ly = render_line(&mut mmu, &mut ppu);
+        assert_eq!(ppu.data[ly][4], 0xFFFFFFFF, "line {}", ly);
+    }
+}
+
+#[test]
+fn window_left_edge() {
+    let (mut mmu, mut ppu) = window_setup();
+    mmu.wb(0xFF40, 0xF3);
+    mmu.wb(0xFF4A, 0);
+    mmu.wb(0xFF4B, 3);
+    while render_line(&mut mmu, &mut ppu) < 8 {}
+    // 4 of the window's pixels are off screen
+    assert_eq!(ppu.data[8][0], 0xFF000000);
+    assert_eq!(ppu.data[8][3], 0xFF000000);
+    assert_eq!(ppu.data[8][4], 0xFFFFFFFF);
+    assert_eq!(ppu.data[8][8], 0xFF000000);
+}
+
+#[test]
+fn window_right_edge() {
+    let (mut mmu, mut ppu) = window_setup();
+    for i in 0..32 {
+        mmu.wb(0x9C00 + i, 1);
+    }
+    mmu.wb(0x8010, 0xFF);
+    mmu.wb(0x8011, 0xFF);
+    mmu.wb(0xFF40, 0xF3);
+    mmu.wb(0xFF4B, 166);
+    let ly = render_line(&mut mmu, &mut ppu);
+    assert_eq!(ppu.data[ly][158], 0xFFFFFFFF);
+    assert_eq!(ppu.data[ly][159], 0xFF000000);
+}
+
+#[test]
+fn dmg_bg_disable() {
+    let (mut mmu, mut ppu) = window_setup();
+    mmu.wb(0xFF47, 0xFF);
+    // window on, but bit 0 blanks it as well as the background
+    mmu.wb(0xFF40, 0xF2);
+    while render_line(&mut mmu, &mut ppu) < 8 {}
+    assert_eq!(ppu.data[8][0], 0xFFFFFFFF);
+    assert_eq!(ppu.data[8][7], 0xFFFFFFFF);
+}
+
+fn stat_requested(mmu: &mut MMU) -> bool {
+    let requested = mmu.rb(0xFF0F) & 0b10 != 0;
+    mmu.wb(0xFF0F, 0);
+    requested
+}
+
+#[test]
+fn line_153() {
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0xFF41, 0x40);
+    mmu.wb(0xFF45, 153);
+    while ppu.get_ly() != 152 {
+        ppu.tick(&mut mmu);
+    }
+    stat_requested(&mut mmu);
+    for _ in 0..114 {
+        ppu.tick(&mut mmu);
+    }
     assert_eq!(mmu.rb(0xFF44), 153);
+    assert!(stat_requested(&mut mmu));
+
+    // LY reads 0 from the second M-cycle on, which LYC=0 picks up
+    mmu.wb(0xFF45, 0);
     ppu.tick(&mut mmu);
+    assert_eq!(mmu.rb(0xFF44), 0);
+    assert_eq!(ppu.stat.mode, Mode::VBlank);
+    assert!(stat_requested(&mut mmu));
+    // and it stays matched into line 0, so there's no second interrupt
+    for _ in 0..113 {
+        ppu.tick(&mut mmu);
+    }
     assert_eq!(ppu.stat.mode, Mode::OAMSearch);
+    assert!(!stat_requested(&mut mmu));
+}
+
+#[test]
+fn stat_blocking() {
+    let (mut mmu, mut ppu) = lcd_on();
+    // HBlank and OAM search sources back to back never let the line go low
+    // between them, while mode 3 in the middle of the line does
+    mmu.wb(0xFF41, 0x28);
+    render_line(&mut mmu, &mut ppu);
+    stat_requested(&mut mmu);
+    let mut requests = 0;
+    for _ in 0..114 {
+        ppu.tick(&mut mmu);
+        requests += stat_requested(&mut mmu) as u32;
+    }
+    // OAM search of the next line is blocked, HBlank of it isn't
+    assert_eq!(requests, 1);
+
+    // LY=LYC on the next line takes over from HBlank without a gap, and
+    // then stays high through that line's HBlank
+    let ly = mmu.rb(0xFF44);
+    mmu.wb(0xFF45, ly + 1);
+    mmu.wb(0xFF41, 0x48);
+    while mmu.rb(0xFF44) == ly {
+        ppu.tick(&mut mmu);
+    }
+    assert!(!stat_requested(&mut mmu));
+    render_line(&mut mmu, &mut ppu);
+    ppu.tick(&mut mmu);
+    assert_eq!(ppu.stat.mode, Mode::HBlank);
+    assert!(!stat_requested(&mut mmu));
+}
+
+#[test]
+fn ly_read_only() {
+    let (mut mmu, mut ppu) = lcd_on();
+    for _ in 0..200 {
+        ppu.tick(&mut mmu);
+    }
+    mmu.wb(0xFF44, 100);
+    assert_eq!(mmu.rb(0xFF44), 1);
+    // and so are the bottom bits of STAT
+    mmu.wb(0xFF41, 0xFF);
+    assert_eq!(mmu.rb(0xFF41), 0xF8 | Mode::HBlank as u8);
+}
+
+#[test]
+fn lcd_off() {
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0xFF47, 0xFF);
+    while mmu.rb(0xFF44) != 10 {
+        ppu.tick(&mut mmu);
+    }
+    mmu.wb(0xFF40, 0x13);
+    ppu.tick(&mut mmu);
     assert_eq!(mmu.rb(0xFF44), 0);
+    assert_eq!(mmu.rb(0xFF41) & 0b11, 0);
+    assert_eq!(ppu.data[5][5], 0xFFFFFFFF);
+    for _ in 0..1000 {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(mmu.rb(0xFF44), 0);
+
+    // the first frame after turning it back on is left blank
+    mmu.wb(0xFF40, 0x93);
+    ppu.update_screen = false;
+    while !ppu.update_screen {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(ppu.data[5][5], 0xFFFFFFFF);
+    ppu.update_screen = false;
+    while !ppu.update_screen {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(ppu.data[5][5], 0xFF000000);
+}
+
+#[test]
+fn access_restrictions() {
+    let (mut mmu, mut ppu) = lcd_on();
+    mmu.wb(0x8000, 0x12);
+    mmu.wb(0xFE00, 0x34);
+
+    // OAM search
+    ppu.tick(&mut mmu);
+    assert_eq!(mmu.rb(0x8000), 0x12);
+    assert_eq!(mmu.rb(0xFE00), 0xFF);
+    mmu.wb(0xFE00, 0x56);
+
+    // pixel transfer
+    while ppu.stat.mode != Mode::PixelTransfer {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(mmu.rb(0x8000), 0xFF);
+    assert_eq!(mmu.rb(0xFE00), 0xFF);
+    mmu.wb(0x8000, 0x78);
+
+    // HBlank
+    while ppu.stat.mode != Mode::HBlank {
+        ppu.tick(&mut mmu);
+    }
+    assert_eq!(mmu.rb(0x8000), 0x12);
+    assert_eq!(mmu.rb(0xFE00), 0x34);
+
+    // the debug switch lets everything through
+    while ppu.stat.mode != Mode::PixelTransfer {
+        ppu.tick(&mut mmu);
+    }
+    mmu.restrict_access = false;
+    mmu.wb(0x8000, 0x78);
+    assert_eq!(mmu.rb(0x8000), 0x78);
+    assert_eq!(mmu.rb(0xFE00), 0x34);
+}
+
+#[test]
+fn raw_framebuffer() {
+    let (mut mmu, mut ppu) = window_setup();
+    // tile 2 is solid colour 2, as a sprite from OBP1 at the start of line 8
+    for i in 0..8 {
+        mmu.wb(0x8021 + i * 2, 0xFF);
+    }
+    mmu.wb(0xFE00, 24);
+    mmu.wb(0xFE01, 8);
+    mmu.wb(0xFE02, 2);
+    mmu.wb(0xFE03, 0x10);
+    mmu.wb(0xFF49, 0b0001_1011);
+    mmu.wb(0xFF47, 0b0001_1011);
+    mmu.wb(0xFF40, 0xF3);
+    mmu.wb(0xFF4B, 87);
+    while render_line(&mut mmu, &mut ppu) < 8 {}
+
+    use crate::ppu::{RawPixel, Layer, Preset};
+    let raw = ppu.raw[8];
+    assert_eq!(raw[0], RawPixel { color: 2, shade: 1, layer: Layer::Object, palette: 1 });
+    assert_eq!(raw[8], RawPixel { color: 0, shade: 3, layer: Layer::Background, palette: 0 });
+    assert_eq!(raw[80], RawPixel { color: 0, shade: 3, layer: Layer::Window, palette: 0 });
+    assert_eq!(raw[84], RawPixel { color: 3, shade: 0, layer: Layer::Window, palette: 0 });
+
+    // colouring it in again gives the same picture
+    for (x, pixel) in raw.iter().enumerate() {
+        assert_eq!(ppu.palette.colorize(*pixel), ppu.data[8][x]);
+    }
+    assert_eq!(Preset::Green.palette().colorize(raw[84]), 0xFF9BBC0F);
 }
@@ -0,0 +1,267 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::recording::{Frame, FrameEncoder, GifEncoder, Y4mEncoder, RawEncoder, Recorder, RecordingFormat, recording_name};
+use std::time::UNIX_EPOCH;
+use crate::ppu::{SCREEN_W, SCREEN_H};
+use crate::gameboy::GameBoy;
+use super::gameboy::{rom, gameboy_with};
+use std::collections::HashMap;
+
+fn frame(seed: u32, colors: u32) -> Box<Frame> {
+    let mut frame = Box::new([[0; SCREEN_W]; SCREEN_H]);
+    for (y, row) in frame.iter_mut().enumerate() {
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let n = (x as u32 / 3 + y as u32 * 7 + seed) % colors;
+            *pixel = 0xFF00_0000 | n.wrapping_mul(0x010305);
+        }
+    }
+    frame
+}
+
+fn lzw_decode(data: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+    let mut table: Vec<Vec<u8>> = Vec::new();
+    let mut size = min_code_size + 1;
+    let mut out = Vec::new();
+    let mut prev: Option<u16> = None;
+    let (mut bits, mut bit_count, mut pos) = (0u32, 0u8, 0);
+    loop {
+        while bit_count < size {
+            bits |= (data[pos] as u32) << bit_count;
+            pos += 1;
+            bit_count += 8;
+        }
+        let code = (bits & ((1 << size) - 1)) as u16;
+        bits >>= size;
+        bit_count -= size;
+
+        if code == clear {
+            table = (0..clear).map(|i| vec![i as u8]).collect();
+            table.push(vec![]);
+            table.push(vec![]);
+            size = min_code_size + 1;
+            prev = None;
+            continue;
+        }
+        if code == end {
+            return out;
+        }
+        let entry = match prev {
+            None => table[code as usize].clone(),
+            Some(p) => {
+                let entry = if (code as usize) < table.len() {
+                    table[code as usize].clone()
+                } else {
+                    let mut e = table[p as usize].clone();
+                    e.push(e[0]);
+                    e
+                };
+                let mut new = table[p as usize].clone();
+                new.push(entry[0]);
+                table.push(new);
+                if table.len() == 1 << size && size < 12 {
+                    size += 1;
+                }
+                entry
+            },
+        };
+        out.extend_from_slice(&entry);
+        prev = Some(code);
+    }
+}
+
+// each frame's delay and pixels
+fn gif_decode(gif: &[u8]) -> Vec<(u16, Vec<u32>)> {
+    assert_eq!(&gif[..6], b"GIF89a");
+    assert_eq!(u16::from_le_bytes([gif[6], gif[7]]) as usize, SCREEN_W);
+    assert_eq!(u16::from_le_bytes([gif[8], gif[9]]) as usize, SCREEN_H);
+    let mut pos = 13;
+    let mut delay = 0;
+    let mut frames = Vec::new();
+    loop {
+        match gif[pos] {
+            0x21 => {
+                if gif[pos + 1] == 0xF9 {
+                    delay = u16::from_le_bytes([gif[pos + 4], gif[pos + 5]]);
+                }
+                pos += 2;
+                while gif[pos] != 0 {
+                    pos += gif[pos] as usize + 1;
+                }
+                pos += 1;
+            },
+            0x2C => {
+                let packed = gif[pos + 9];
+                let bits = (packed & 7) + 1;
+                let table = &gif[pos + 10..pos + 10 + (3 << bits)];
+                pos += 10 + (3 << bits);
+                let min_code_size = gif[pos];
+                pos += 1;
+                let mut data = Vec::new();
+                while gif[pos] != 0 {
+                    let len = gif[pos] as usize;
+                    data.extend_from_slice(&gif[pos + 1..pos + 1 + len]);
+                    pos += len + 1;
+                }
+                pos += 1;
+                let pixels = lzw_decode(&data, min_code_size).iter().map(|&i| {
+                    let c = &table[i as usize * 3..i as usize * 3 + 3];
+                    0xFF00_0000 | (c[0] as u32) << 16 | (c[1] as u32) << 8 | c[2] as u32
+                }).collect();
+                frames.push((delay, pixels));
+            },
+            0x3B => return frames,
+            b => panic!("unexpected block {:#04X}", b),
+        }
+    }
+}
+
+fn pixels(frame: &Frame) -> Vec<u32> {
+    frame.iter().flat_map(|row| row.iter().copied()).collect()
+}
+
+#[test]
+fn gif() {
+    let mut encoder = GifEncoder::new(Vec::new());
+    let (a, b) = (frame(0, 4), frame(1, 200));
+    // every other frame is kept, and repeats are merged
+    for f in [&a, &a, &a, &a, &b, &b, &a, &a, &a].iter() {
+        encoder.encode(f).unwrap();
+    }
+    encoder.finish().unwrap();
+
+    let frames = gif_decode(&encoder.out);
+    assert_eq!(frames.len(), 3);
+    assert_eq!(frames[0].1, pixels(&a));
+    assert_eq!(frames[1].1, pixels(&b));
+    assert_eq!(frames[2].1, pixels(&a));
+    // 59.73 frames a second, rounded to centiseconds
+    assert_eq!(frames[0].0, 7);
+    assert_eq!(frames[1].0, 3);
+    assert_eq!(frames[2].0, 5);
+}
+
+#[test]
+fn gif_too_many_colors() {
+    let mut encoder = GifEncoder::new(Vec::new());
+    let mut f = frame(0, 1);
+    for (i, pixel) in f[..2].iter_mut().flat_map(|row| row.iter_mut()).enumerate() {
+        *pixel = 0xFF00_0000 | (i as u32) << 8;
+    }
+    encoder.encode(&f).unwrap();
+    encoder.finish().unwrap();
+
+    let frames = gif_decode(&encoder.out);
+    assert_eq!(frames.len(), 1);
+    // reduced to 3-3-2 bits
+    assert_eq!(frames[0].1[0], 0xFF000000);
+    assert_eq!(frames[0].1[255], 0xFF00FF00);
+    assert_eq!(frames[0].1[SCREEN_W * 2..], pixels(&f)[SCREEN_W * 2..]);
+}
+
+#[test]
+fn y4m() {
+    let mut encoder = Y4mEncoder::new(Vec::new());
+    let mut f = frame(0, 1);
+    f[0][0] = 0xFFFFFFFF;
+    encoder.encode(&f).unwrap();
+    encoder.encode(&f).unwrap();
+    encoder.finish().unwrap();
+
+    let header = b"YUV4MPEG2 W160 H144 F4194304:70224 Ip A1:1 C444\n";
+    let frame_size = 6 + SCREEN_W * SCREEN_H * 3;
+    assert_eq!(&encoder.out[..header.len()], header);
+    assert_eq!(encoder.out.len(), header.len() + frame_size * 2);
+    let planes = &encoder.out[header.len() + 6..];
+    // white and black in studio range, with no colour
+    assert_eq!(planes[0], 235);
+    assert_eq!(planes[1], 16);
+    assert_eq!(planes[SCREEN_W * SCREEN_H], 128);
+    assert_eq!(planes[SCREEN_W * SCREEN_H * 2 + 1], 128);
+}
+
+#[test]
+fn raw() {
+    let mut encoder = RawEncoder::new(Vec::new());
+    let mut f = frame(0, 1);
+    f[0][1] = 0xFF123456;
+    encoder.encode(&f).unwrap();
+    encoder.finish().unwrap();
+    assert_eq!(encoder.out.len(), SCREEN_W * SCREEN_H * 3);
+    assert_eq!(&encoder.out[..6], &[0, 0, 0, 0x12, 0x34, 0x56]);
+}
+
+#[test]
+fn recorder() {
+    let mut recorder = Recorder::start(RawEncoder::new(Vec::new()));
+    for seed in 0..10 {
+        recorder.write_frame(&frame(seed, 4));
+    }
+    let encoder = recorder.finish().unwrap();
+    let size = SCREEN_W * SCREEN_H * 3;
+    assert_eq!(encoder.out.len(), size * 10);
+    let mut last = RawEncoder::new(Vec::new());
+    last.encode(&frame(9, 4)).unwrap();
+    assert_eq!(encoder.out[size * 9..], last.out[..]);
+}
+
+#[test]
+fn formats() {
+    assert_eq!(RecordingFormat::from_path("out/run.GIF"), Some(RecordingFormat::Gif));
+    assert_eq!(RecordingFormat::from_path("run.rgb"), Some(RecordingFormat::Raw));
+    assert_eq!(RecordingFormat::from_path("run.mp4"), None);
+    assert_eq!(RecordingFormat::from_path("run"), None);
+    for format in RecordingFormat::ALL.iter() {
+        assert_eq!(RecordingFormat::from_name(format.name()), Some(*format));
+    }
+    assert!(!RecordingFormat::Gif.wants_wav());
+    assert!(RecordingFormat::Y4m.wants_wav());
+    assert!(RecordingFormat::Raw.wants_wav());
+}
+
+#[test]
+fn names() {
+    assert_eq!(recording_name("TETRIS", UNIX_EPOCH, RecordingFormat::Gif), "TETRIS-19700101-000000.gif");
+    assert_eq!(recording_name("", UNIX_EPOCH, RecordingFormat::Y4m), "recording-19700101-000000.y4m");
+}
+
+#[test]
+fn whole_frames() {
+    let mut gameboy = gameboy_with(rom());
+    gameboy.mmu.wb(0xFF40, 0x91);
+    // start partway through a frame, where counting cycles would cut it in two
+    for _ in 0..5000 {
+        gameboy.step();
+    }
+
+    // like a game would, the shade is switched at the start of every VBlank
+    let mut recorder = Recorder::start(RawEncoder::new(Vec::new()));
+    let mut expected = Vec::new();
+    let mut ly = gameboy.mmu.rb(0xFF44);
+    let mut cycles = 0;
+    while expected.len() < 4 {
+        cycles += gameboy.step() as u32;
+        if gameboy.take_frame(cycles) {
+            cycles = 0;
+            recorder.write_frame(gameboy.framebuffer());
+            let mut encoder = RawEncoder::new(Vec::new());
+            encoder.encode(gameboy.framebuffer()).unwrap();
+            expected.push(encoder.out);
+        }
+        let last_ly = ly;
+        ly = gameboy.mmu.rb(0xFF44);
+        if ly == 144 && last_ly != 144 {
+            let bgp = gameboy.mmu.rb(0xFF47);
+            gameboy.mmu.wb(0xFF47, !bgp);
+        }
+    }
+
+    let out = recorder.finish().unwrap().out;
+    assert_eq!(out, expected.concat());
+    // after the first, which began before the shade was switched
+    for frame in expected[1..].iter() {
+        assert!(frame.iter().all(|&b| b == frame[0]));
+    }
+    assert_ne!(expected[1][0], expected[2][0]);
+}
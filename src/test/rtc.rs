@@ -0,0 +1,165 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::cartridge::{Cartridge, KILOBYTE};
+use crate::mmu::MMU;
+use crate::rtc::{RtcClock, RTC_FOOTER_SIZE};
+use crate::interrupts::IntReq;
+use crate::timer::Timer;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECOND: u32 = 1 << 20;
+
+fn cartridge_rtc(clock: RtcClock) -> Cartridge {
+    let mut rom: Vec<u8> = vec![0; 32 * KILOBYTE as usize];
+    rom[0x147] = 0x10; // MBC3+TIMER+RAM+BATTERY
+    rom[0x149] = 0x03;
+    let mut cartridge = match Cartridge::new(rom) {
+        Err(e) => panic!("Error loading cartridge: {:#?}", e),
+        Ok(c) => c,
+    };
+    cartridge.set_rtc_clock(clock);
+    cartridge
+}
+
+fn rtc_mmu(cartridge: Cartridge) -> MMU {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut mmu = MMU::new(cartridge, timer.clone());
+    mmu.wb(0x0000, 0x0A);
+    mmu
+}
+
+fn tick(mmu: &mut MMU, m_clocks: u32) {
+    for _ in 0..(m_clocks / 128) {
+        mmu.cartridge.tick(128);
+    }
+}
+
+fn latch(mmu: &mut MMU) {
+    mmu.wb(0x6000, 0x00);
+    mmu.wb(0x6000, 0x01);
+}
+
+fn write_reg(mmu: &mut MMU, reg: u8, value: u8) {
+    mmu.wb(0x4000, reg);
+    mmu.wb(0xA000, value);
+}
+
+fn read_reg(mmu: &mut MMU, reg: u8) -> u8 {
+    mmu.wb(0x4000, reg);
+    mmu.rb(0xA000)
+}
+
+#[test]
+fn counting_and_latching() {
+    let mut mmu = rtc_mmu(cartridge_rtc(RtcClock::Emulated));
+    write_reg(&mut mmu, 0x08, 58);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x08), 58);
+
+    tick(&mut mmu, 2 * SECOND);
+    assert_eq!(read_reg(&mut mmu, 0x08), 58);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x08), 0);
+    assert_eq!(read_reg(&mut mmu, 0x09), 1);
+
+    // a single write of 0x01 doesn't latch
+    tick(&mut mmu, SECOND);
+    mmu.wb(0x6000, 0x01);
+    assert_eq!(read_reg(&mut mmu, 0x08), 0);
+
+    // RAM banks are still reachable next to the clock
+    mmu.wb(0x4000, 0x01);
+    mmu.wb(0xA000, 0x77);
+    assert_eq!(mmu.rb(0xA000), 0x77);
+    assert_eq!(read_reg(&mut mmu, 0x08), 0);
+}
+
+#[test]
+fn halt() {
+    let mut mmu = rtc_mmu(cartridge_rtc(RtcClock::Emulated));
+    write_reg(&mut mmu, 0x0C, 0x40);
+    tick(&mut mmu, 3 * SECOND);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x08), 0);
+
+    write_reg(&mut mmu, 0x0C, 0x00);
+    tick(&mut mmu, 3 * SECOND);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x08), 3);
+}
+
+#[test]
+fn day_carry() {
+    let mut mmu = rtc_mmu(cartridge_rtc(RtcClock::Emulated));
+    write_reg(&mut mmu, 0x08, 59);
+    write_reg(&mut mmu, 0x09, 59);
+    write_reg(&mut mmu, 0x0A, 23);
+    write_reg(&mut mmu, 0x0B, 0xFF);
+    write_reg(&mut mmu, 0x0C, 0x01);
+    tick(&mut mmu, SECOND);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x0A), 0);
+    assert_eq!(read_reg(&mut mmu, 0x0B), 0);
+    assert_eq!(read_reg(&mut mmu, 0x0C), 0x80);
+}
+
+#[test]
+fn out_of_range() {
+    // seconds past 59 wrap at 64 without counting the minutes up
+    let mut mmu = rtc_mmu(cartridge_rtc(RtcClock::Emulated));
+    write_reg(&mut mmu, 0x08, 62);
+    tick(&mut mmu, 2 * SECOND);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x08), 0);
+    assert_eq!(read_reg(&mut mmu, 0x09), 0);
+}
+
+#[test]
+fn footer() {
+    let mut mmu = rtc_mmu(cartridge_rtc(RtcClock::Emulated));
+    write_reg(&mut mmu, 0x09, 42);
+    write_reg(&mut mmu, 0x0B, 7);
+    latch(&mut mmu);
+    let save = mmu.cartridge.battery_save();
+    assert_eq!(save.len(), 32 * KILOBYTE as usize + RTC_FOOTER_SIZE);
+    assert!(mmu.cartridge.ram_dirty);
+
+    let mut mmu = rtc_mmu(cartridge_rtc(RtcClock::Emulated));
+    mmu.cartridge.load_battery_save(&save);
+    assert_eq!(read_reg(&mut mmu, 0x09), 42);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x0B), 7);
+
+    // the timestamp is when it was saved, not when the clock was loaded
+    let footer = save.len() - 8;
+    let mut old = save.clone();
+    old[footer..].copy_from_slice(&1000u64.to_le_bytes());
+    mmu.cartridge.load_battery_save(&old);
+    let save = mmu.cartridge.battery_save();
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&save[footer..]);
+    let timestamp = u64::from_le_bytes(timestamp);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    assert!(now - timestamp <= 1);
+}
+
+#[test]
+fn wall_clock() {
+    let mmu = rtc_mmu(cartridge_rtc(RtcClock::WallClock));
+    let mut save = mmu.cartridge.battery_save();
+
+    // pretend the save was written an hour, a minute and a second ago
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let footer = save.len() - 8;
+    save[footer..].copy_from_slice(&(now - 3661).to_le_bytes());
+
+    let mut mmu = rtc_mmu(cartridge_rtc(RtcClock::WallClock));
+    mmu.cartridge.load_battery_save(&save);
+    latch(&mut mmu);
+    assert_eq!(read_reg(&mut mmu, 0x0A), 1);
+    assert_eq!(read_reg(&mut mmu, 0x09), 1);
+    assert!((1..=2).contains(&read_reg(&mut mmu, 0x08)));
+}
@@ -0,0 +1,80 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::gameboy::GameBoy;
+use crate::savestate::SaveStateError;
+use super::gameboy::{rom_with, gameboy_with};
+
+// MBC1+RAM+BATTERY with 8KB of RAM
+fn rom(title: &[u8]) -> Vec<u8> {
+    rom_with(title, &[(0x147, 0x03), (0x149, 0x02)])
+}
+
+fn gameboy() -> GameBoy {
+    let mut gameboy = gameboy_with(rom(b"SAVE"));
+    gameboy.mmu.wb(0xFF40, 0x80);
+    gameboy
+}
+
+#[test]
+fn round_trip() {
+    let mut gameboy = gameboy();
+    gameboy.run_frame();
+    gameboy.step();
+    gameboy.mmu.wb(0xC123, 0x42);
+    gameboy.mmu.wb(0x0000, 0x0A);
+    gameboy.mmu.wb(0xA456, 0x24);
+    gameboy.mmu.wb(0x2000, 0x01);
+    let state = gameboy.save_state();
+    let pc = gameboy.cpu.reg.pc;
+    let ly = gameboy.mmu.rb(0xFF44);
+
+    gameboy.run_frame();
+    gameboy.mmu.wb(0xC123, 0x00);
+    gameboy.mmu.wb(0xA456, 0x00);
+    assert_ne!(gameboy.cpu.reg.pc, pc);
+
+    gameboy.load_state(&state).unwrap();
+    assert_eq!(gameboy.cpu.reg.pc, pc);
+    assert_eq!(gameboy.mmu.rb(0xFF44), ly);
+    assert_eq!(gameboy.mmu.rb(0xC123), 0x42);
+    assert_eq!(gameboy.mmu.rb(0xA456), 0x24);
+    assert_eq!(gameboy.save_state(), state);
+}
+
+#[test]
+fn wrong_cartridge() {
+    let state = gameboy().save_state();
+
+    let mut other = GameBoy::new(vec![0; 0x100]);
+    other.load_rom(rom(b"OTHER")).unwrap();
+    match other.load_state(&state) {
+        Err(SaveStateError::WrongCartridge) => (),
+        r => panic!("Unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn invalid_state() {
+    let mut gameboy = gameboy();
+    let mut state = gameboy.save_state();
+    gameboy.run_frame();
+    let pc = gameboy.cpu.reg.pc;
+
+    match gameboy.load_state(&state[..state.len() - 1]) {
+        Err(SaveStateError::UnexpectedEnd) => (),
+        r => panic!("Unexpected result: {:?}", r),
+    }
+    assert_eq!(gameboy.cpu.reg.pc, pc);
+
+    state[8] = 0xFF;
+    match gameboy.load_state(&state) {
+        Err(SaveStateError::UnsupportedVersion(0xFF)) => (),
+        r => panic!("Unexpected result: {:?}", r),
+    }
+
+    state[0] = b'X';
+    match gameboy.load_state(&state) {
+        Err(SaveStateError::InvalidHeader) => (),
+        r => panic!("Unexpected result: {:?}", r),
+    }
+}
@@ -0,0 +1,100 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::screenshot::{write_png, screenshot_name};
+use crate::ppu::{SCREEN_W, SCREEN_H};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn frame() -> [[u32; SCREEN_W]; SCREEN_H] {
+    let mut frame = [[0xFFFFFFFF; SCREEN_W]; SCREEN_H];
+    frame[0][0] = 0xFF123456;
+    frame[143][159] = 0xFFABCDEF;
+    frame
+}
+
+fn be32(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+// splits a PNG into its chunks, checking the signature
+fn chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+    assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n']);
+    let mut chunks = Vec::new();
+    let mut i = 8;
+    while i < png.len() {
+        let len = be32(&png[i..]) as usize;
+        let kind = String::from_utf8(png[i + 4..i + 8].to_vec()).unwrap();
+        chunks.push((kind, png[i + 8..i + 8 + len].to_vec()));
+        i += 12 + len;
+    }
+    assert_eq!(i, png.len());
+    chunks
+}
+
+// the image data, out of its stored deflate blocks
+fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+    assert_eq!(&zlib[..2], &[0x78, 0x01]);
+    let mut data = Vec::new();
+    let mut i = 2;
+    loop {
+        let last = zlib[i] & 1 != 0;
+        let len = u16::from_le_bytes([zlib[i + 1], zlib[i + 2]]) as usize;
+        let nlen = u16::from_le_bytes([zlib[i + 3], zlib[i + 4]]);
+        assert_eq!(len as u16, !nlen);
+        data.extend_from_slice(&zlib[i + 5..i + 5 + len]);
+        i += 5 + len;
+        if last {
+            break;
+        }
+    }
+    assert_eq!(i + 4, zlib.len());
+    data
+}
+
+#[test]
+fn png() {
+    let mut png = Vec::new();
+    write_png(&mut png, &frame(), 1).unwrap();
+    let chunks = chunks(&png);
+    let kinds: Vec<&str> = chunks.iter().map(|(kind, _)| kind.as_str()).collect();
+    assert_eq!(kinds, ["IHDR", "IDAT", "IEND"]);
+    assert_eq!(be32(&chunks[0].1), 160);
+    assert_eq!(be32(&chunks[0].1[4..]), 144);
+    assert_eq!(&chunks[0].1[8..], &[8, 2, 0, 0, 0]);
+    // the CRC of an empty IEND is always the same
+    assert_eq!(&png[png.len() - 4..], &[0xAE, 0x42, 0x60, 0x82]);
+
+    let data = inflate_stored(&chunks[1].1);
+    assert_eq!(data.len(), (160 * 3 + 1) * 144);
+    assert_eq!(&data[..4], &[0, 0x12, 0x34, 0x56]);
+    assert_eq!(&data[data.len() - 3..], &[0xAB, 0xCD, 0xEF]);
+}
+
+#[test]
+fn scaled_png() {
+    let mut png = Vec::new();
+    write_png(&mut png, &frame(), 3).unwrap();
+    let chunks = chunks(&png);
+    assert_eq!(be32(&chunks[0].1), 480);
+    assert_eq!(be32(&chunks[0].1[4..]), 432);
+
+    let data = inflate_stored(&chunks[1].1);
+    let stride = 480 * 3 + 1;
+    assert_eq!(data.len(), stride * 432);
+    for row in 0..3 {
+        assert_eq!(&data[row * stride..row * stride + 13],
+            &[0, 0x12, 0x34, 0x56, 0x12, 0x34, 0x56, 0x12, 0x34, 0x56, 0xFF, 0xFF, 0xFF]);
+    }
+    assert_eq!(&data[3 * stride + 1..3 * stride + 4], &[0xFF, 0xFF, 0xFF]);
+}
+
+#[test]
+fn names() {
+    // 2021-03-04 05:06:07 UTC
+    let time = UNIX_EPOCH + Duration::from_secs(1614834367);
+    assert_eq!(screenshot_name("TETRIS", time), "TETRIS-20210304-050607.png");
+    assert_eq!(screenshot_name("POKEMON RED", time), "POKEMON_RED-20210304-050607.png");
+    assert_eq!(screenshot_name("", UNIX_EPOCH), "screenshot-19700101-000000.png");
+    // a leap day
+    let time = UNIX_EPOCH + Duration::from_secs(951782400);
+    assert_eq!(screenshot_name("A/B", time), "A_B-20000229-000000.png");
+}
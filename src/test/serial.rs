@@ -0,0 +1,38 @@
+#![allow(dead_code, unused_imports)]
+
+use crate::mmu::MMU;
+use crate::cartridge::Cartridge;
+use crate::interrupts::IntReq;
+use crate::timer::Timer;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[test]
+fn transfer() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut mmu = MMU::new(Cartridge::empty(), timer.clone());
+
+    mmu.wb(0xFF01, b'O');
+    mmu.wb(0xFF02, 0x81);
+    mmu.wb(0xFF01, b'K');
+    mmu.wb(0xFF02, 0x81);
+    assert_eq!(mmu.serial.output, b"OK");
+    assert_eq!(mmu.rb(0xFF01), 0xFF);
+    assert_eq!(mmu.rb(0xFF02), 0x7F);
+    assert_eq!(intr.borrow().flags & 0b1000, 0b1000);
+}
+
+#[test]
+fn external_clock() {
+    let intr = Rc::new(RefCell::new(IntReq::new()));
+    let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
+    let mut mmu = MMU::new(Cartridge::empty(), timer.clone());
+
+    // nothing drives the external clock, so the transfer never ends
+    mmu.wb(0xFF01, b'X');
+    mmu.wb(0xFF02, 0x80);
+    assert!(mmu.serial.output.is_empty());
+    assert_eq!(mmu.rb(0xFF02), 0xFE);
+    assert_eq!(intr.borrow().flags & 0b1000, 0);
+}
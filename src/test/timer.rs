@@ -21,8 +21,7 @@ fn cartridge() -> Cartridge {
 fn div() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge();
-    let mmu = MMU::new(&mut cartridge, timer.clone());
+    let mmu = MMU::new(cartridge(), timer.clone());
 
     for _ in 0..63 {
         timer.borrow_mut().tick();
@@ -49,8 +48,7 @@ fn div() {
 fn write_div() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
 
     timer.borrow_mut().tick_n(64);
 
@@ -65,8 +63,7 @@ fn write_div() {
 fn tima() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
 
     // enable timer, increment every 256 M-cycles
     mmu.wb(0xFF07, 0b0100);
@@ -113,8 +110,7 @@ fn tima() {
 fn overflow() {
     let intr = Rc::new(RefCell::new(IntReq::new()));
     let timer = Rc::new(RefCell::new(Timer::new(intr.clone())));
-    let mut cartridge = cartridge();
-    let mut mmu = MMU::new(&mut cartridge, timer.clone());
+    let mut mmu = MMU::new(cartridge(), timer.clone());
     mmu.wb(0xFF06, 0x20);
     assert_eq!(timer.borrow_mut().tma, 0x20);
     assert_eq!(mmu.timer.borrow_mut().tma, 0x20);
@@ -1,4 +1,5 @@
 use crate::interrupts::*;
+use crate::savestate::{StateWriter, StateReader, SaveStateError};
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -58,6 +59,23 @@ impl Timer {
         self.div = (self.counter >> 8) as u8;
     }
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u16(self.counter);
+        w.write_u8(self.div);
+        w.write_u8(self.tima);
+        w.write_u8(self.tma);
+        w.write_u8(self.tac);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), SaveStateError> {
+        self.counter = r.read_u16()?;
+        self.div = r.read_u8()?;
+        self.tima = r.read_u8()?;
+        self.tma = r.read_u8()?;
+        self.tac = r.read_u8()?;
+        Ok(())
+    }
+
     fn check_overflow(&mut self) {
         let (tima, overflow) = self.tima.overflowing_add(1);
 